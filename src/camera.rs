@@ -5,12 +5,51 @@ use winit::event::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode, Win
 
 use crate::spring::Spring;
 
+/// Physical camera settings the image is exposed with, so lights and the environment can be
+/// given in photometric units. Defaults to the "sunny 16" rule.
+#[derive(Clone, Copy, Debug)]
+pub struct Exposure {
+    /// f-number
+    pub aperture: f32,
+    /// In seconds
+    pub shutter_speed: f32,
+    pub iso: f32,
+    /// In stops, positive brightens the image
+    pub compensation: f32,
+}
+
+impl Default for Exposure {
+    fn default() -> Self {
+        Self {
+            aperture: 16.0,
+            shutter_speed: 1.0 / 125.0,
+            iso: 100.0,
+            compensation: 0.0,
+        }
+    }
+}
+
+impl Exposure {
+    /// Exposure value at ISO 100, compensation included
+    pub fn ev100(&self) -> f32 {
+        (self.aperture * self.aperture / self.shutter_speed * 100.0 / self.iso).log2()
+            - self.compensation
+    }
+
+    /// Scale from luminance (cd/m²) to the [0, 1] range before tonemapping, from the saturation
+    /// based sensitivity model (luminance that saturates the sensor is 1.2 * 2^EV100)
+    pub fn exposure(&self) -> f32 {
+        1.0 / (1.2 * 2.0_f32.powf(self.ev100()))
+    }
+}
+
 pub struct Camera {
     pub eye: Vec3,
     front: Vec3,
     up: Vec3,
     pub pitch: f32,
     pub yaw: f32,
+    pub exposure: Exposure,
 }
 
 impl Default for Camera {
@@ -21,15 +60,54 @@ impl Default for Camera {
             up: vec3(0.0, 1.0, 0.0),
             pitch: 0.0,
             yaw: 90.0,
+            exposure: Exposure::default(),
         }
     }
 }
 
 impl Camera {
+    /// Vertical field of view, in degrees
+    pub const FOVY: f32 = 45.0;
+    pub const NEAR: f32 = 0.01;
+    pub const FAR: f32 = 100.0;
+
+    /// Direction the camera looks in
+    pub fn front(&self) -> Vec3 {
+        self.front
+    }
+
     pub fn build_uniforms(&self) -> (Mat4, Vec4) {
         let view = Mat4::look_to_lh(self.eye, self.front, self.up);
         (view, vec4(self.eye.x, self.eye.y, self.eye.z, 1.0))
     }
+
+    pub fn build_projection(&self, aspect_ratio: f32) -> Mat4 {
+        Mat4::perspective_lh(
+            Camera::FOVY.to_radians(),
+            aspect_ratio,
+            Camera::NEAR,
+            Camera::FAR,
+        )
+    }
+
+    /// World space corners of the slice of the view frustum between near and far
+    /// (near plane corners first).
+    pub fn frustum_corners(&self, aspect_ratio: f32, near: f32, far: f32) -> [Vec3; 8] {
+        let (view, _) = self.build_uniforms();
+        let projection = Mat4::perspective_lh(Camera::FOVY.to_radians(), aspect_ratio, near, far);
+        let inverse = (projection * view).inverse();
+
+        let mut corners = [Vec3::ZERO; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            let ndc = vec3(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { 0.0 } else { 1.0 },
+            );
+            *corner = inverse.project_point3(ndc);
+        }
+        corners
+    }
 }
 
 pub trait CameraController {
@@ -0,0 +1,153 @@
+use std::{error::Error, fmt, path::Path, sync::mpsc};
+
+use half::f16;
+use image::{ImageBuffer, Rgba};
+use wgpu::{BufferUsages, TextureFormat};
+
+use crate::texture::Texture;
+
+#[derive(Debug)]
+pub enum CaptureError {
+    UnsupportedFormat(TextureFormat),
+    Readback(wgpu::BufferAsyncError),
+    Image(image::ImageError),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::UnsupportedFormat(x) => write!(f, "can't capture {:?} textures", x),
+            CaptureError::Readback(x) => write!(f, "couldn't read back texture: {}", x),
+            CaptureError::Image(x) => write!(f, "couldn't write image: {}", x),
+        }
+    }
+}
+
+impl Error for CaptureError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CaptureError::UnsupportedFormat(_) => None,
+            CaptureError::Readback(x) => Some(x),
+            CaptureError::Image(x) => Some(x),
+        }
+    }
+}
+
+/// Copy a texture into a mappable buffer and read it back on the CPU. Returned bytes are
+/// tightly packed (row padding required by wgpu is stripped).
+pub fn read_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &Texture,
+) -> Result<Vec<u8>, CaptureError> {
+    let (width, height) = (texture.texture.width(), texture.texture.height());
+    let bytes_per_pixel = match texture.texture.format() {
+        TextureFormat::Rgba8Unorm
+        | TextureFormat::Rgba8UnormSrgb
+        | TextureFormat::Bgra8Unorm
+        | TextureFormat::Bgra8UnormSrgb => 4,
+        TextureFormat::Rgba16Float => 8,
+        x => return Err(CaptureError::UnsupportedFormat(x)),
+    };
+
+    let unpadded_bytes_per_row = bytes_per_pixel * width;
+    let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+    let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback buffer"),
+        size: (padded_bytes_per_row * height) as u64,
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback encoder"),
+    });
+    encoder.copy_texture_to_buffer(
+        wgpu::ImageCopyTexture {
+            texture: &texture.texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        wgpu::ImageCopyBuffer {
+            buffer: &buffer,
+            layout: wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(padded_bytes_per_row),
+                rows_per_image: Some(height),
+            },
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+    queue.submit(std::iter::once(encoder.finish()));
+
+    let slice = buffer.slice(..);
+    let (sender, receiver) = mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait);
+    receiver
+        .recv()
+        .expect("map_async callback dropped")
+        .map_err(CaptureError::Readback)?;
+
+    let mut bytes = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+    {
+        let mapped = slice.get_mapped_range();
+        for row in mapped.chunks(padded_bytes_per_row as usize) {
+            bytes.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+        }
+    }
+    buffer.unmap();
+
+    Ok(bytes)
+}
+
+/// Read back a texture and write it to disk. 8-bit textures are written as-is (so an sRGB
+/// texture gives an sRGB image, what you want for PNG), Rgba16Float is widened to f32 (for EXR).
+/// The image format is picked by the image crate from the file extension.
+pub fn save_texture<P: AsRef<Path>>(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    texture: &Texture,
+    path: P,
+) -> Result<(), CaptureError> {
+    let (width, height) = (texture.texture.width(), texture.texture.height());
+    let mut bytes = read_texture(device, queue, texture)?;
+
+    match texture.texture.format() {
+        TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+            ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, bytes)
+                .unwrap()
+                .save(path)
+                .map_err(CaptureError::Image)
+        }
+        TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => {
+            for bgra in bytes.chunks_mut(4) {
+                bgra.swap(0, 2);
+            }
+            ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, bytes)
+                .unwrap()
+                .save(path)
+                .map_err(CaptureError::Image)
+        }
+        TextureFormat::Rgba16Float => {
+            let floats = bytes
+                .chunks(2)
+                .map(|x| f16::from_le_bytes([x[0], x[1]]).to_f32())
+                .collect::<Vec<f32>>();
+            ImageBuffer::<Rgba<f32>, _>::from_raw(width, height, floats)
+                .unwrap()
+                .save(path)
+                .map_err(CaptureError::Image)
+        }
+        x => Err(CaptureError::UnsupportedFormat(x)),
+    }
+}
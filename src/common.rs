@@ -7,14 +7,17 @@ pub struct VertexAttributes {
     pub normal: [f32; 3],
     pub uv: [f32; 2],
     pub tangent: [f32; 3],
+    /// Second UV set (TEXCOORD_1), same as uv if the mesh doesn't have one
+    pub uv_1: [f32; 2],
 }
 
 unsafe impl bytemuck::Pod for VertexAttributes {}
 unsafe impl bytemuck::Zeroable for VertexAttributes {}
 
 impl VertexAttributes {
-    const BUFFER_LAYOUT: [VertexAttribute; 4] =
-        wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x3, 2 => Float32x2, 3=> Float32x3];
+    const BUFFER_LAYOUT: [VertexAttribute; 5] = wgpu::vertex_attr_array![
+        0 => Float32x3, 1 => Float32x3, 2 => Float32x2, 3=> Float32x3, 4 => Float32x2
+    ];
 
     pub fn buffer_layout() -> VertexBufferLayout<'static> {
         VertexBufferLayout {
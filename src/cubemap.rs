@@ -14,6 +14,43 @@ pub struct Cubemap {
 }
 
 impl Cubemap {
+    /// An empty Rgba16Float cubemap, writable from compute shaders through 2D array views of
+    /// its mip levels (see Environment).
+    pub fn new(
+        device: &wgpu::Device,
+        size: u32,
+        mip_level_count: u32,
+        label: Option<&str>,
+    ) -> Self {
+        let texture = device.create_texture(&TextureDescriptor {
+            label,
+            size: Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 6,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba16Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&TextureViewDescriptor {
+            label,
+            format: Some(wgpu::TextureFormat::Rgba16Float),
+            dimension: Some(TextureViewDimension::Cube),
+            ..Default::default()
+        });
+
+        Cubemap {
+            texture,
+            view,
+            format: wgpu::TextureFormat::Rgba16Float,
+        }
+    }
+
     pub fn from_dds<P: AsRef<Path>>(device: &wgpu::Device, queue: &wgpu::Queue, path: P) -> Self {
         let file = std::fs::read(path).unwrap();
         let img = ddsfile::Dds::read(file.as_slice()).unwrap();
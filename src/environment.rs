@@ -0,0 +1,367 @@
+use std::{collections::HashMap, path::Path};
+
+use half::f16;
+use wgpu::{
+    util::DeviceExt, BindGroupLayout, ComputePipeline, PipelineLayoutDescriptor, TextureFormat,
+    TextureUsages, TextureView, TextureViewDimension,
+};
+
+use crate::{
+    bytemuck_impl,
+    cubemap::Cubemap,
+    texture::{Sampler, Texture},
+};
+
+/// Largest skybox face baked from a panorama, below that faces are a quarter of its width
+const MAX_SKYBOX_SIZE: u32 = 1024;
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTER_SIZE: u32 = 256;
+/// Mip levels of the prefiltered cubemap, each for a rougher surface (see Bake::Prefilter)
+const PREFILTER_LEVELS: u32 = 7;
+const BRDF_LOOKUP_SIZE: u32 = 256;
+/// Samples per texel for the irradiance, prefilter and BRDF lookup integrals
+const SAMPLE_COUNT: u32 = 1024;
+
+/// Skybox and image based lighting maps baked on the GPU from an equirectangular panorama, to
+/// hand to Skybox::update_cubemap and IBL::update.
+pub struct Environment {
+    /// The panorama as a cubemap, with a box filtered mip chain
+    pub skybox: Cubemap,
+    /// Cosine convolved radiance, divided by π
+    pub irradiance: Cubemap,
+    /// GGX prefiltered radiance, compose.wgsl picks the mip level from the roughness
+    pub prefilter: Cubemap,
+    /// Split sum BRDF scale and bias, laid out like OCEANMAN_BRDF.dds
+    pub brdf_lookup: Texture,
+}
+
+impl Environment {
+    /// Load a .hdr or .exr equirectangular panorama and bake everything from it.
+    pub fn from_equirect<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+    ) -> Result<Self, image::ImageError> {
+        let image = image::open(path)?.into_rgba32f();
+        let (width, height) = image.dimensions();
+        // Half floats so it can be filtered, clamped so a bright sun doesn't turn into infinity
+        let pixels = image
+            .as_raw()
+            .iter()
+            .map(|x| f16::from_f32(x.min(f16::MAX.to_f32())))
+            .collect::<Vec<f16>>();
+        let equirect = Texture::new_from_bytes(
+            device,
+            queue,
+            bytemuck::cast_slice(pixels.as_slice()),
+            width,
+            height,
+            TextureFormat::Rgba16Float,
+            TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            Some("Equirect texture"),
+            false,
+        );
+
+        let skybox_size = (width / 4).next_power_of_two().min(MAX_SKYBOX_SIZE);
+        let skybox = Cubemap::new(
+            device,
+            skybox_size,
+            Texture::mip_level_count(skybox_size, skybox_size),
+            Some("Environment skybox"),
+        );
+        let irradiance = Cubemap::new(device, IRRADIANCE_SIZE, 1, Some("Environment irradiance"));
+        let prefilter = Cubemap::new(
+            device,
+            PREFILTER_SIZE,
+            PREFILTER_LEVELS,
+            Some("Environment prefilter"),
+        );
+        let brdf_lookup = Texture::new(
+            device,
+            BRDF_LOOKUP_SIZE,
+            BRDF_LOOKUP_SIZE,
+            TextureFormat::Rgba16Float,
+            TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            Some("BRDF lookup"),
+            false,
+        );
+
+        let mut baker = Baker::new(device);
+
+        baker.dispatch(
+            Bake::Equirect,
+            Some(&equirect.view),
+            &skybox.texture,
+            0,
+            BakeParams::default(),
+        );
+        for level in 1..skybox.texture.mip_level_count() {
+            baker.dispatch(
+                Bake::Downsample,
+                Some(&level_view(&skybox.texture, level - 1)),
+                &skybox.texture,
+                level,
+                BakeParams::default(),
+            );
+        }
+
+        baker.dispatch(
+            Bake::Irradiance,
+            Some(&skybox.view),
+            &irradiance.texture,
+            0,
+            BakeParams::default(),
+        );
+
+        // compose.wgsl samples level levels * roughness * (2 - roughness)
+        for level in 0..PREFILTER_LEVELS {
+            let roughness = 1.0 - (1.0 - level as f32 / PREFILTER_LEVELS as f32).sqrt();
+            baker.dispatch(
+                Bake::Prefilter,
+                Some(&skybox.view),
+                &prefilter.texture,
+                level,
+                BakeParams {
+                    roughness,
+                    ..Default::default()
+                },
+            );
+        }
+
+        baker.dispatch(
+            Bake::BrdfLookup,
+            None,
+            &brdf_lookup.texture,
+            0,
+            BakeParams::default(),
+        );
+
+        queue.submit(std::iter::once(baker.encoder.finish()));
+
+        Ok(Self {
+            skybox,
+            irradiance,
+            prefilter,
+            brdf_lookup,
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct BakeParams {
+    roughness: f32,
+    sample_count: u32,
+    padding: [u32; 2],
+}
+bytemuck_impl!(BakeParams);
+
+impl Default for BakeParams {
+    fn default() -> Self {
+        Self {
+            roughness: 0.0,
+            sample_count: SAMPLE_COUNT,
+            padding: [0; 2],
+        }
+    }
+}
+
+/// One of the entry points in environment.wgsl.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Bake {
+    /// Panorama to skybox faces
+    Equirect,
+    /// A skybox mip level from the one above
+    Downsample,
+    /// Skybox to irradiance
+    Irradiance,
+    /// Skybox to one prefilter mip level
+    Prefilter,
+    /// BRDF lookup table, doesn't read anything
+    BrdfLookup,
+}
+
+impl Bake {
+    fn entry_point(self) -> &'static str {
+        match self {
+            Bake::Equirect => "cs_equirect",
+            Bake::Downsample => "cs_downsample",
+            Bake::Irradiance => "cs_irradiance",
+            Bake::Prefilter => "cs_prefilter",
+            Bake::BrdfLookup => "cs_brdf",
+        }
+    }
+
+    /// Binding and view dimension of the texture it reads
+    fn source(self) -> Option<(u32, TextureViewDimension)> {
+        match self {
+            Bake::Equirect => Some((2, TextureViewDimension::D2)),
+            Bake::Downsample => Some((3, TextureViewDimension::D2Array)),
+            Bake::Irradiance | Bake::Prefilter => Some((4, TextureViewDimension::Cube)),
+            Bake::BrdfLookup => None,
+        }
+    }
+}
+
+/// Records the bakes into one encoder, making pipelines as they're needed.
+struct Baker<'a> {
+    device: &'a wgpu::Device,
+    shader: wgpu::ShaderModule,
+    sampler: Sampler,
+    pipelines: HashMap<Bake, ComputePipeline>,
+    encoder: wgpu::CommandEncoder,
+}
+
+impl<'a> Baker<'a> {
+    fn new(device: &'a wgpu::Device) -> Self {
+        let shader =
+            device.create_shader_module(wgpu::include_wgsl!("shaders/environment.wgsl", true));
+        let encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment encoder"),
+        });
+
+        Self {
+            device,
+            shader,
+            sampler: Sampler::environment_sampler(device),
+            pipelines: HashMap::new(),
+            encoder,
+        }
+    }
+
+    fn bind_group_layout(device: &wgpu::Device, bake: Bake) -> BindGroupLayout {
+        let mut entries = vec![
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rgba16Float,
+                    view_dimension: TextureViewDimension::D2Array,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+        if let Some((binding, view_dimension)) = bake.source() {
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension,
+                    multisampled: false,
+                },
+                count: None,
+            });
+            entries.push(wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            });
+        }
+
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some(format!("Environment bind group layout ({:?})", bake).as_str()),
+            entries: &entries,
+        })
+    }
+
+    fn pipeline(device: &wgpu::Device, shader: &wgpu::ShaderModule, bake: Bake) -> ComputePipeline {
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(format!("Environment pipeline ({:?})", bake).as_str()),
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some("Environment pipeline layout"),
+                bind_group_layouts: &[&Baker::bind_group_layout(device, bake)],
+                push_constant_ranges: &[],
+            })),
+            module: shader,
+            entry_point: bake.entry_point(),
+        })
+    }
+
+    /// Run bake over every texel of one mip level of output (all its layers), reading source.
+    fn dispatch(
+        &mut self,
+        bake: Bake,
+        source: Option<&TextureView>,
+        output: &wgpu::Texture,
+        level: u32,
+        params: BakeParams,
+    ) {
+        let device = self.device;
+        let shader = &self.shader;
+        let pipeline = self
+            .pipelines
+            .entry(bake)
+            .or_insert_with(|| Baker::pipeline(device, shader, bake));
+
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment params buffer"),
+            contents: bytemuck::cast_slice(&[params]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let output_view = level_view(output, level);
+
+        let mut entries = vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&output_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: params_buffer.as_entire_binding(),
+            },
+        ];
+        if let (Some((binding, _)), Some(source)) = (bake.source(), source) {
+            entries.push(wgpu::BindGroupEntry {
+                binding,
+                resource: wgpu::BindingResource::TextureView(source),
+            });
+            entries.push(wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::Sampler(&self.sampler.sampler),
+            });
+        }
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Environment bind group"),
+            layout: &Baker::bind_group_layout(device, bake),
+            entries: &entries,
+        });
+
+        let mut pass = self
+            .encoder
+            .begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some(format!("Environment {:?}", bake).as_str()),
+                timestamp_writes: None,
+            });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+
+        // Matches the 8x8 workgroup size in environment.wgsl
+        let size = (output.width() >> level).max(1);
+        let workgroups = size.div_ceil(8);
+        pass.dispatch_workgroups(workgroups, workgroups, output.depth_or_array_layers());
+    }
+}
+
+/// One mip level of a texture as a 2D array, every layer (cube face) included.
+fn level_view(texture: &wgpu::Texture, level: u32) -> TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        label: Some("Environment level"),
+        dimension: Some(TextureViewDimension::D2Array),
+        base_mip_level: level,
+        mip_level_count: Some(1),
+        ..Default::default()
+    })
+}
@@ -15,16 +15,27 @@ pub struct GBuffers {
     /// Material of fragment, RGBA8
     /// Written to in WriteGBuffers pass
     pub material: Texture,
-    /// Occlusion factor of fragment, RBGA16Float
-    /// Written to in SSAO pass
+    /// Ambient occlusion of fragment, RBGA16Float. r = diffuse visibility, g = specular
+    /// visibility, ba = bent normal as an offset from the normal in octahedral encoding (0 = the
+    /// normal itself, see compose.wgsl)
+    /// Written to in SSAO or GTAO pass
     pub occlusion: Texture,
-    /// Shadow buffer of fragment, R16Float
-    /// Written to in WriteShadowmaps pass
-    pub shadow: Texture,
+    /// Emitted radiance of fragment (linear, HDR), RGBA16Float. Added on top of the lit color
+    /// in Compose
+    /// Written to in WriteGBuffers pass
+    pub emissive: Texture,
     pub bind_group: wgpu::BindGroup,
 }
 
 impl GBuffers {
+    /// Value of the occlusion target when nothing is occluded
+    pub const NO_OCCLUSION: wgpu::Color = wgpu::Color {
+        r: 1.0,
+        g: 1.0,
+        b: 0.0,
+        a: 0.0,
+    };
+
     pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Self {
         let depth = Texture::new_depth_texture(device, config, false);
 
@@ -68,13 +79,13 @@ impl GBuffers {
             false,
         );
 
-        let shadow = Texture::new(
+        let emissive = Texture::new(
             device,
             config.width,
             config.height,
-            wgpu::TextureFormat::R16Float,
+            wgpu::TextureFormat::Rgba16Float,
             TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
-            Some("Gbuffers - Shadow"),
+            Some("Gbuffers - emissive"),
             false,
         );
 
@@ -104,7 +115,7 @@ impl GBuffers {
                 },
                 BindGroupEntry {
                     binding: 5,
-                    resource: wgpu::BindingResource::TextureView(&shadow.view),
+                    resource: wgpu::BindingResource::TextureView(&emissive.view),
                 },
             ],
         });
@@ -115,7 +126,7 @@ impl GBuffers {
             normal,
             material,
             occlusion,
-            shadow,
+            emissive,
             bind_group,
         }
     }
@@ -187,4 +198,22 @@ impl GBuffers {
             ],
         })
     }
+
+    /// Used instead of an AO pass when AO is off, so Compose sees no occlusion.
+    pub fn clear_occlusion(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Clear occlusion"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.occlusion.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(GBuffers::NO_OCCLUSION),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+    }
 }
@@ -1,5 +1,8 @@
-use glam::{vec3, Mat4, Quat, Vec3, Vec4};
+use std::{collections::HashMap, error::Error, fmt, fs, ops::Range, path::Path, rc::Rc};
+
+use glam::{vec3, vec4, Mat4, Quat, Vec3, Vec4};
 use gltf::{buffer::Data, image::Format};
+use half::f16;
 use mikktspace::generate_tangents;
 
 use wgpu::{
@@ -9,376 +12,909 @@ use wgpu::{
 
 use crate::{
     common::VertexAttributes,
+    mipmaps::{MipFilter, MipmapGenerator},
     resources::{
-        LightingUniform, LightingUniformData, Material, MaterialUniformData, Mesh, MeshUniformData,
-        SceneUniform, SceneUniformData,
+        AlphaMode, Light, LightKind, LightingUniform, Material, MaterialUniformData, Mesh,
+        MeshInstance, MeshInstances, SceneUniform, SceneUniformData,
     },
     tangent_generation::TangentGenerator,
-    texture::Texture,
+    texture::{BytesDescriptor, MaterialSamplerDescriptor, Sampler, Texture},
 };
 
 pub struct Scene {
     pub meshes: Vec<Mesh>,
+    pub instances: MeshInstances,
     pub materials: Vec<Material>,
     pub scene: SceneUniform,
+    /// From the file's KHR_lights_punctual lights, or a default rig if it has none
+    pub lights: Vec<Light>,
     pub lighting: LightingUniform,
 }
 
 #[derive(Debug)]
 pub enum SceneLoadError {
-    Message(String),
+    /// The glTF file, or a buffer/image it references, couldn't be read
+    Io(std::io::Error),
+    /// The file isn't valid glTF, or one of its buffers/images couldn't be decoded
+    Gltf(gltf::Error),
+    /// The file has no scenes to load
+    NoScene,
+    /// A primitive is missing something the renderer needs (attribute is the glTF name, e.g.
+    /// POSITION)
+    MissingAttribute {
+        mesh: String,
+        primitive: usize,
+        attribute: &'static str,
+    },
+    /// An image is in a pixel format that can't be converted to a texture
+    UnsupportedImageFormat { image: usize, format: Format },
+}
+
+impl From<gltf::Error> for SceneLoadError {
+    fn from(value: gltf::Error) -> Self {
+        match value {
+            gltf::Error::Io(x) => SceneLoadError::Io(x),
+            x => SceneLoadError::Gltf(x),
+        }
+    }
+}
+
+impl fmt::Display for SceneLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneLoadError::Io(x) => write!(f, "couldn't read file: {}", x),
+            SceneLoadError::Gltf(x) => write!(f, "invalid glTF: {}", x),
+            SceneLoadError::NoScene => write!(f, "file has no scene"),
+            SceneLoadError::MissingAttribute {
+                mesh,
+                primitive,
+                attribute,
+            } => write!(
+                f,
+                "primitive {} of mesh \"{}\" has no {}",
+                primitive, mesh, attribute
+            ),
+            SceneLoadError::UnsupportedImageFormat { image, format } => {
+                write!(f, "image {} has unsupported format {:?}", image, format)
+            }
+        }
+    }
+}
+
+impl Error for SceneLoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SceneLoadError::Io(x) => Some(x),
+            SceneLoadError::Gltf(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+/// How a texture's colors are encoded. Base color and emissive textures are sRGB, everything
+/// else (normals, metallic-roughness, occlusion) is linear data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+/// Fill in missing channels the way the image crate reads them: one channel is luminance, two
+/// are luminance + alpha, and alpha defaults to opaque.
+fn expand_to_rgba<T: Copy>(pixel: &[T], opaque: T) -> [T; 4] {
+    match pixel.len() {
+        1 => [pixel[0], pixel[0], pixel[0], opaque],
+        2 => [pixel[0], pixel[0], pixel[0], pixel[1]],
+        3 => [pixel[0], pixel[1], pixel[2], opaque],
+        _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+    }
+}
+
+fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert glTF image data to bytes a texture can be created from. 8 bit images become Rgba8
+/// (sRGB or not, so the GPU decodes sRGB), 16 bit and float images become Rgba16Float with sRGB
+/// decoded on the CPU (float images are taken to be linear already). Images with fewer channels
+/// are always expanded to RGBA, not uploaded as R8/Rg8: a gray metallic-roughness map has to
+/// read the same in its g and b channels.
+fn convert_image(
+    image: &gltf::image::Data,
+    index: usize,
+    color_space: ColorSpace,
+) -> Result<(Vec<u8>, wgpu::TextureFormat), SceneLoadError> {
+    let channels = match image.format {
+        Format::R8 | Format::R16 => 1,
+        Format::R8G8 | Format::R16G16 => 2,
+        Format::R8G8B8 | Format::R16G16B16 | Format::R32G32B32FLOAT => 3,
+        _ => 4,
+    };
+
+    let to_rgba16_float = |values: Vec<f32>, color_space: ColorSpace| {
+        values
+            .chunks(channels)
+            .flat_map(|x| {
+                let mut rgba = expand_to_rgba(x, 1.0);
+                if color_space == ColorSpace::Srgb {
+                    for channel in &mut rgba[..3] {
+                        *channel = srgb_to_linear(*channel);
+                    }
+                }
+                rgba.map(f16::from_f32)
+            })
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<u8>>()
+    };
+
+    match image.format {
+        Format::R8 | Format::R8G8 | Format::R8G8B8 | Format::R8G8B8A8 => {
+            let data = image
+                .pixels
+                .chunks(channels)
+                .flat_map(|x| expand_to_rgba(x, u8::MAX))
+                .collect::<Vec<u8>>();
+            let format = match color_space {
+                ColorSpace::Srgb => wgpu::TextureFormat::Rgba8UnormSrgb,
+                ColorSpace::Linear => wgpu::TextureFormat::Rgba8Unorm,
+            };
+            Ok((data, format))
+        }
+        Format::R16 | Format::R16G16 | Format::R16G16B16 | Format::R16G16B16A16 => {
+            let values = image
+                .pixels
+                .chunks(2)
+                .map(|x| u16::from_ne_bytes([x[0], x[1]]) as f32 / u16::MAX as f32)
+                .collect::<Vec<f32>>();
+            Ok((
+                to_rgba16_float(values, color_space),
+                wgpu::TextureFormat::Rgba16Float,
+            ))
+        }
+        Format::R32G32B32FLOAT | Format::R32G32B32A32FLOAT => {
+            let values = image
+                .pixels
+                .chunks(4)
+                .map(|x| f32::from_ne_bytes([x[0], x[1], x[2], x[3]]))
+                .collect::<Vec<f32>>();
+            Ok((
+                to_rgba16_float(values, ColorSpace::Linear),
+                wgpu::TextureFormat::Rgba16Float,
+            ))
+        }
+        // Formats older/newer gltf versions might add
+        #[allow(unreachable_patterns)]
+        format => Err(SceneLoadError::UnsupportedImageFormat {
+            image: index,
+            format,
+        }),
+    }
+}
+
+/// Creates a scene's material textures: converts the image, uploads it and generates mipmaps.
+struct TextureLoader<'a> {
+    device: &'a wgpu::Device,
+    queue: &'a wgpu::Queue,
+    images: &'a [gltf::image::Data],
+    mipmaps: MipmapGenerator,
+    samplers: HashMap<MaterialSamplerDescriptor, Rc<Sampler>>,
+    /// Keyed by mip filter too, since a normal map's mips differ from the same image's color mips
+    textures: HashMap<(usize, ColorSpace, MipFilter), Rc<Texture>>,
+    default_textures: HashMap<([u8; 4], ColorSpace), Rc<Texture>>,
+}
+
+impl<'a> TextureLoader<'a> {
+    fn new(
+        device: &'a wgpu::Device,
+        queue: &'a wgpu::Queue,
+        images: &'a [gltf::image::Data],
+    ) -> Self {
+        Self {
+            device,
+            queue,
+            images,
+            mipmaps: MipmapGenerator::new(device),
+            samplers: HashMap::new(),
+            textures: HashMap::new(),
+            default_textures: HashMap::new(),
+        }
+    }
+
+    /// Get the sampler for a descriptor, creating it the first time it's asked for.
+    fn sampler(&mut self, descriptor: MaterialSamplerDescriptor) -> Rc<Sampler> {
+        self.samplers
+            .entry(descriptor)
+            .or_insert_with(|| Rc::new(Sampler::material_sampler(self.device, descriptor)))
+            .clone()
+    }
+
+    /// Get a material texture along with the sampler for its glTF sampler settings, or a 1x1
+    /// texture of default (with the default sampler) if the material doesn't have it. Each image
+    /// is only uploaded once per color space and mip filter, later materials share the texture.
+    fn load(
+        &mut self,
+        texture: Option<gltf::Texture>,
+        color_space: ColorSpace,
+        mip_filter: MipFilter,
+        default: [u8; 4],
+    ) -> Result<(Rc<Texture>, Rc<Sampler>), SceneLoadError> {
+        match texture {
+            Some(texture) => {
+                let sampler = self.sampler(sampler_descriptor(&texture.sampler()));
+                let source = texture.source();
+                let key = (source.index(), color_space, mip_filter);
+                if let Some(texture) = self.textures.get(&key) {
+                    return Ok((texture.clone(), sampler));
+                }
+
+                let index = source.index();
+                let image = &self.images[index];
+                let (data, format) = convert_image(image, index, color_space)?;
+
+                let label = match source.name() {
+                    Some(name) => format!("Image \"{}\" ({:?})", name, color_space),
+                    None => format!("Image {} ({:?})", index, color_space),
+                };
+                let texture = Texture::new_from_bytes_with_mips(
+                    self.device,
+                    self.queue,
+                    data.as_slice(),
+                    BytesDescriptor {
+                        label: Some(label.as_str()),
+                        width: image.width,
+                        height: image.height,
+                        format,
+                        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                    },
+                );
+                self.mipmaps
+                    .generate(self.device, self.queue, &texture, mip_filter);
+
+                let texture = Rc::new(texture);
+                self.textures.insert(key, texture.clone());
+                Ok((texture, sampler))
+            }
+            None => {
+                let sampler = self.sampler(MaterialSamplerDescriptor::default());
+                let texture = self
+                    .default_textures
+                    .entry((default, color_space))
+                    .or_insert_with(|| {
+                        Rc::new(Texture::new_1x1_texture(
+                            self.device,
+                            self.queue,
+                            &default,
+                            match color_space {
+                                ColorSpace::Srgb => wgpu::TextureFormat::Rgba8UnormSrgb,
+                                ColorSpace::Linear => wgpu::TextureFormat::Rgba8Unorm,
+                            },
+                            TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                            Some(
+                                format!("Default texture {:?} ({:?})", default, color_space)
+                                    .as_str(),
+                            ),
+                        ))
+                    })
+                    .clone();
+                Ok((texture, sampler))
+            }
+        }
+    }
+}
+
+/// Translate a glTF sampler. Missing filters default to trilinear; min filters without a mipmap
+/// mode sample only the base level, as the spec intends.
+fn sampler_descriptor(sampler: &gltf::texture::Sampler) -> MaterialSamplerDescriptor {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    use wgpu::{AddressMode, FilterMode};
+
+    let address_mode = |mode: WrappingMode| match mode {
+        WrappingMode::ClampToEdge => AddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => AddressMode::MirrorRepeat,
+        WrappingMode::Repeat => AddressMode::Repeat,
+    };
+
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => FilterMode::Nearest,
+        Some(MagFilter::Linear) | None => FilterMode::Linear,
+    };
+
+    let (min_filter, mipmap_filter) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) => (FilterMode::Nearest, None),
+        Some(MinFilter::Linear) => (FilterMode::Linear, None),
+        Some(MinFilter::NearestMipmapNearest) => (FilterMode::Nearest, Some(FilterMode::Nearest)),
+        Some(MinFilter::LinearMipmapNearest) => (FilterMode::Linear, Some(FilterMode::Nearest)),
+        Some(MinFilter::NearestMipmapLinear) => (FilterMode::Nearest, Some(FilterMode::Linear)),
+        Some(MinFilter::LinearMipmapLinear) | None => {
+            (FilterMode::Linear, Some(FilterMode::Linear))
+        }
+    };
+
+    MaterialSamplerDescriptor {
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        mag_filter,
+        min_filter,
+        mipmap_filter,
+    }
+}
+
+/// Lumens of each default light, floodlight strength so they still show up at daylight exposure
+const DEFAULT_LIGHT_POWER: f32 = 500_000.0;
+
+/// Used when a file doesn't have any lights of its own.
+fn default_lights() -> Vec<Light> {
+    vec![
+        Light::point(vec3(-4.0, 4.0, -1.0), Vec3::ONE, DEFAULT_LIGHT_POWER),
+        Light::point(vec3(4.0, 4.0, -1.0), Vec3::ONE, DEFAULT_LIGHT_POWER),
+        Light::point(vec3(-4.0, -4.0, -1.0), Vec3::ONE, DEFAULT_LIGHT_POWER),
+        Light::point(vec3(4.0, -4.0, -1.0), Vec3::ONE, DEFAULT_LIGHT_POWER),
+    ]
+}
+
+/// Place a KHR_lights_punctual light with its node's world transform. Lights shine down their
+/// local -z, which is +z after flipping.
+fn punctual_light(gltf_light: &gltf::khr_lights_punctual::Light, transform: Mat4) -> Light {
+    use gltf::khr_lights_punctual::Kind;
+
+    let mut light = Light {
+        kind: match gltf_light.kind() {
+            Kind::Point => LightKind::Point,
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            },
+            Kind::Directional => LightKind::Directional,
+        },
+        position: transform.transform_point3(Vec3::ZERO),
+        direction: transform.transform_vector3(Vec3::Z).normalize_or_zero(),
+        color: gltf_light.color().into(),
+        intensity: 0.0,
+        range: gltf_light.range(),
+        casts_shadows: true,
+    };
+    // KHR_lights_punctual intensities are candela (lux for directional lights)
+    light.set_candela(gltf_light.intensity());
+    light
+}
+
+/// Matrix for a glTF translation/rotation/scale, flipped on z like the vertex data.
+fn transform_from_trs(translation: [f32; 3], rotation: [f32; 4], scale: [f32; 3]) -> Mat4 {
+    let rotation_fixed = [
+        rotation[0],
+        rotation[1],
+        rotation[2] * -1.0,
+        rotation[3] * -1.0,
+    ];
+    let translation_fixed = [translation[0], translation[1], translation[2] * -1.0];
+    Mat4::from_scale_rotation_translation(
+        scale.into(),
+        Quat::from_array(rotation_fixed),
+        translation_fixed.into(),
+    )
+}
+
+/// Extensions the loader implements that the gltf crate doesn't know about, it would reject files
+/// listing them in extensionsRequired
+const LOADER_EXTENSIONS: &[&str] = &["EXT_mesh_gpu_instancing"];
+
+/// gltf::import, except files requiring one of LOADER_EXTENSIONS pass validation.
+fn import(path: &Path) -> gltf::Result<(gltf::Document, Vec<Data>, Vec<gltf::image::Data>)> {
+    let bytes = fs::read(path).map_err(gltf::Error::Io)?;
+    let gltf::Gltf { document, blob } = gltf::Gltf::from_slice_without_validation(&bytes)?;
+
+    let mut json = document.into_json();
+    json.extensions_required
+        .retain(|x| !LOADER_EXTENSIONS.contains(&x.as_str()));
+    let document = gltf::Document::from_json(json)?;
+
+    let base = path.parent();
+    let buffers = gltf::import_buffers(&document, base, blob)?;
+    let images = gltf::import_images(&document, base, &buffers)?;
+    Ok((document, buffers, images))
+}
+
+/// Instance transforms (relative to the node) of a node using EXT_mesh_gpu_instancing, None if it
+/// doesn't use the extension.
+fn gpu_instances(
+    document: &gltf::Document,
+    node: &gltf::Node,
+    buffers: &[Data],
+) -> Option<Vec<Mat4>> {
+    use gltf::{
+        accessor::{DataType, Iter},
+        animation::util::Rotations,
+    };
+
+    let attributes = node
+        .extension_value("EXT_mesh_gpu_instancing")?
+        .get("attributes")?;
+    let accessor = |name: &str| {
+        attributes
+            .get(name)
+            .and_then(|x| x.as_u64())
+            .and_then(|x| document.accessors().nth(x as usize))
+    };
+    let get_buffer = |buffer: gltf::Buffer| buffers.get(buffer.index()).map(|x| x.0.as_slice());
+
+    let translations = accessor("TRANSLATION")
+        .and_then(|x| Iter::<[f32; 3]>::new(x, get_buffer))
+        .map(|x| x.collect::<Vec<_>>());
+    // Rotations can also be normalized integers
+    let rotations = accessor("ROTATION")
+        .and_then(|x| match x.data_type() {
+            DataType::I8 => Iter::new(x, get_buffer).map(Rotations::I8),
+            DataType::U8 => Iter::new(x, get_buffer).map(Rotations::U8),
+            DataType::I16 => Iter::new(x, get_buffer).map(Rotations::I16),
+            DataType::U16 => Iter::new(x, get_buffer).map(Rotations::U16),
+            DataType::F32 => Iter::new(x, get_buffer).map(Rotations::F32),
+            DataType::U32 => None,
+        })
+        .map(|x| x.into_f32().collect::<Vec<_>>());
+    let scales = accessor("SCALE")
+        .and_then(|x| Iter::<[f32; 3]>::new(x, get_buffer))
+        .map(|x| x.collect::<Vec<_>>());
+
+    let count = translations
+        .as_ref()
+        .map(Vec::len)
+        .or(rotations.as_ref().map(Vec::len))
+        .or(scales.as_ref().map(Vec::len))?;
+
+    Some(
+        (0..count)
+            .map(|i| {
+                transform_from_trs(
+                    translations
+                        .as_ref()
+                        .and_then(|x| x.get(i).copied())
+                        .unwrap_or([0.0, 0.0, 0.0]),
+                    rotations
+                        .as_ref()
+                        .and_then(|x| x.get(i).copied())
+                        .unwrap_or([0.0, 0.0, 0.0, 1.0]),
+                    scales
+                        .as_ref()
+                        .and_then(|x| x.get(i).copied())
+                        .unwrap_or([1.0, 1.0, 1.0]),
+                )
+            })
+            .collect(),
+    )
 }
 
 impl Scene {
-    pub fn load_mesh<'a>(
-        device: &wgpu::Device,
+    /// Collect the world transform of every instance of every mesh under node (indexed by glTF
+    /// mesh index), and every light.
+    fn gather_node(
+        document: &gltf::Document,
         node: &gltf::Node,
-        original_transform: Mat4,
-        buffers: &Vec<Data>,
-    ) -> Result<Vec<Mesh>, SceneLoadError> {
+        parent_transform: Mat4,
+        buffers: &[Data],
+        instances: &mut [Vec<Mat4>],
+        lights: &mut Vec<Light>,
+    ) {
         let (translation, rotation, scale) = node.transform().decomposed();
+        let transform = parent_transform * transform_from_trs(translation, rotation, scale);
 
-        let rotation_fixed = [
-            rotation[0],
-            rotation[1],
-            rotation[2] * -1.0,
-            rotation[3] * -1.0,
-        ];
-        let translation_fixed = [translation[0], translation[1], translation[2] * -1.0];
-        let transform = original_transform
-            * Mat4::from_scale_rotation_translation(
-                scale.into(),
-                Quat::from_array(rotation_fixed),
-                translation_fixed.into(),
-            );
+        if let Some(mesh) = node.mesh() {
+            match gpu_instances(document, node, buffers) {
+                Some(local) => {
+                    instances[mesh.index()].extend(local.into_iter().map(|x| transform * x))
+                }
+                None => instances[mesh.index()].push(transform),
+            }
+        }
+
+        if let Some(light) = node.light() {
+            lights.push(punctual_light(&light, transform));
+        }
 
+        for child in node.children() {
+            Scene::gather_node(document, &child, transform, buffers, instances, lights);
+        }
+    }
+
+    /// Upload each primitive of a mesh once, drawn for the given range of instances. Instances
+    /// from first_mirrored on have a negative determinant. Primitives without a material get
+    /// default_material.
+    pub fn load_mesh(
+        device: &wgpu::Device,
+        mesh: &gltf::Mesh,
+        buffers: &[Data],
+        instances: Range<u32>,
+        first_mirrored: u32,
+        default_material: usize,
+    ) -> Result<Vec<Mesh>, SceneLoadError> {
         let mut meshes: Vec<Mesh> = Vec::new();
 
-        if let Some(mesh) = node.mesh() {
-            for primitive in mesh.primitives() {
-                let reader = primitive.reader(|buffer| {
-                    if buffer.index() < buffers.len() {
-                        Some(buffers[buffer.index()].0.as_slice())
-                    } else {
-                        None
-                    }
-                });
-                let material = primitive.material().index().unwrap_or(0);
-
-                let indices = reader
-                    .read_indices()
-                    .ok_or(SceneLoadError::Message(String::from(
-                        "Couldn't load indices",
-                    )))?
-                    .into_u32()
-                    .collect::<Vec<_>>();
-                let positions = reader
-                    .read_positions()
-                    .ok_or(SceneLoadError::Message(String::from(
-                        "Couldn't load positions",
-                    )))?
-                    .map(|pos| [pos[0], pos[1], pos[2] * -1.0]);
-                let normals = reader
-                    .read_normals()
-                    .ok_or(SceneLoadError::Message(String::from(
-                        "Couldn't load normals.",
-                    )))?
-                    .map(|pos| [pos[0], pos[1], pos[2] * -1.0]);
-                let uvs = reader
-                    .read_tex_coords(0)
-                    .ok_or(SceneLoadError::Message(String::from("Couldn't load uvs.")))?
-                    .into_f32();
-
-                let tangents = reader.read_tangents();
-
-                let mut vertices = if tangents.is_some() {
-                    positions
-                        .zip(normals.zip(tangents.clone().unwrap().zip(uvs)))
-                        .map(|(position, (normal, (tangent, uv)))| VertexAttributes {
-                            position,
-                            normal,
-                            uv,
-                            tangent: [tangent[0], tangent[1], tangent[2] * -1.0], // TODO: investigate handedness
-                        })
-                        .collect::<Vec<_>>()
+        for primitive in mesh.primitives() {
+            let reader = primitive.reader(|buffer| {
+                if buffer.index() < buffers.len() {
+                    Some(buffers[buffer.index()].0.as_slice())
                 } else {
-                    positions
-                        .zip(normals.zip(uvs))
-                        .map(|(position, (normal, uv))| VertexAttributes {
-                            position,
-                            normal,
-                            uv,
-                            tangent: [0.0, 0.0, 0.0], // TODO: investigate handedness
-                        })
-                        .collect::<Vec<_>>()
-                };
-
-                if tangents.is_none() {
-                    let mut tangent_generator = TangentGenerator {
-                        vertices: vertices.clone(),
-                        indices: indices.clone(),
-                    };
+                    None
+                }
+            });
+            let material = primitive.material().index().unwrap_or(default_material);
+            let missing = |attribute| SceneLoadError::MissingAttribute {
+                mesh: mesh
+                    .name()
+                    .map(String::from)
+                    .unwrap_or_else(|| mesh.index().to_string()),
+                primitive: primitive.index(),
+                attribute,
+            };
 
-                    generate_tangents(&mut tangent_generator);
+            let indices = reader
+                .read_indices()
+                .ok_or_else(|| missing("indices"))?
+                .into_u32()
+                .collect::<Vec<_>>();
+            let positions = reader
+                .read_positions()
+                .ok_or_else(|| missing("POSITION"))?
+                .map(|pos| [pos[0], pos[1], pos[2] * -1.0]);
+            let normals = reader
+                .read_normals()
+                .ok_or_else(|| missing("NORMAL"))?
+                .map(|pos| [pos[0], pos[1], pos[2] * -1.0]);
+            let uvs = reader
+                .read_tex_coords(0)
+                .ok_or_else(|| missing("TEXCOORD_0"))?
+                .into_f32();
+
+            let tangents = reader.read_tangents();
+
+            let mut vertices = if tangents.is_some() {
+                positions
+                    .zip(normals.zip(tangents.clone().unwrap().zip(uvs)))
+                    .map(|(position, (normal, (tangent, uv)))| VertexAttributes {
+                        position,
+                        normal,
+                        uv,
+                        tangent: [tangent[0], tangent[1], tangent[2] * -1.0], // TODO: investigate handedness
+                        uv_1: uv,
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                positions
+                    .zip(normals.zip(uvs))
+                    .map(|(position, (normal, uv))| VertexAttributes {
+                        position,
+                        normal,
+                        uv,
+                        tangent: [0.0, 0.0, 0.0], // TODO: investigate handedness
+                        uv_1: uv,
+                    })
+                    .collect::<Vec<_>>()
+            };
 
-                    vertices = tangent_generator.vertices;
+            if let Some(uvs_1) = reader.read_tex_coords(1) {
+                for (vertex, uv_1) in vertices.iter_mut().zip(uvs_1.into_f32()) {
+                    vertex.uv_1 = uv_1;
                 }
+            }
 
-                let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
-                    label: Some(
-                        format!("Vertex buffer for {}", mesh.name().unwrap_or("")).as_str(),
-                    ),
-                    contents: bytemuck::cast_slice(vertices.as_slice()),
-                    usage: BufferUsages::COPY_DST | BufferUsages::VERTEX,
-                });
-
-                let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
-                    label: Some(format!("Index buffer for {}", mesh.name().unwrap_or("")).as_str()),
-                    contents: bytemuck::cast_slice(indices.as_slice()),
-                    usage: BufferUsages::COPY_DST | BufferUsages::INDEX,
-                });
-
-                meshes.push(Mesh::new(
-                    device,
-                    vertex_buffer,
-                    index_buffer,
-                    vertices.len() as u32,
-                    indices.len() as u32,
-                    MeshUniformData::new(transform),
-                    material,
-                    Some(format!("{}", mesh.name().unwrap_or("Mesh")).as_str()),
-                ));
+            if tangents.is_none() {
+                let mut tangent_generator = TangentGenerator {
+                    vertices: vertices.clone(),
+                    indices: indices.clone(),
+                };
+
+                generate_tangents(&mut tangent_generator);
+
+                vertices = tangent_generator.vertices;
             }
-        }
 
-        for child in node.children() {
-            meshes.append(&mut Scene::load_mesh(device, &child, transform, buffers)?);
+            let (min, max) = vertices.iter().fold(
+                (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                |(min, max), vertex| {
+                    let position = Vec3::from(vertex.position);
+                    (min.min(position), max.max(position))
+                },
+            );
+            let center = (min + max) * 0.5;
+
+            let vertex_buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some(format!("Vertex buffer for {}", mesh.name().unwrap_or("")).as_str()),
+                contents: bytemuck::cast_slice(vertices.as_slice()),
+                usage: BufferUsages::COPY_DST | BufferUsages::VERTEX,
+            });
+
+            let index_buffer = device.create_buffer_init(&BufferInitDescriptor {
+                label: Some(format!("Index buffer for {}", mesh.name().unwrap_or("")).as_str()),
+                contents: bytemuck::cast_slice(indices.as_slice()),
+                usage: BufferUsages::COPY_DST | BufferUsages::INDEX,
+            });
+
+            meshes.push(Mesh::new(
+                vertex_buffer,
+                index_buffer,
+                indices.len() as u32,
+                material,
+                center,
+                instances.clone(),
+                first_mirrored,
+            ));
         }
 
         Ok(meshes)
     }
 
-    pub fn from_gltf<'a>(
+    /// Create a material's uniform and textures.
+    fn load_material(
+        device: &wgpu::Device,
+        textures: &mut TextureLoader,
+        material: &gltf::Material,
+    ) -> Result<Material, SceneLoadError> {
+        let pbr = material.pbr_metallic_roughness();
+        let alpha_mode = match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+            gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+            gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+        };
+
+        let material_data = MaterialUniformData {
+            ambient: pbr.base_color_factor().into(),
+            diffuse: {
+                let x = pbr.roughness_factor();
+                Vec4::splat(x)
+            },
+            specular: {
+                let x = pbr.metallic_factor();
+                Vec4::splat(x)
+            },
+            occlusion: match material.occlusion_texture() {
+                Some(x) => vec4(x.strength(), x.tex_coord() as f32, 0.0, 0.0),
+                None => vec4(1.0, 0.0, 0.0, 0.0),
+            },
+            emissive: (Vec3::from(material.emissive_factor())
+                * material.emissive_strength().unwrap_or(1.0))
+            .extend(1.0),
+            alpha: vec4(
+                material.alpha_cutoff().unwrap_or(0.5),
+                alpha_mode as u32 as f32,
+                0.0,
+                0.0,
+            ),
+        };
+
+        let albedo_texture = textures.load(
+            pbr.base_color_texture().map(|x| x.texture()),
+            ColorSpace::Srgb,
+            MipFilter::Color,
+            [255, 255, 255, 255],
+        )?;
+        let normal_texture = textures.load(
+            material.normal_texture().map(|x| x.texture()),
+            ColorSpace::Linear,
+            MipFilter::Normal,
+            [128, 128, 255, 255],
+        )?;
+        let metal_roughness_texture = textures.load(
+            pbr.metallic_roughness_texture().map(|x| x.texture()),
+            ColorSpace::Linear,
+            MipFilter::Color,
+            [255, 128, 128, 255],
+        )?;
+        let occlusion_texture = textures.load(
+            material.occlusion_texture().map(|x| x.texture()),
+            ColorSpace::Linear,
+            MipFilter::Color,
+            [255, 255, 255, 255],
+        )?;
+        // Default is white so the emissive factor alone controls emission (factor defaults to
+        // black, so materials without emission stay unlit)
+        let emissive_texture = textures.load(
+            material.emissive_texture().map(|x| x.texture()),
+            ColorSpace::Srgb,
+            MipFilter::Color,
+            [255, 255, 255, 255],
+        )?;
+
+        Ok(Material::new(
+            device,
+            material_data,
+            albedo_texture,
+            normal_texture,
+            metal_roughness_texture,
+            occlusion_texture,
+            emissive_texture,
+            alpha_mode,
+            material.double_sided(),
+        ))
+    }
+
+    pub fn from_gltf(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         path: &String,
     ) -> Result<Self, SceneLoadError> {
-        let (document, buffers, images) = gltf::import(path).unwrap();
+        let (document, buffers, images) = import(Path::new(path))?;
 
-        let scene = document.default_scene().unwrap();
+        // Files without a default scene can still have scenes, take the first one
+        let scene = document
+            .default_scene()
+            .or_else(|| document.scenes().next())
+            .ok_or(SceneLoadError::NoScene)?;
 
         let mut meshes: Vec<Mesh> = Vec::new();
-        let mut materials: Vec<Material> = Vec::new();
 
-        // TODO: get this to visit full node tree
+        let mut mesh_transforms = vec![Vec::new(); document.meshes().len()];
+        let mut lights: Vec<Light> = Vec::new();
         for node in scene.nodes() {
-            meshes.append(&mut Scene::load_mesh(
-                device,
+            Scene::gather_node(
+                &document,
                 &node,
                 Mat4::IDENTITY,
                 &buffers,
-            )?);
+                &mut mesh_transforms,
+                &mut lights,
+            );
+        }
+        if lights.is_empty() {
+            lights = default_lights();
         }
 
-        for material in document.materials() {
-            let pbr = material.pbr_metallic_roughness();
-
-            let material_data = MaterialUniformData {
-                ambient: pbr.base_color_factor().into(),
-                diffuse: {
-                    let x = pbr.roughness_factor();
-                    Vec4::splat(x)
-                },
-                specular: {
-                    let x = pbr.metallic_factor();
-                    Vec4::splat(x)
-                },
-            };
-
-            let albedo_texture = if let Some(texture_info) = pbr.base_color_texture() {
-                let image = &images[texture_info.texture().source().index()];
-
-                let mut data: Vec<u8> = Vec::new();
-                println!("Diffuse format: {:?}", image.format);
-                let slice = match image.format {
-                    Format::R8G8B8A8 => image.pixels.as_slice(),
-                    Format::R8G8B8 => {
-                        for rgb in image.pixels.chunks(3) {
-                            data.push(rgb[0]);
-                            data.push(rgb[1]);
-                            data.push(rgb[2]);
-                            data.push(u8::MAX);
-                        }
-                        data.as_slice()
-                    }
-                    _ => todo!(),
-                };
-
-                Texture::new_from_bytes(
-                    device,
-                    queue,
-                    slice,
-                    image.width,
-                    image.height,
-                    wgpu::TextureFormat::Rgba8UnormSrgb,
-                    TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                    Some(format!("Color texture for {}", material.name().unwrap_or("")).as_str()),
-                    false,
-                )
-            } else {
-                Texture::new_1x1_texture(
-                    device,
-                    queue,
-                    &[255, 255, 255, 255],
-                    wgpu::TextureFormat::Rgba8UnormSrgb,
-                    TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                    Some(format!("Color texture for {}", material.name().unwrap_or("")).as_str()),
-                )
-            };
-
-            let normal_texture = if let Some(texture_info) = material.normal_texture() {
-                let image = &images[texture_info.texture().source().index()];
-
-                let mut data: Vec<u8> = Vec::new();
-                println!("Normal format: {:?}", image.format);
-                let slice = match image.format {
-                    Format::R8G8B8A8 => image.pixels.as_slice(),
-                    Format::R8G8B8 => {
-                        for rgb in image.pixels.chunks(3) {
-                            data.push(rgb[0]);
-                            data.push(rgb[1]);
-                            data.push(rgb[2]);
-                            data.push(u8::MAX);
-                        }
-                        data.as_slice()
-                    }
-                    _ => todo!(),
-                };
-
-                Texture::new_from_bytes(
-                    device,
-                    queue,
-                    slice,
-                    image.width,
-                    image.height,
-                    wgpu::TextureFormat::Rgba8Unorm,
-                    TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                    Some(format!("Normal texture for {}", material.name().unwrap_or("")).as_str()),
-                    false,
-                )
-            } else {
-                Texture::new_1x1_texture(
-                    device,
-                    queue,
-                    &[128, 128, 255, 255],
-                    wgpu::TextureFormat::Rgba8Unorm,
-                    TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                    Some(format!("Normal texture for {}", material.name().unwrap_or("")).as_str()),
-                )
-            };
+        // Each mesh's buffers are made once, its instances are laid out contiguously with the
+        // mirrored ones last so they can be drawn with the opposite winding
+        let mut instances: Vec<MeshInstance> = Vec::new();
+        for mesh in document.meshes() {
+            let transforms = &mesh_transforms[mesh.index()];
+            if transforms.is_empty() {
+                // Not used by this scene
+                continue;
+            }
 
-            let metal_roughness_texture =
-                if let Some(texture_info) = pbr.metallic_roughness_texture() {
-                    let image = &images[texture_info.texture().source().index()];
-
-                    let mut data: Vec<u8> = Vec::new();
-                    println!("Normal format: {:?}", image.format);
-                    let slice = match image.format {
-                        Format::R8G8B8A8 => image.pixels.as_slice(),
-                        Format::R8G8B8 => {
-                            for rgb in image.pixels.chunks(3) {
-                                data.push(rgb[0]);
-                                data.push(rgb[1]);
-                                data.push(rgb[2]);
-                                data.push(u8::MAX);
-                            }
-                            data.as_slice()
-                        }
-                        _ => todo!(),
-                    };
-
-                    Texture::new_from_bytes(
-                        device,
-                        queue,
-                        slice,
-                        image.width,
-                        image.height,
-                        wgpu::TextureFormat::Rgba8Unorm,
-                        TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                        Some(
-                            format!(
-                                "Metallic-roughness texture for {}",
-                                material.name().unwrap_or("")
-                            )
-                            .as_str(),
-                        ),
-                        false,
-                    )
-                } else {
-                    Texture::new_1x1_texture(
-                        device,
-                        queue,
-                        &[255, 128, 128, 255],
-                        wgpu::TextureFormat::Rgba8Unorm,
-                        TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                        Some(
-                            format!(
-                                "Metallic-roughness texture for {}",
-                                material.name().unwrap_or("")
-                            )
-                            .as_str(),
-                        ),
-                    )
-                };
-            materials.push(Material::new(
+            let start = instances.len() as u32;
+            let (unmirrored, mirrored): (Vec<Mat4>, Vec<Mat4>) = transforms
+                .iter()
+                .copied()
+                .partition(|x| x.determinant() >= 0.0);
+            instances.extend(unmirrored.iter().map(|x| MeshInstance::new(*x)));
+            let first_mirrored = instances.len() as u32;
+            instances.extend(mirrored.iter().map(|x| MeshInstance::new(*x)));
+            meshes.append(&mut Scene::load_mesh(
                 device,
-                material_data,
-                albedo_texture,
-                normal_texture,
-                metal_roughness_texture,
-            ));
+                &mesh,
+                &buffers,
+                start..instances.len() as u32,
+                first_mirrored,
+                document.materials().len(),
+            )?);
+        }
+
+        let mut textures = TextureLoader::new(device, queue, &images);
+        let mut materials = document
+            .materials()
+            .map(|x| Scene::load_material(device, &mut textures, &x))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Primitives without a material use glTF's default material, after the file's own
+        if let Some(material) = document
+            .meshes()
+            .flat_map(|x| x.primitives())
+            .map(|x| x.material())
+            .find(|x| x.index().is_none())
+        {
+            materials.push(Scene::load_material(device, &mut textures, &material)?);
         }
 
-        let lighting_direction = Vec3::new(1.0, 0.0, 0.0);
         Ok(Self {
             meshes,
+            instances: MeshInstances::new(device, instances),
             materials,
-            scene: SceneUniform::new(
-                device,
-                SceneUniformData::new(),
-                SceneUniformData::shadow(vec3(100.0, 100.0, 100.0), lighting_direction),
-            ),
-            lighting: LightingUniform::new(
-                device,
-                LightingUniformData::new(vec![
-                    (vec3(-4.0, 4.0, -1.0), vec3(1.0, 1.0, 1.0)),
-                    (vec3(4.0, 4.0, -1.0), vec3(1.0, 1.0, 1.0)),
-                    (vec3(-4.0, -4.0, -1.0), vec3(1.0, 1.0, 1.0)),
-                    (vec3(4.0, -4.0, -1.0), vec3(1.0, 1.0, 1.0)),
-                ]),
-            ),
+            scene: SceneUniform::new(device, SceneUniformData::new()),
+            lighting: LightingUniform::new(device, &lights),
+            lights,
         })
     }
 
     pub fn new(device: &wgpu::Device) -> Self {
-        let lighting_direction = Vec3::new(1.0, 0.0, 0.0);
+        let lights = default_lights();
         Self {
             meshes: vec![],
+            instances: MeshInstances::new(device, vec![]),
             materials: vec![],
-            scene: SceneUniform::new(
-                device,
-                SceneUniformData::new(),
-                SceneUniformData::shadow(vec3(100.0, 100.0, 100.0), lighting_direction),
-            ),
-            lighting: LightingUniform::new(
-                device,
-                LightingUniformData::new(vec![
-                    (vec3(-4.0, 4.0, -1.0), vec3(1.0, 1.0, 1.0)),
-                    (vec3(4.0, 4.0, -1.0), vec3(1.0, 1.0, 1.0)),
-                    (vec3(-4.0, -4.0, -1.0), vec3(1.0, 1.0, 1.0)),
-                    (vec3(4.0, -4.0, -1.0), vec3(1.0, 1.0, 1.0)),
-                ]),
-            ),
+            scene: SceneUniform::new(device, SceneUniformData::new()),
+            lighting: LightingUniform::new(device, &lights),
+            lights,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(format: Format, pixels: Vec<u8>) -> gltf::image::Data {
+        gltf::image::Data {
+            pixels,
+            format,
+            width: 1,
+            height: 1,
+        }
+    }
+
+    fn rgba16_float(bytes: &[u8]) -> Vec<f32> {
+        bytes
+            .chunks(2)
+            .map(|x| f16::from_le_bytes([x[0], x[1]]).to_f32())
+            .collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-3, "{:?} != {:?}", actual, expected);
         }
     }
+
+    #[test]
+    fn gray_8_bit_expands_to_rgba() {
+        let (bytes, format) =
+            convert_image(&image(Format::R8, vec![77]), 0, ColorSpace::Linear).unwrap();
+        assert_eq!(format, wgpu::TextureFormat::Rgba8Unorm);
+        assert_eq!(bytes, vec![77, 77, 77, 255]);
+    }
+
+    #[test]
+    fn gray_alpha_16_bit_becomes_half_float() {
+        let pixels = [u16::MAX / 2, u16::MAX]
+            .iter()
+            .flat_map(|x| x.to_ne_bytes())
+            .collect();
+        let (bytes, format) =
+            convert_image(&image(Format::R16G16, pixels), 0, ColorSpace::Linear).unwrap();
+        assert_eq!(format, wgpu::TextureFormat::Rgba16Float);
+        assert_close(&rgba16_float(&bytes), &[0.5, 0.5, 0.5, 1.0]);
+    }
+
+    #[test]
+    fn srgb_16_bit_is_decoded_except_alpha() {
+        let pixels = [u16::MAX / 2, 0, u16::MAX, u16::MAX / 2]
+            .iter()
+            .flat_map(|x| x.to_ne_bytes())
+            .collect();
+        let (bytes, format) =
+            convert_image(&image(Format::R16G16B16A16, pixels), 0, ColorSpace::Srgb).unwrap();
+        assert_eq!(format, wgpu::TextureFormat::Rgba16Float);
+        assert_close(&rgba16_float(&bytes), &[0.214, 0.0, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn float_is_linear_and_gets_opaque_alpha() {
+        let pixels = [0.5f32, 2.0, 0.25]
+            .iter()
+            .flat_map(|x| x.to_ne_bytes())
+            .collect();
+        // Float images are already linear, even for sRGB usages
+        let (bytes, format) =
+            convert_image(&image(Format::R32G32B32FLOAT, pixels), 0, ColorSpace::Srgb).unwrap();
+        assert_eq!(format, wgpu::TextureFormat::Rgba16Float);
+        assert_close(&rgba16_float(&bytes), &[0.5, 2.0, 0.25, 1.0]);
+    }
+
+    #[test]
+    fn gpu_instancing_can_be_required() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/gpu_instancing.gltf");
+        // The file lists EXT_mesh_gpu_instancing in extensionsRequired
+        assert!(gltf::import(&path).is_err());
+
+        let (document, buffers, _) = import(&path).unwrap();
+        let node = document.nodes().next().unwrap();
+        let translations = gpu_instances(&document, &node, &buffers)
+            .unwrap()
+            .iter()
+            .map(|x| x.w_axis.truncate())
+            .collect::<Vec<_>>();
+        assert_eq!(translations, [Vec3::ZERO, vec3(2.0, 0.0, 0.0)]);
+    }
 }
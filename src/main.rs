@@ -1,6 +1,6 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use egui_wgpu::renderer::ScreenDescriptor;
 use winit::{
     event::*,
@@ -9,10 +9,13 @@ use winit::{
 };
 
 mod camera;
+mod capture;
 mod common;
 mod cubemap;
+mod environment;
 mod gbuffers;
 mod loader;
+mod mipmaps;
 mod passes;
 mod renderer;
 mod resources;
@@ -26,18 +29,78 @@ use renderer::Renderer;
 
 #[derive(Parser)]
 pub struct RendererConfig {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// gltf scene to load
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub gltf: Option<String>,
     /// skybox to load
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub skybox: Option<String>,
     /// irradiance (diffuse) to load
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub irradiance: Option<String>,
     /// prefilter (specular) to load
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub prefilter: Option<String>,
+    /// equirectangular .hdr or .exr panorama to bake the skybox and IBL maps from, replaces
+    /// skybox, irradiance and prefilter
+    #[arg(short, long, global = true)]
+    pub environment: Option<String>,
+    /// graphics backend(s) to request an adapter from
+    #[arg(long, value_enum, default_value_t = Backend::All, global = true)]
+    pub backend: Backend,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    Vulkan,
+    Gl,
+    Dx12,
+    Metal,
+    All,
+}
+
+impl Backend {
+    pub fn backends(self) -> wgpu::Backends {
+        match self {
+            Backend::Vulkan => wgpu::Backends::VULKAN,
+            Backend::Gl => wgpu::Backends::GL,
+            Backend::Dx12 => wgpu::Backends::DX12,
+            Backend::Metal => wgpu::Backends::METAL,
+            Backend::All => wgpu::Backends::all(),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Render a single frame without opening a window
+    Render {
+        /// image to write; .exr saves the HDR compose output, anything else (.png) the
+        /// tonemapped frame
+        #[arg(short, long)]
+        out: String,
+        /// width of the rendered image
+        #[arg(long, default_value_t = 1600)]
+        width: u32,
+        /// height of the rendered image
+        #[arg(long, default_value_t = 900)]
+        height: u32,
+    },
+}
+
+/// Headless entry point: render one frame with the default camera and write it out.
+pub fn render_headless(args: &RendererConfig, out: &str, width: u32, height: u32) {
+    let mut app = Renderer::new_headless(args, width, height).unwrap_or_else(|e| {
+        eprintln!("Failed to start renderer: {}", e);
+        std::process::exit(1);
+    });
+    app.update(Duration::ZERO);
+    if let Err(e) = app.render_to_file(out) {
+        eprintln!("Failed to write {}: {}", out, e);
+        std::process::exit(1);
+    }
 }
 
 // I HATE ASYNC! I HATE ASYNC!
@@ -45,20 +108,23 @@ pub async fn run() {
     let args = RendererConfig::parse();
 
     env_logger::init();
+    if let Some(Command::Render { out, width, height }) = &args.command {
+        render_headless(&args, out, *width, *height);
+        return;
+    }
+
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
         .with_inner_size(winit::dpi::PhysicalSize::new(1600, 900))
-        .with_resizable(false)
         .build(&event_loop)
         .unwrap();
 
     let mut egui_state = egui_winit::State::new(&event_loop);
     let egui_context = egui::Context::default();
-    let egui_screen_descriptor = ScreenDescriptor {
-        size_in_pixels: [1600, 900],
-        pixels_per_point: window.scale_factor() as f32,
-    };
-    let mut app = Renderer::new(&window, &args);
+    let mut app = Renderer::new(&window, &args).unwrap_or_else(|e| {
+        eprintln!("Failed to start renderer: {}", e);
+        std::process::exit(1);
+    });
     let mut last_render_time = Instant::now();
     event_loop.run(move |event, _, control_flow| match event {
         Event::WindowEvent {
@@ -78,6 +144,10 @@ pub async fn run() {
                         },
                     ..
                 } => *control_flow = ControlFlow::Exit,
+                WindowEvent::Resized(physical_size) => app.resize(*physical_size),
+                WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                    app.resize(**new_inner_size)
+                }
                 WindowEvent::MouseInput {
                     button: MouseButton::Right,
                     state,
@@ -105,12 +175,26 @@ pub async fn run() {
             let dt = now - last_render_time;
             last_render_time = now;
             app.update(dt);
+            let size = app.size();
+            let egui_screen_descriptor = ScreenDescriptor {
+                size_in_pixels: [size.width, size.height],
+                pixels_per_point: window.scale_factor() as f32,
+            };
             match app.render(
                 &ui_output.textures_delta,
                 &clipped_primitives,
                 &egui_screen_descriptor,
             ) {
                 Ok(_) => {}
+                // The surface no longer matches the window (e.g. after a resize or minimize),
+                // configure it again at the window's current size
+                Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                    app.resize(window.inner_size())
+                }
+                Err(wgpu::SurfaceError::OutOfMemory) => {
+                    eprintln!("Out of memory, exiting");
+                    *control_flow = ControlFlow::Exit;
+                }
                 Err(e) => eprintln!("{:?}", e),
             }
         }
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use wgpu::{
+    BindGroupLayout, FragmentState, MultisampleState, PipelineLayoutDescriptor, PrimitiveState,
+    RenderPipeline, TextureFormat, VertexState,
+};
+
+use crate::texture::Texture;
+
+/// How a mip level is filtered down from the one above it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MipFilter {
+    /// Box filter, in linear space for sRGB textures
+    Color,
+    /// Box filter, then renormalized (for normal maps)
+    Normal,
+}
+
+/// Fills in the mip chain of a texture (made with Texture::new_from_bytes_with_mips) on the GPU,
+/// rendering each level from the one above. Pipelines are made per texture format/filter as
+/// they're needed, so keep one of these around while loading a scene.
+pub struct MipmapGenerator {
+    shader: wgpu::ShaderModule,
+    bind_group_layout: BindGroupLayout,
+    pipelines: HashMap<(TextureFormat, MipFilter), RenderPipeline>,
+}
+
+impl MipmapGenerator {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/mipmaps.wgsl", true));
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Mipmaps bind group layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        });
+
+        Self {
+            shader,
+            bind_group_layout,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    fn pipeline(
+        device: &wgpu::Device,
+        shader: &wgpu::ShaderModule,
+        bind_group_layout: &BindGroupLayout,
+        format: TextureFormat,
+        filter: MipFilter,
+    ) -> RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(format!("Mipmaps pipeline ({:?}, {:?})", format, filter).as_str()),
+
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some("Mipmaps pipeline layout"),
+                bind_group_layouts: &[bind_group_layout],
+                push_constant_ranges: &[],
+            })),
+            vertex: VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(FragmentState {
+                module: shader,
+                entry_point: match filter {
+                    MipFilter::Color => "fs_main",
+                    MipFilter::Normal => "fs_normal",
+                },
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+
+            multiview: None,
+        })
+    }
+
+    /// Render every mip level of texture after the first.
+    pub fn generate(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture: &Texture,
+        filter: MipFilter,
+    ) {
+        let mip_level_count = texture.texture.mip_level_count();
+        if mip_level_count <= 1 {
+            return;
+        }
+
+        let format = texture.texture.format();
+        let pipeline = self.pipelines.entry((format, filter)).or_insert_with(|| {
+            MipmapGenerator::pipeline(
+                device,
+                &self.shader,
+                &self.bind_group_layout,
+                format,
+                filter,
+            )
+        });
+
+        let views = (0..mip_level_count)
+            .map(|level| {
+                texture.texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some("Mip level"),
+                    base_mip_level: level,
+                    mip_level_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmaps encoder"),
+        });
+
+        for level in 1..mip_level_count as usize {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Mipmaps bind group"),
+                layout: &self.bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&views[level - 1]),
+                }],
+            });
+
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mipmaps"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &views[level],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..6, 0..1);
+        }
+
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+}
@@ -1,24 +1,35 @@
+use std::ops::Range;
+
 use ddsfile::D3DFormat;
+use glam::Vec3;
 use half::f16;
 use wgpu::{
-    BindGroupDescriptor, BindGroupEntry, Device, FragmentState, MultisampleState,
-    PipelineLayoutDescriptor, PrimitiveState, RenderPipeline, ShaderModule, TextureFormat,
-    TextureUsages, TextureView, VertexState,
+    BindGroupDescriptor, BindGroupEntry, BlendComponent, BlendFactor, BlendOperation, BlendState,
+    Device, FragmentState, MultisampleState, PipelineLayoutDescriptor, PrimitiveState, RenderPass,
+    RenderPipeline, ShaderModule, TextureFormat, TextureUsages, TextureView, VertexState,
 };
 
 use crate::{
+    common::VertexAttributes,
     cubemap::Cubemap,
     gbuffers::GBuffers,
     loader::Scene,
-    resources::{LightingUniform, SceneUniform},
+    resources::{AlphaMode, LightingUniform, Material, Mesh, MeshInstance, SceneUniform},
+    shadowmap::Shadows,
     texture::{Sampler, Texture},
     RendererConfig,
 };
 
-use super::ReloadableShaders;
+use super::{MeshPipelines, ReloadableShaders};
 
+/// Image based lighting, along with the BRDF lookup tables shared with area lights.
 pub struct IBL {
     brdf_lookup: Texture,
+    /// Linearly transformed cosine fit of GGX for area lights: the inverse matrix (normalized so
+    /// the middle element is 1) in rgba, see area_light_form_factor in compose.wgsl
+    ltc_matrix: Texture,
+    /// r = GGX albedo, g = its Fresnel part, a = form factor of a horizon clipped sphere
+    ltc_amplitude: Texture,
     diffuse_radiance: Cubemap,
     specular_radiance: Cubemap,
     cubemap_sampler: Sampler,
@@ -31,36 +42,16 @@ impl IBL {
         queue: &wgpu::Queue,
         renderer_config: &RendererConfig,
     ) -> Self {
-        let brdf_lookup = {
-            let file = std::fs::read("resources/OCEANMAN_BRDF.dds").unwrap();
-            let img = ddsfile::Dds::read(file.as_slice()).unwrap();
-
-            if img.get_d3d_format().unwrap() != D3DFormat::A32B32G32R32F {
-                panic!("Format is {:?}", img.get_d3d_format());
-            }
-
-            let slice =
-                &img.data.as_slice()[0..(4 * 4 * img.get_width() * img.get_height()) as usize];
-            let bytes = slice
-                .chunks(4)
-                .map(|x| {
-                    let elem_f32 = f32::from_le_bytes([x[0], x[1], x[2], x[3]]);
-                    f16::from_f32(elem_f32)
-                })
-                .collect::<Vec<f16>>();
-
-            Texture::new_from_bytes(
-                device,
-                queue,
-                bytemuck::cast_slice(bytes.as_slice()),
-                img.get_width(),
-                img.get_height(),
-                TextureFormat::Rgba16Float,
-                TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                Some("BRDF lookup"),
-                false,
-            )
-        };
+        let brdf_lookup =
+            IBL::lookup_table(device, queue, "resources/OCEANMAN_BRDF.dds", "BRDF lookup");
+        let ltc_matrix =
+            IBL::lookup_table(device, queue, "resources/OCEANMAN_LTC_1.dds", "LTC matrix");
+        let ltc_amplitude = IBL::lookup_table(
+            device,
+            queue,
+            "resources/OCEANMAN_LTC_2.dds",
+            "LTC amplitude",
+        );
 
         let default = String::from("resources/OCEANMAN_UNSPECIFIED.dds");
 
@@ -98,11 +89,21 @@ impl IBL {
                     binding: 3,
                     resource: wgpu::BindingResource::Sampler(&cubemap_sampler.sampler),
                 },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&ltc_matrix.view),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&ltc_amplitude.view),
+                },
             ],
         });
 
         Self {
             brdf_lookup,
+            ltc_matrix,
+            ltc_amplitude,
             diffuse_radiance,
             specular_radiance,
             cubemap_sampler,
@@ -110,21 +111,27 @@ impl IBL {
         }
     }
 
+    /// Replace the given maps, e.g. loaded with Cubemap::from_dds or baked by Environment.
     pub fn update(
         &mut self,
         device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        irradiance: Option<&String>,
-        prefilter: Option<&String>,
+        irradiance: Option<Cubemap>,
+        prefilter: Option<Cubemap>,
+        brdf_lookup: Option<Texture>,
     ) {
-        if let Some(irradiance_path) = irradiance {
+        if let Some(irradiance) = irradiance {
             self.diffuse_radiance.texture.destroy();
-            self.diffuse_radiance = Cubemap::from_dds(device, queue, irradiance_path);
+            self.diffuse_radiance = irradiance;
         }
 
-        if let Some(prefilter_path) = prefilter {
+        if let Some(prefilter) = prefilter {
             self.specular_radiance.texture.destroy();
-            self.specular_radiance = Cubemap::from_dds(device, queue, prefilter_path);
+            self.specular_radiance = prefilter;
+        }
+
+        if let Some(brdf_lookup) = brdf_lookup {
+            self.brdf_lookup.texture.destroy();
+            self.brdf_lookup = brdf_lookup;
         }
 
         self.bind_group = device.create_bind_group(&BindGroupDescriptor {
@@ -147,6 +154,14 @@ impl IBL {
                     binding: 3,
                     resource: wgpu::BindingResource::Sampler(&self.cubemap_sampler.sampler),
                 },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&self.ltc_matrix.view),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&self.ltc_amplitude.view),
+                },
             ],
         });
     }
@@ -191,26 +206,112 @@ impl IBL {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
             ],
         })
     }
+
+    /// Load a 2D lookup table from an A32B32G32R32F dds, as half floats so it can be filtered.
+    fn lookup_table(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &str,
+        label: &str,
+    ) -> Texture {
+        let file = std::fs::read(path).unwrap();
+        let img = ddsfile::Dds::read(file.as_slice()).unwrap();
+
+        if img.get_d3d_format().unwrap() != D3DFormat::A32B32G32R32F {
+            panic!("Format is {:?}", img.get_d3d_format());
+        }
+
+        let slice = &img.data.as_slice()[0..(4 * 4 * img.get_width() * img.get_height()) as usize];
+        let bytes = slice
+            .chunks(4)
+            .map(|x| {
+                let elem_f32 = f32::from_le_bytes([x[0], x[1], x[2], x[3]]);
+                f16::from_f32(elem_f32)
+            })
+            .collect::<Vec<f16>>();
+
+        Texture::new_from_bytes(
+            device,
+            queue,
+            bytemuck::cast_slice(bytes.as_slice()),
+            img.get_width(),
+            img.get_height(),
+            TextureFormat::Rgba16Float,
+            TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            Some(label),
+            false,
+        )
+    }
 }
 pub struct Compose {
     pub ibl: IBL,
     pipeline: wgpu::RenderPipeline,
+    forward_pipelines: MeshPipelines,
+    oit_pipelines: MeshPipelines,
+    oit_resolve_pipeline: wgpu::RenderPipeline,
+    /// Weighted blended OIT targets: rgb = sum of weighted premultiplied color, a = sum of
+    /// weighted alpha
+    oit_accum: Texture,
+    /// Product of (1 - alpha) over all transparent surfaces
+    oit_revealage: Texture,
+    oit_bind_group: wgpu::BindGroup,
 }
 
 impl Compose {
+    const OIT_ACCUM_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+    const OIT_REVEALAGE_FORMAT: TextureFormat = TextureFormat::R8Unorm;
+
     pub fn new(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        config: &wgpu::SurfaceConfiguration,
         renderer_config: &RendererConfig,
     ) -> Self {
         let ibl = IBL::new(device, queue, renderer_config);
         let shader =
             device.create_shader_module(wgpu::include_wgsl!("../shaders/compose.wgsl", true));
         let pipeline = Compose::pipeline(device, &shader);
-        Self { ibl, pipeline }
+        let forward_pipelines = MeshPipelines::new(|double_sided, mirrored| {
+            Compose::forward_pipeline(device, &shader, double_sided, mirrored)
+        });
+        let oit_pipelines = MeshPipelines::new(|double_sided, mirrored| {
+            Compose::oit_pipeline(device, &shader, double_sided, mirrored)
+        });
+        let oit_resolve_pipeline = Compose::oit_resolve_pipeline(device, &shader);
+        let (oit_accum, oit_revealage, oit_bind_group) = Compose::oit_targets(device, config);
+        Self {
+            ibl,
+            pipeline,
+            forward_pipelines,
+            oit_pipelines,
+            oit_resolve_pipeline,
+            oit_accum,
+            oit_revealage,
+            oit_bind_group,
+        }
     }
 
     pub fn pipeline(device: &wgpu::Device, shader: &ShaderModule) -> RenderPipeline {
@@ -224,6 +325,7 @@ impl Compose {
                     &GBuffers::bind_group_layout(device),
                     &LightingUniform::bind_group_layout(device),
                     &IBL::bind_group_layout(device),
+                    &Shadows::bind_group_layout(device),
                 ],
                 push_constant_ranges: &[],
             })),
@@ -261,10 +363,283 @@ impl Compose {
         })
     }
 
+    /// Pipeline for alpha blended materials with sorted blending. Shares compose.wgsl (and the
+    /// lighting code in it) with the deferred pipeline, but draws mesh instances with the
+    /// material in group 1. Like WriteGBuffers there's a single and double sided variant, each
+    /// also for mirrored instances.
+    pub fn forward_pipeline(
+        device: &wgpu::Device,
+        shader: &ShaderModule,
+        double_sided: bool,
+        mirrored: bool,
+    ) -> RenderPipeline {
+        Compose::mesh_pipeline(
+            device,
+            shader,
+            "fs_forward",
+            double_sided,
+            mirrored,
+            &[Some(wgpu::ColorTargetState {
+                format: wgpu::TextureFormat::Rgba16Float,
+                blend: Some(BlendState::ALPHA_BLENDING),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            "Compose forward pipeline",
+        )
+    }
+
+    /// Same as forward_pipeline, but accumulates into the weighted blended OIT targets.
+    pub fn oit_pipeline(
+        device: &wgpu::Device,
+        shader: &ShaderModule,
+        double_sided: bool,
+        mirrored: bool,
+    ) -> RenderPipeline {
+        Compose::mesh_pipeline(
+            device,
+            shader,
+            "fs_forward_oit",
+            double_sided,
+            mirrored,
+            &[
+                Some(wgpu::ColorTargetState {
+                    format: Compose::OIT_ACCUM_FORMAT,
+                    blend: Some(BlendState {
+                        color: BlendComponent {
+                            src_factor: BlendFactor::One,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Add,
+                        },
+                        alpha: BlendComponent {
+                            src_factor: BlendFactor::One,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Add,
+                        },
+                    }),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }),
+                Some(wgpu::ColorTargetState {
+                    format: Compose::OIT_REVEALAGE_FORMAT,
+                    blend: Some(BlendState {
+                        color: BlendComponent {
+                            src_factor: BlendFactor::Zero,
+                            dst_factor: BlendFactor::OneMinusSrc,
+                            operation: BlendOperation::Add,
+                        },
+                        alpha: BlendComponent::REPLACE,
+                    }),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }),
+            ],
+            "Compose OIT pipeline",
+        )
+    }
+
+    fn mesh_pipeline(
+        device: &wgpu::Device,
+        shader: &ShaderModule,
+        entry_point: &str,
+        double_sided: bool,
+        mirrored: bool,
+        targets: &[Option<wgpu::ColorTargetState>],
+        label: &str,
+    ) -> RenderPipeline {
+        let label = match (double_sided, mirrored) {
+            (false, false) => String::from(label),
+            (false, true) => format!("{} (mirrored)", label),
+            (true, false) => format!("{} (double sided)", label),
+            (true, true) => format!("{} (double sided, mirrored)", label),
+        };
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label.as_str()),
+
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some(format!("{} layout", label).as_str()),
+                bind_group_layouts: &[
+                    &SceneUniform::bind_group_layout(device),
+                    &Material::bind_group_layout(device),
+                    &LightingUniform::bind_group_layout(device),
+                    &IBL::bind_group_layout(device),
+                    &Shadows::bind_group_layout(device),
+                ],
+                push_constant_ranges: &[],
+            })),
+            vertex: VertexState {
+                module: shader,
+                entry_point: "vs_forward",
+                buffers: &[
+                    VertexAttributes::buffer_layout(),
+                    MeshInstance::buffer_layout(),
+                ],
+            },
+            fragment: Some(FragmentState {
+                module: shader,
+                entry_point,
+                targets,
+            }),
+            primitive: PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: if mirrored {
+                    wgpu::FrontFace::Cw
+                } else {
+                    wgpu::FrontFace::Ccw
+                },
+                cull_mode: if double_sided {
+                    None
+                } else {
+                    Some(wgpu::Face::Back)
+                },
+                unclipped_depth: false,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                conservative: false,
+            },
+            // Tested against the opaque geometry, but not written so transparent surfaces
+            // don't hide each other
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+
+            multiview: None,
+        })
+    }
+
+    /// Fullscreen pipeline blending the OIT targets over the composed image.
+    pub fn oit_resolve_pipeline(device: &wgpu::Device, shader: &ShaderModule) -> RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Compose OIT resolve pipeline"),
+
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some("Compose OIT resolve pipeline layout"),
+                bind_group_layouts: &[
+                    &SceneUniform::bind_group_layout(device),
+                    &Compose::oit_bind_group_layout(device),
+                ],
+                push_constant_ranges: &[],
+            })),
+            vertex: VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(FragmentState {
+                module: shader,
+                entry_point: "fs_oit_resolve",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: wgpu::TextureFormat::Rgba16Float,
+                    blend: Some(BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+
+            multiview: None,
+        })
+    }
+
+    /// OIT textures are at bindings 11/12, clear of the gbuffers and material bindings that
+    /// share group 1 in compose.wgsl.
+    pub fn oit_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Compose OIT bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 11,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 12,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        })
+    }
+
+    fn oit_targets(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> (Texture, Texture, wgpu::BindGroup) {
+        let accum = Texture::new(
+            device,
+            config.width,
+            config.height,
+            Compose::OIT_ACCUM_FORMAT,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            Some("Compose - OIT accumulation"),
+            false,
+        );
+        let revealage = Texture::new(
+            device,
+            config.width,
+            config.height,
+            Compose::OIT_REVEALAGE_FORMAT,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            Some("Compose - OIT revealage"),
+            false,
+        );
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("Compose OIT bind group"),
+            layout: &Compose::oit_bind_group_layout(device),
+            entries: &[
+                BindGroupEntry {
+                    binding: 11,
+                    resource: wgpu::BindingResource::TextureView(&accum.view),
+                },
+                BindGroupEntry {
+                    binding: 12,
+                    resource: wgpu::BindingResource::TextureView(&revealage.view),
+                },
+            ],
+        });
+        (accum, revealage, bind_group)
+    }
+
+    /// Recreate the screen sized OIT targets (e.g. on resize).
+    pub fn update_input(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        (self.oit_accum, self.oit_revealage, self.oit_bind_group) =
+            Compose::oit_targets(device, config);
+    }
+
     pub fn pass(
         &self,
         scene: &Scene,
         gbuffers: &GBuffers,
+        shadows: &Shadows,
         output: &TextureView,
         encoder: &mut wgpu::CommandEncoder,
     ) {
@@ -289,10 +664,183 @@ impl Compose {
             pass.set_bind_group(1, &gbuffers.bind_group, &[]);
             pass.set_bind_group(2, &scene.lighting.uniform_bind_group, &[]);
             pass.set_bind_group(3, &self.ibl.bind_group, &[]);
+            pass.set_bind_group(4, &shadows.bind_group, &[]);
+
+            pass.draw(0..6, 0..1);
+        }
+    }
+
+    /// Draw meshes with alpha blended materials on top of output, sorted back to front (by
+    /// distance from camera_position to each instance's center). Goes after Skybox.
+    pub fn forward_pass(
+        &self,
+        scene: &Scene,
+        shadows: &Shadows,
+        camera_position: Vec3,
+        output: &TextureView,
+        depth_buffer: &TextureView,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        // Instances are drawn one at a time so they can be sorted
+        let mut meshes = Compose::blended_meshes(scene)
+            .into_iter()
+            .flat_map(|(mesh, instances)| instances.map(move |i| (mesh, i..i + 1)))
+            .collect::<Vec<_>>();
+        if meshes.is_empty() {
+            return;
+        }
+        let distance = |(mesh, instances): &(&Mesh, Range<u32>)| {
+            scene.instances.data[instances.start as usize]
+                .world
+                .transform_point3(mesh.center)
+                .distance_squared(camera_position)
+        };
+        meshes.sort_by(|a, b| distance(b).total_cmp(&distance(a)));
+
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Compose - forward"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: output,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_buffer,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            self.draw_meshes(&mut pass, &self.forward_pipelines, scene, shadows, &meshes);
+        }
+    }
+
+    /// Alternative to forward_pass: weighted blended order independent transparency. Blended
+    /// meshes are accumulated unsorted into the OIT targets, which are then resolved over output.
+    /// Handles intersecting/overlapping meshes that sorting per mesh can't, at the cost of
+    /// approximating the blend order.
+    pub fn oit_pass(
+        &self,
+        scene: &Scene,
+        shadows: &Shadows,
+        output: &TextureView,
+        depth_buffer: &TextureView,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let meshes = Compose::blended_meshes(scene);
+        if meshes.is_empty() {
+            return;
+        }
+
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Compose - OIT accumulation"),
+                color_attachments: &[
+                    Some(wgpu::RenderPassColorAttachment {
+                        view: &self.oit_accum.view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                            store: true,
+                        },
+                    }),
+                    Some(wgpu::RenderPassColorAttachment {
+                        view: &self.oit_revealage.view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                            store: true,
+                        },
+                    }),
+                ],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_buffer,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            self.draw_meshes(&mut pass, &self.oit_pipelines, scene, shadows, &meshes);
+        }
+
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Compose - OIT resolve"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: output,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            pass.set_pipeline(&self.oit_resolve_pipeline);
+            pass.set_bind_group(0, &scene.scene.uniform_bind_group, &[]);
+            pass.set_bind_group(1, &self.oit_bind_group, &[]);
 
             pass.draw(0..6, 0..1);
         }
     }
+
+    /// Every mesh with a blended material, with all of its instances.
+    fn blended_meshes(scene: &Scene) -> Vec<(&Mesh, Range<u32>)> {
+        scene
+            .meshes
+            .iter()
+            .filter(|x| scene.materials[x.material_index].alpha_mode == AlphaMode::Blend)
+            .map(|x| (x, x.instances.clone()))
+            .collect()
+    }
+
+    /// Draw meshes (each for a range of instances) in order with the forward or OIT pipelines,
+    /// picking the single or double sided and mirrored variants per mesh.
+    fn draw_meshes<'a>(
+        &'a self,
+        pass: &mut RenderPass<'a>,
+        pipelines: &'a MeshPipelines,
+        scene: &'a Scene,
+        shadows: &'a Shadows,
+        meshes: &[(&'a Mesh, Range<u32>)],
+    ) {
+        pass.set_bind_group(0, &scene.scene.uniform_bind_group, &[]);
+        pass.set_bind_group(2, &scene.lighting.uniform_bind_group, &[]);
+        pass.set_bind_group(3, &self.ibl.bind_group, &[]);
+        pass.set_bind_group(4, &shadows.bind_group, &[]);
+        pass.set_vertex_buffer(1, scene.instances.buffer.slice(..));
+
+        for (mesh, instances) in meshes {
+            let material = &scene.materials[mesh.material_index];
+            pass.set_bind_group(1, &material.bind_group, &[]);
+            pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            for (instances, mirrored) in mesh.split_mirrored(instances.clone()) {
+                if instances.is_empty() {
+                    continue;
+                }
+                pass.set_pipeline(pipelines.get(material.double_sided, mirrored));
+                pass.draw_indexed(0..mesh.index_count, 0, instances);
+            }
+        }
+    }
 }
 
 impl ReloadableShaders for Compose {
@@ -307,5 +855,12 @@ impl ReloadableShaders for Compose {
         shader_module: wgpu::ShaderModule,
     ) {
         self.pipeline = Compose::pipeline(device, &shader_module);
+        self.forward_pipelines = MeshPipelines::new(|double_sided, mirrored| {
+            Compose::forward_pipeline(device, &shader_module, double_sided, mirrored)
+        });
+        self.oit_pipelines = MeshPipelines::new(|double_sided, mirrored| {
+            Compose::oit_pipeline(device, &shader_module, double_sided, mirrored)
+        });
+        self.oit_resolve_pipeline = Compose::oit_resolve_pipeline(device, &shader_module);
     }
 }
@@ -53,7 +53,29 @@ impl Fxaa {
         let sampler = Sampler::fxaa_sampler(device);
 
         let pipeline = Fxaa::pipeline(device, config, &shader);
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        let bind_group = Fxaa::bind_group(device, &uniform, input_texture, &sampler);
+
+        Self {
+            uniform,
+            sampler,
+            bind_group,
+            pipeline,
+        }
+    }
+
+    /// Point the pass at a new input texture (e.g. after the tonemapping output was recreated
+    /// on resize).
+    pub fn update_input(&mut self, device: &wgpu::Device, input_texture: &Texture) {
+        self.bind_group = Fxaa::bind_group(device, &self.uniform, input_texture, &self.sampler);
+    }
+
+    fn bind_group(
+        device: &wgpu::Device,
+        uniform: &FxaaUniform,
+        input_texture: &Texture,
+        sampler: &Sampler,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
             layout: &Fxaa::bind_group_layout(device),
             entries: &[
@@ -64,14 +86,7 @@ impl Fxaa {
                     resource: BindingResource::Sampler(&sampler.sampler),
                 },
             ],
-        });
-
-        Self {
-            uniform,
-            sampler,
-            bind_group,
-            pipeline,
-        }
+        })
     }
 
     pub fn bind_group_layout(device: &wgpu::Device) -> BindGroupLayout {
@@ -0,0 +1,358 @@
+use wgpu::{
+    BindGroupLayout, Device, FragmentState, MultisampleState, PipelineLayoutDescriptor,
+    PrimitiveState, RenderPipeline, ShaderModule, TextureFormat, TextureUsages, TextureView,
+    VertexState,
+};
+
+use crate::{
+    bytemuck_impl, gbuffers::GBuffers, loader::Scene, resources::SceneUniform, texture::Texture,
+    uniform::Uniform,
+};
+
+use super::ReloadableShaders;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct GtaoParams {
+    /// View space radius horizons are searched in
+    pub radius: f32,
+    /// Exponent applied to the final visibility
+    pub intensity: f32,
+    /// Number of directions (slices) horizons are searched in per pixel
+    pub slice_count: u32,
+    /// Samples taken on each side of a slice
+    pub step_count: u32,
+    /// Occlude specular with the bent normal/visibility cone (1) or just visibility (0)
+    pub specular_occlusion: u32,
+    pub padding: [u32; 3],
+}
+bytemuck_impl!(GtaoParams);
+
+impl Default for GtaoParams {
+    fn default() -> Self {
+        GtaoParams {
+            radius: 0.5,
+            intensity: 1.0,
+            slice_count: 2,
+            step_count: 4,
+            specular_occlusion: 1,
+            padding: [0; 3],
+        }
+    }
+}
+
+pub type GtaoUniform = Uniform<GtaoParams>;
+
+/// Horizon based ground truth ambient occlusion, an alternative to SSAO. Like SSAO it writes
+/// into gbuffers.occlusion (after a depth-aware blur), but also fills in specular visibility
+/// and the bent normal.
+pub struct Gtao {
+    pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+    pub uniform: GtaoUniform,
+    textures_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+    raw_occlusion: Texture,
+}
+
+impl Gtao {
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        gbuffers: &GBuffers,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::include_wgsl!("../shaders/gtao.wgsl", true));
+        let pipeline = Gtao::pipeline(device, &shader);
+        let blur_pipeline = Gtao::blur_pipeline(device, &shader);
+
+        let uniform = GtaoUniform::new(device, Some("GTAO"), GtaoParams::default());
+
+        let raw_occlusion = Gtao::raw_occlusion(device, config);
+        let textures_bind_group = Gtao::textures_bind_group(device, gbuffers, &uniform);
+        let blur_bind_group = Gtao::blur_bind_group(device, gbuffers, &raw_occlusion);
+
+        Self {
+            pipeline,
+            blur_pipeline,
+            uniform,
+            textures_bind_group,
+            blur_bind_group,
+            raw_occlusion,
+        }
+    }
+
+    fn raw_occlusion(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Texture {
+        Texture::new(
+            device,
+            config.width,
+            config.height,
+            TextureFormat::Rgba16Float,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            Some("GTAO - unblurred occlusion"),
+            false,
+        )
+    }
+
+    /// Recreate everything that depends on the gbuffers (e.g. after they were recreated on
+    /// resize).
+    pub fn update_input(
+        &mut self,
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        gbuffers: &GBuffers,
+    ) {
+        self.raw_occlusion = Gtao::raw_occlusion(device, config);
+        self.textures_bind_group = Gtao::textures_bind_group(device, gbuffers, &self.uniform);
+        self.blur_bind_group = Gtao::blur_bind_group(device, gbuffers, &self.raw_occlusion);
+    }
+
+    fn textures_bind_group(
+        device: &wgpu::Device,
+        gbuffers: &GBuffers,
+        uniform: &GtaoUniform,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("GTAO textures bind group"),
+            layout: &Gtao::textures_bind_group_layout(device),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&gbuffers.depth.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&gbuffers.normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&gbuffers.material.view),
+                },
+                uniform.bind_group_entry(3),
+            ],
+        })
+    }
+
+    fn blur_bind_group(
+        device: &wgpu::Device,
+        gbuffers: &GBuffers,
+        raw_occlusion: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("GTAO blur bind group"),
+            layout: &Gtao::blur_bind_group_layout(device),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&gbuffers.depth.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&raw_occlusion.view),
+                },
+            ],
+        })
+    }
+
+    pub fn textures_bind_group_layout(device: &wgpu::Device) -> BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("GTAO textures bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                GtaoUniform::bind_group_layout_entry(3),
+            ],
+        })
+    }
+
+    /// Blur shares binding 0 (depth) with the GTAO textures, the unblurred occlusion is at 5
+    /// so both can live in one shader file.
+    pub fn blur_bind_group_layout(device: &wgpu::Device) -> BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("GTAO blur bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        })
+    }
+
+    pub fn pipeline(device: &wgpu::Device, shader: &ShaderModule) -> RenderPipeline {
+        Gtao::fullscreen_pipeline(
+            device,
+            shader,
+            "fs_main",
+            &Gtao::textures_bind_group_layout(device),
+            "GTAO pipeline",
+        )
+    }
+
+    pub fn blur_pipeline(device: &wgpu::Device, shader: &ShaderModule) -> RenderPipeline {
+        Gtao::fullscreen_pipeline(
+            device,
+            shader,
+            "fs_blur",
+            &Gtao::blur_bind_group_layout(device),
+            "GTAO blur pipeline",
+        )
+    }
+
+    fn fullscreen_pipeline(
+        device: &wgpu::Device,
+        shader: &ShaderModule,
+        entry_point: &str,
+        textures_layout: &BindGroupLayout,
+        label: &str,
+    ) -> RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some(format!("{} layout", label).as_str()),
+                bind_group_layouts: &[&SceneUniform::bind_group_layout(device), textures_layout],
+                push_constant_ranges: &[],
+            })),
+            vertex: VertexState {
+                module: shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(FragmentState {
+                module: shader,
+                entry_point,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: wgpu::TextureFormat::Rgba16Float,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+
+            multiview: None,
+        })
+    }
+
+    fn fullscreen_pass(
+        pipeline: &RenderPipeline,
+        scene: &Scene,
+        textures_bind_group: &wgpu::BindGroup,
+        output: &TextureView,
+        label: &str,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(GBuffers::NO_OCCLUSION),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &scene.scene.uniform_bind_group, &[]);
+        pass.set_bind_group(1, textures_bind_group, &[]);
+
+        pass.draw(0..6, 0..1);
+    }
+
+    pub fn pass(&self, scene: &Scene, gbuffers: &GBuffers, encoder: &mut wgpu::CommandEncoder) {
+        Gtao::fullscreen_pass(
+            &self.pipeline,
+            scene,
+            &self.textures_bind_group,
+            &self.raw_occlusion.view,
+            "GTAO",
+            encoder,
+        );
+        Gtao::fullscreen_pass(
+            &self.blur_pipeline,
+            scene,
+            &self.blur_bind_group,
+            &gbuffers.occlusion.view,
+            "GTAO blur",
+            encoder,
+        );
+    }
+}
+
+impl ReloadableShaders for Gtao {
+    fn available_shaders() -> &'static [&'static str] {
+        &["../shaders/gtao.wgsl"]
+    }
+
+    fn reload(
+        &mut self,
+        device: &Device,
+        _config: &wgpu::SurfaceConfiguration,
+        shader_module: wgpu::ShaderModule,
+    ) {
+        self.pipeline = Gtao::pipeline(device, &shader_module);
+        self.blur_pipeline = Gtao::blur_pipeline(device, &shader_module);
+    }
+}
@@ -0,0 +1,110 @@
+use wgpu::{BindGroupLayout, ComputePipeline, Device, PipelineLayoutDescriptor, ShaderModule};
+
+use crate::{
+    gbuffers::GBuffers,
+    loader::Scene,
+    resources::{LightingUniform, SceneUniform, CLUSTER_GRID},
+};
+
+use super::ReloadableShaders;
+
+/// Bins the scene's lights into view space clusters (see CLUSTER_GRID), so Compose only shades
+/// the lights that can reach each pixel. Uses the gbuffer depth to skip clusters behind
+/// everything in a screen tile, so it goes between WriteGBuffers and Compose.
+pub struct LightClusters {
+    pipeline: ComputePipeline,
+    depth_bind_group: wgpu::BindGroup,
+}
+
+impl LightClusters {
+    pub fn new(device: &wgpu::Device, gbuffers: &GBuffers) -> Self {
+        let shader = device
+            .create_shader_module(wgpu::include_wgsl!("../shaders/light_clusters.wgsl", true));
+        let pipeline = LightClusters::pipeline(device, &shader);
+        let depth_bind_group = LightClusters::depth_bind_group(device, gbuffers);
+
+        Self {
+            pipeline,
+            depth_bind_group,
+        }
+    }
+
+    /// Recreate the depth bind group (e.g. after the gbuffers were recreated on resize).
+    pub fn update_input(&mut self, device: &wgpu::Device, gbuffers: &GBuffers) {
+        self.depth_bind_group = LightClusters::depth_bind_group(device, gbuffers);
+    }
+
+    fn depth_bind_group(device: &wgpu::Device, gbuffers: &GBuffers) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Light clusters depth bind group"),
+            layout: &LightClusters::depth_bind_group_layout(device),
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&gbuffers.depth.view),
+            }],
+        })
+    }
+
+    pub fn depth_bind_group_layout(device: &wgpu::Device) -> BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Light clusters depth bind group layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Depth,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+        })
+    }
+
+    pub fn pipeline(device: &wgpu::Device, shader: &ShaderModule) -> ComputePipeline {
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Light clusters pipeline"),
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some("Light clusters pipeline layout"),
+                bind_group_layouts: &[
+                    &SceneUniform::bind_group_layout(device),
+                    &LightClusters::depth_bind_group_layout(device),
+                    &LightingUniform::cluster_bind_group_layout(device),
+                ],
+                push_constant_ranges: &[],
+            })),
+            module: shader,
+            entry_point: "cs_main",
+        })
+    }
+
+    pub fn pass(&self, scene: &Scene, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Light clusters"),
+            timestamp_writes: None,
+        });
+
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &scene.scene.uniform_bind_group, &[]);
+        pass.set_bind_group(1, &self.depth_bind_group, &[]);
+        pass.set_bind_group(2, &scene.lighting.cluster_bind_group, &[]);
+
+        // One workgroup per screen tile
+        pass.dispatch_workgroups(CLUSTER_GRID[0], CLUSTER_GRID[1], 1);
+    }
+}
+
+impl ReloadableShaders for LightClusters {
+    fn available_shaders() -> &'static [&'static str] {
+        &["../shaders/light_clusters.wgsl"]
+    }
+
+    fn reload(
+        &mut self,
+        device: &Device,
+        _config: &wgpu::SurfaceConfiguration,
+        shader_module: wgpu::ShaderModule,
+    ) {
+        self.pipeline = LightClusters::pipeline(device, &shader_module);
+    }
+}
@@ -1,20 +1,46 @@
 mod compose;
 mod fxaa;
+mod gtao;
+mod light_clusters;
 mod skybox;
-// mod ssao;
+mod ssao;
 mod tonemapping;
 mod write_gbuffers;
-// mod write_shadowmaps;
+mod write_shadowmaps;
 
 pub use compose::Compose;
 pub use fxaa::Fxaa;
 pub use fxaa::FxaaParams;
+pub use gtao::Gtao;
+pub use gtao::GtaoParams;
+pub use light_clusters::LightClusters;
 pub use skybox::Skybox;
-// pub use ssao::SSAO;
+pub use ssao::SsaoParams;
+pub use ssao::MAX_SSAO_SAMPLES;
+pub use ssao::SSAO;
 pub use tonemapping::Tonemapping;
-use wgpu::Device;
+use wgpu::{Device, RenderPipeline};
 pub use write_gbuffers::WriteGBuffers;
-// pub use write_shadowmaps::WriteShadowmaps;
+pub use write_shadowmaps::WriteShadowmaps;
+
+/// Variants of a pipeline drawing scene meshes: single or double sided, and wound as authored or
+/// mirrored (instances with a negative determinant, whose front faces are clockwise).
+struct MeshPipelines([RenderPipeline; 4]);
+
+impl MeshPipelines {
+    fn new(pipeline: impl Fn(bool, bool) -> RenderPipeline) -> Self {
+        Self([
+            pipeline(false, false),
+            pipeline(false, true),
+            pipeline(true, false),
+            pipeline(true, true),
+        ])
+    }
+
+    fn get(&self, double_sided: bool, mirrored: bool) -> &RenderPipeline {
+        &self.0[double_sided as usize * 2 + mirrored as usize]
+    }
+}
 
 // TODO: gut instinct says this could be done better
 pub trait ReloadableShaders {
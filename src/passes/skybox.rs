@@ -7,21 +7,26 @@ use wgpu::{
 use crate::{
     cubemap::Cubemap,
     loader::Scene,
-    resources::SceneUniform,
+    resources::{LightKind, LightingUniform, SceneUniform, MAX_LIGHTS},
     texture::{Sampler, Texture},
     RendererConfig,
 };
 
 use super::ReloadableShaders;
 
+/// Draws the skybox behind the composed image, along with the shapes of area lights.
 pub struct Skybox {
     pipeline: wgpu::RenderPipeline,
+    emitter_pipeline: wgpu::RenderPipeline,
     cubemap: Cubemap,
     cubemap_sampler: Sampler,
     cubemap_bind_group: wgpu::BindGroup,
 }
 
 impl Skybox {
+    /// Disk emitters are a fan of this many triangles, matches DISK_SEGMENTS in skybox.wgsl
+    const DISK_SEGMENTS: u32 = 32;
+
     pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, config: &RendererConfig) -> Self {
         let default = String::from("resources/OCEANMAN_UNSPECIFIED.dds");
         let cubemap_path = match &config.skybox {
@@ -35,6 +40,7 @@ impl Skybox {
         let shader =
             device.create_shader_module(wgpu::include_wgsl!("../shaders/skybox.wgsl", true));
         let pipeline = Skybox::pipeline(device, &shader);
+        let emitter_pipeline = Skybox::emitter_pipeline(device, &shader);
 
         let cubemap_bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: Some("skybox bind group"),
@@ -55,18 +61,13 @@ impl Skybox {
             cubemap,
             cubemap_sampler,
             pipeline,
+            emitter_pipeline,
             cubemap_bind_group,
         }
     }
 
-    pub fn update_cubemap(
-        &mut self,
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        new_skybox: &String,
-    ) {
-        let cubemap = Cubemap::from_dds(device, queue, new_skybox);
-
+    /// Replace the skybox, e.g. with one loaded with Cubemap::from_dds or baked by Environment.
+    pub fn update_cubemap(&mut self, device: &wgpu::Device, cubemap: Cubemap) {
         let cubemap_bind_group = device.create_bind_group(&BindGroupDescriptor {
             label: Some("skybox bind group"),
             layout: &Skybox::cubemap_bind_group_layout(device),
@@ -164,6 +165,62 @@ impl Skybox {
         })
     }
 
+    /// Area light emitters, depth tested and written against the gbuffer depth like opaque
+    /// geometry. Group 1 is unused but keeps the lighting bind group at the index it has in
+    /// compose.wgsl.
+    pub fn emitter_pipeline(device: &Device, shader_module: &ShaderModule) -> RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Skybox emitter pipeline"),
+
+            layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some("Skybox emitter pipeline layout"),
+                bind_group_layouts: &[
+                    &SceneUniform::bind_group_layout(device),
+                    &Skybox::cubemap_bind_group_layout(device),
+                    &LightingUniform::bind_group_layout(device),
+                ],
+                push_constant_ranges: &[],
+            })),
+            vertex: VertexState {
+                module: shader_module,
+                entry_point: "vs_emitter",
+                buffers: &[],
+            },
+            fragment: Some(FragmentState {
+                module: shader_module,
+                entry_point: "fs_emitter",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: wgpu::TextureFormat::Rgba16Float,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+
+            multiview: None,
+        })
+    }
+
     pub fn pass(
         &self,
         scene: &Scene,
@@ -198,6 +255,17 @@ impl Skybox {
             pass.set_bind_group(0, &scene.scene.uniform_bind_group, &[]);
             pass.set_bind_group(1, &self.cubemap_bind_group, &[]);
             pass.draw(0..36, 0..1);
+
+            pass.set_pipeline(&self.emitter_pipeline);
+            pass.set_bind_group(2, &scene.lighting.uniform_bind_group, &[]);
+            for (i, light) in scene.lights.iter().take(MAX_LIGHTS).enumerate() {
+                let vertex_count = match light.kind {
+                    LightKind::Rect { .. } => 6,
+                    LightKind::Disk { .. } => Skybox::DISK_SEGMENTS * 3,
+                    _ => continue,
+                };
+                pass.draw(0..vertex_count, i as u32..i as u32 + 1);
+            }
         }
     }
 }
@@ -214,5 +282,6 @@ impl ReloadableShaders for Skybox {
         shader_module: wgpu::ShaderModule,
     ) {
         self.pipeline = Skybox::pipeline(device, &shader_module);
+        self.emitter_pipeline = Skybox::emitter_pipeline(device, &shader_module);
     }
 }
@@ -1,21 +1,62 @@
 use glam::vec3;
 use half::f16;
-use rand::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use wgpu::{
     BindGroupLayout, Device, FragmentState, MultisampleState, PipelineLayoutDescriptor,
     PrimitiveState, RenderPipeline, ShaderModule, TextureFormat, TextureUsages, TextureView,
     VertexState,
 };
 
-use crate::{gbuffers::GBuffers, loader::Scene, resources::SceneUniform, texture::Texture};
+use crate::{
+    bytemuck_impl, gbuffers::GBuffers, loader::Scene, resources::SceneUniform, texture::Texture,
+    uniform::Uniform,
+};
 
 use super::ReloadableShaders;
 
+/// Sample kernel is stored in an 8x8 texture
+pub const MAX_SSAO_SAMPLES: u32 = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SsaoParams {
+    /// View space radius of the sampled hemisphere
+    pub radius: f32,
+    /// Depth difference a sample needs before it counts as occluding (fights self-occlusion)
+    pub bias: f32,
+    /// Exponent applied to the final ambient factor
+    pub intensity: f32,
+    pub sample_count: u32,
+}
+bytemuck_impl!(SsaoParams);
+
+impl Default for SsaoParams {
+    fn default() -> Self {
+        SsaoParams {
+            radius: 0.5,
+            bias: 0.025,
+            intensity: 1.0,
+            sample_count: 16,
+        }
+    }
+}
+
+pub type SsaoUniform = Uniform<SsaoParams>;
+
+/// Hemisphere SSAO. Writes unblurred occlusion into its own texture, then a depth-aware blur
+/// writes the result into gbuffers.occlusion. Has no bent normal and occludes diffuse and
+/// specular the same.
 pub struct SSAO {
     pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+    pub uniform: SsaoUniform,
     textures_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
     sample_kernel: Texture,
     random_noise: Texture,
+    raw_occlusion: Texture,
+    /// Sample count and seed the kernel/noise textures were last generated with
+    kernel_key: (u32, u64),
 }
 
 fn lerp(v0: f32, v1: f32, t: f32) -> f32 {
@@ -23,19 +64,20 @@ fn lerp(v0: f32, v1: f32, t: f32) -> f32 {
 }
 
 impl SSAO {
-    pub fn sample_kernel_16x1() -> Vec<f16> {
+    /// Hemisphere (+z) sample kernel for an 8x8 texture, only the first sample_count texels
+    /// are used. Samples are scaled so more of them land close to the origin.
+    pub fn sample_kernel_8x8(sample_count: u32, rng: &mut StdRng) -> Vec<f16> {
         let mut vec: Vec<f16> = Vec::new();
-        let mut rng = rand::thread_rng();
 
-        for i in 0..16 {
+        for i in 0..MAX_SSAO_SAMPLES {
             let mut sample = vec3(
                 rng.gen_range(-1.0..1.0),
                 rng.gen_range(-1.0..1.0),
                 rng.gen_range(0.0..1.0),
             );
-            sample = sample.normalize();
+            sample = sample.normalize_or_zero();
 
-            let mut scale: f32 = i as f32 / 16.0;
+            let mut scale: f32 = i as f32 / sample_count as f32;
             scale = lerp(0.1, 1.0, scale * scale);
             sample *= scale;
 
@@ -48,13 +90,12 @@ impl SSAO {
         vec
     }
 
-    pub fn random_noise_16x1() -> Vec<f16> {
+    pub fn random_noise_4x4(rng: &mut StdRng) -> Vec<f16> {
         let mut vec: Vec<f16> = Vec::new();
-        let mut rng = rand::thread_rng();
 
-        for i in 0..16 {
+        for _ in 0..16 {
             let mut noise = vec3(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0);
-            noise = noise.normalize();
+            noise = noise.normalize_or_zero();
 
             vec.push(f16::from_f32(noise.x));
             vec.push(f16::from_f32(noise.y));
@@ -65,38 +106,145 @@ impl SSAO {
         vec
     }
 
-    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, gbuffers: &GBuffers) -> Self {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        config: &wgpu::SurfaceConfiguration,
+        gbuffers: &GBuffers,
+        seed: u64,
+    ) -> Self {
         let shader = device.create_shader_module(wgpu::include_wgsl!("../shaders/ssao.wgsl", true));
         let pipeline = SSAO::pipeline(device, &shader);
+        let blur_pipeline = SSAO::blur_pipeline(device, &shader);
 
-        let sample_kernel_data = SSAO::sample_kernel_16x1();
+        let params = SsaoParams::default();
+        let uniform = SsaoUniform::new(device, Some("SSAO"), params);
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let sample_kernel_data = SSAO::sample_kernel_8x8(params.sample_count, &mut rng);
         let sample_kernel = Texture::new_from_bytes(
             device,
             queue,
             bytemuck::cast_slice(sample_kernel_data.as_slice()),
-            4,
-            4,
+            8,
+            8,
             TextureFormat::Rgba16Float,
             TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-            Some("Sample kernel (16x1)"),
+            Some("Sample kernel (8x8)"),
             false,
         );
 
-        let random_noise_data = SSAO::random_noise_16x1();
+        let random_noise_data = SSAO::random_noise_4x4(&mut rng);
         let random_noise = Texture::new_from_bytes(
             device,
             queue,
-            bytemuck::cast_slice(sample_kernel_data.as_slice()),
+            bytemuck::cast_slice(random_noise_data.as_slice()),
             4,
             4,
             TextureFormat::Rgba16Float,
             TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-            Some("Random noise (16x1)"),
+            Some("Random noise (4x4)"),
+            false,
+        );
+
+        let raw_occlusion = SSAO::raw_occlusion(device, config);
+        let textures_bind_group =
+            SSAO::textures_bind_group(device, gbuffers, &sample_kernel, &random_noise, &uniform);
+        let blur_bind_group = SSAO::blur_bind_group(device, gbuffers, &raw_occlusion);
+
+        Self {
+            pipeline,
+            blur_pipeline,
+            uniform,
+            textures_bind_group,
+            blur_bind_group,
+            sample_kernel,
+            random_noise,
+            raw_occlusion,
+            kernel_key: (params.sample_count, seed),
+        }
+    }
+
+    fn raw_occlusion(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Texture {
+        Texture::new(
+            device,
+            config.width,
+            config.height,
+            TextureFormat::Rgba16Float,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            Some("SSAO - unblurred occlusion"),
             false,
+        )
+    }
+
+    /// Upload new parameters, regenerating the kernel and noise if the sample count or seed
+    /// changed.
+    pub fn update(&mut self, queue: &wgpu::Queue, params: SsaoParams, seed: u64) {
+        let params = SsaoParams {
+            sample_count: params.sample_count.clamp(1, MAX_SSAO_SAMPLES),
+            ..params
+        };
+        self.uniform.update(queue, params);
+
+        if self.kernel_key == (params.sample_count, seed) {
+            return;
+        }
+        self.kernel_key = (params.sample_count, seed);
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let sample_kernel_data = SSAO::sample_kernel_8x8(params.sample_count, &mut rng);
+        let random_noise_data = SSAO::random_noise_4x4(&mut rng);
+        for (texture, data) in [
+            (&self.sample_kernel, sample_kernel_data),
+            (&self.random_noise, random_noise_data),
+        ] {
+            let size = texture.texture.size();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                bytemuck::cast_slice(data.as_slice()),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(8 * size.width),
+                    rows_per_image: Some(size.height),
+                },
+                size,
+            );
+        }
+    }
+
+    /// Recreate everything that depends on the gbuffers (e.g. after they were recreated on
+    /// resize).
+    pub fn update_input(
+        &mut self,
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        gbuffers: &GBuffers,
+    ) {
+        self.raw_occlusion = SSAO::raw_occlusion(device, config);
+        self.textures_bind_group = SSAO::textures_bind_group(
+            device,
+            gbuffers,
+            &self.sample_kernel,
+            &self.random_noise,
+            &self.uniform,
         );
+        self.blur_bind_group = SSAO::blur_bind_group(device, gbuffers, &self.raw_occlusion);
+    }
 
-        let textures_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
+    fn textures_bind_group(
+        device: &wgpu::Device,
+        gbuffers: &GBuffers,
+        sample_kernel: &Texture,
+        random_noise: &Texture,
+        uniform: &SsaoUniform,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("SSAO textures bind group"),
             layout: &SSAO::textures_bind_group_layout(device),
             entries: &[
                 wgpu::BindGroupEntry {
@@ -115,20 +263,35 @@ impl SSAO {
                     binding: 3,
                     resource: wgpu::BindingResource::TextureView(&random_noise.view),
                 },
+                uniform.bind_group_entry(4),
             ],
-        });
+        })
+    }
 
-        Self {
-            pipeline,
-            textures_bind_group,
-            sample_kernel,
-            random_noise,
-        }
+    fn blur_bind_group(
+        device: &wgpu::Device,
+        gbuffers: &GBuffers,
+        raw_occlusion: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("SSAO blur bind group"),
+            layout: &SSAO::blur_bind_group_layout(device),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&gbuffers.depth.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&raw_occlusion.view),
+                },
+            ],
+        })
     }
 
     pub fn textures_bind_group_layout(device: &wgpu::Device) -> BindGroupLayout {
         device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: None,
+            label: Some("SSAO textures bind group layout"),
             entries: &[
                 wgpu::BindGroupLayoutEntry {
                     binding: 0,
@@ -170,30 +333,84 @@ impl SSAO {
                     },
                     count: None,
                 },
+                SsaoUniform::bind_group_layout_entry(4),
+            ],
+        })
+    }
+
+    /// Blur shares binding 0 (depth) with the SSAO textures, the unblurred occlusion is at 5
+    /// so both can live in one shader file.
+    pub fn blur_bind_group_layout(device: &wgpu::Device) -> BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("SSAO blur bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
             ],
         })
     }
 
     pub fn pipeline(device: &wgpu::Device, shader: &ShaderModule) -> RenderPipeline {
+        SSAO::fullscreen_pipeline(
+            device,
+            shader,
+            "fs_main",
+            &SSAO::textures_bind_group_layout(device),
+            "SSAO pipeline",
+        )
+    }
+
+    pub fn blur_pipeline(device: &wgpu::Device, shader: &ShaderModule) -> RenderPipeline {
+        SSAO::fullscreen_pipeline(
+            device,
+            shader,
+            "fs_blur",
+            &SSAO::blur_bind_group_layout(device),
+            "SSAO blur pipeline",
+        )
+    }
+
+    fn fullscreen_pipeline(
+        device: &wgpu::Device,
+        shader: &ShaderModule,
+        entry_point: &str,
+        textures_layout: &BindGroupLayout,
+        label: &str,
+    ) -> RenderPipeline {
         device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("SSAO pipeline"),
+            label: Some(label),
 
             layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
-                label: Some("SSAO pipeline layout"),
-                bind_group_layouts: &[
-                    &SceneUniform::bind_group_layout(device),
-                    &SSAO::textures_bind_group_layout(device),
-                ],
+                label: Some(format!("{} layout", label).as_str()),
+                bind_group_layouts: &[&SceneUniform::bind_group_layout(device), textures_layout],
                 push_constant_ranges: &[],
             })),
             vertex: VertexState {
-                module: &shader,
+                module: shader,
                 entry_point: "vs_main",
                 buffers: &[],
             },
             fragment: Some(FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
+                module: shader,
+                entry_point,
                 targets: &[Some(wgpu::ColorTargetState {
                     format: wgpu::TextureFormat::Rgba16Float,
                     blend: None,
@@ -220,29 +437,53 @@ impl SSAO {
         })
     }
 
-    pub fn pass(&self, scene: &Scene, output: &TextureView, encoder: &mut wgpu::CommandEncoder) {
-        {
-            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("SSAO"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: output,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: None,
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            });
+    fn fullscreen_pass(
+        pipeline: &RenderPipeline,
+        scene: &Scene,
+        textures_bind_group: &wgpu::BindGroup,
+        output: &TextureView,
+        label: &str,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(GBuffers::NO_OCCLUSION),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
 
-            pass.set_pipeline(&self.pipeline);
-            pass.set_bind_group(0, &scene.scene.uniform_bind_group, &[]);
-            pass.set_bind_group(1, &self.textures_bind_group, &[]);
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, &scene.scene.uniform_bind_group, &[]);
+        pass.set_bind_group(1, textures_bind_group, &[]);
 
-            pass.draw(0..6, 0..1);
-        }
+        pass.draw(0..6, 0..1);
+    }
+
+    pub fn pass(&self, scene: &Scene, gbuffers: &GBuffers, encoder: &mut wgpu::CommandEncoder) {
+        SSAO::fullscreen_pass(
+            &self.pipeline,
+            scene,
+            &self.textures_bind_group,
+            &self.raw_occlusion.view,
+            "SSAO",
+            encoder,
+        );
+        SSAO::fullscreen_pass(
+            &self.blur_pipeline,
+            scene,
+            &self.blur_bind_group,
+            &gbuffers.occlusion.view,
+            "SSAO blur",
+            encoder,
+        );
     }
 }
 
@@ -254,9 +495,10 @@ impl ReloadableShaders for SSAO {
     fn reload(
         &mut self,
         device: &Device,
-        config: &wgpu::SurfaceConfiguration,
+        _config: &wgpu::SurfaceConfiguration,
         shader_module: wgpu::ShaderModule,
     ) {
         self.pipeline = SSAO::pipeline(device, &shader_module);
+        self.blur_pipeline = SSAO::blur_pipeline(device, &shader_module);
     }
 }
@@ -21,22 +21,30 @@ impl Tonemapping {
         let shader =
             device.create_shader_module(wgpu::include_wgsl!("../shaders/tonemapping.wgsl", true));
 
-        let texture_bind_group_layout = Tonemapping::texture_bind_group_layout(device);
         let pipeline = Tonemapping::pipeline(device, config, &shader);
+        let texture_bind_group = Tonemapping::texture_bind_group(device, texture);
 
-        let texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        Self {
+            texture_bind_group,
+            pipeline,
+        }
+    }
+
+    /// Point the pass at a new input texture (e.g. after the compose output was recreated
+    /// on resize).
+    pub fn update_input(&mut self, device: &wgpu::Device, texture: &Texture) {
+        self.texture_bind_group = Tonemapping::texture_bind_group(device, texture);
+    }
+
+    fn texture_bind_group(device: &wgpu::Device, texture: &Texture) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
-            layout: &texture_bind_group_layout,
+            layout: &Tonemapping::texture_bind_group_layout(device),
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
                 resource: wgpu::BindingResource::TextureView(&texture.view),
             }],
-        });
-
-        Self {
-            texture_bind_group,
-            pipeline,
-        }
+        })
     }
 
     pub fn texture_bind_group_layout(device: &wgpu::Device) -> BindGroupLayout {
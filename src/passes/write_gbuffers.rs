@@ -7,14 +7,14 @@ use crate::{
     common::VertexAttributes,
     gbuffers::GBuffers,
     loader::Scene,
-    resources::{Material, Mesh, SceneUniform},
+    resources::{AlphaMode, Material, MeshInstance, SceneUniform},
     texture::Texture,
 };
 
-use super::ReloadableShaders;
+use super::{MeshPipelines, ReloadableShaders};
 
 pub struct WriteGBuffers {
-    pipeline: wgpu::RenderPipeline,
+    pipelines: MeshPipelines,
 }
 
 impl WriteGBuffers {
@@ -22,27 +22,43 @@ impl WriteGBuffers {
         let shader = device
             .create_shader_module(wgpu::include_wgsl!("../shaders/write_gbuffers.wgsl", true));
 
-        let pipeline = WriteGBuffers::pipeline(device, &shader);
-        Self { pipeline }
+        let pipelines = MeshPipelines::new(|double_sided, mirrored| {
+            WriteGBuffers::pipeline(device, &shader, double_sided, mirrored)
+        });
+        Self { pipelines }
     }
 
-    pub fn pipeline(device: &wgpu::Device, shader: &ShaderModule) -> RenderPipeline {
+    /// Single sided materials cull back faces, double sided ones draw them (the shader flips
+    /// their normal). Mirrored instances flip the winding, so their front faces are clockwise.
+    pub fn pipeline(
+        device: &wgpu::Device,
+        shader: &ShaderModule,
+        double_sided: bool,
+        mirrored: bool,
+    ) -> RenderPipeline {
         device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Write gbuffers pipeline"),
+            label: Some(match (double_sided, mirrored) {
+                (false, false) => "Write gbuffers pipeline",
+                (false, true) => "Write gbuffers pipeline (mirrored)",
+                (true, false) => "Write gbuffers pipeline (double sided)",
+                (true, true) => "Write gbuffers pipeline (double sided, mirrored)",
+            }),
 
             layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
                 label: Some("Write gbuffers pipeline layout"),
                 bind_group_layouts: &[
                     &SceneUniform::bind_group_layout(device),
                     &Material::bind_group_layout(device),
-                    &Mesh::bind_group_layout(device),
                 ],
                 push_constant_ranges: &[],
             })),
             vertex: VertexState {
                 module: &shader,
                 entry_point: "vs_main",
-                buffers: &[VertexAttributes::buffer_layout()],
+                buffers: &[
+                    VertexAttributes::buffer_layout(),
+                    MeshInstance::buffer_layout(),
+                ],
             },
             fragment: Some(FragmentState {
                 module: &shader,
@@ -63,13 +79,26 @@ impl WriteGBuffers {
                         blend: Some(BlendState::REPLACE),
                         write_mask: wgpu::ColorWrites::ALL,
                     }),
+                    Some(wgpu::ColorTargetState {
+                        format: wgpu::TextureFormat::Rgba16Float,
+                        blend: Some(BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }),
                 ],
             }),
             primitive: PrimitiveState {
                 topology: wgpu::PrimitiveTopology::TriangleList,
                 strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: None,
+                front_face: if mirrored {
+                    wgpu::FrontFace::Cw
+                } else {
+                    wgpu::FrontFace::Ccw
+                },
+                cull_mode: if double_sided {
+                    None
+                } else {
+                    Some(wgpu::Face::Back)
+                },
                 unclipped_depth: false,
                 polygon_mode: wgpu::PolygonMode::Fill,
                 conservative: false,
@@ -121,6 +150,14 @@ impl WriteGBuffers {
                             store: true,
                         },
                     }),
+                    Some(wgpu::RenderPassColorAttachment {
+                        view: &gbuffers.emissive.view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    }),
                 ],
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: &gbuffers.depth.view,
@@ -134,15 +171,27 @@ impl WriteGBuffers {
                 occlusion_query_set: None,
             });
 
-            pass.set_pipeline(&self.pipeline);
             pass.set_bind_group(0, &scene.scene.uniform_bind_group, &[]);
+            pass.set_vertex_buffer(1, scene.instances.buffer.slice(..));
 
-            for mesh in &scene.meshes {
-                pass.set_bind_group(1, &scene.materials[mesh.material_index].bind_group, &[]);
-                pass.set_bind_group(2, &mesh.bind_group, &[]);
+            // Single sided meshes first, then double sided, so the pipeline only changes once
+            // Blended meshes are drawn by Compose's forward pass instead
+            for mesh in scene
+                .meshes
+                .iter()
+                .filter(|x| scene.materials[x.material_index].alpha_mode != AlphaMode::Blend)
+            {
+                let material = &scene.materials[mesh.material_index];
+                pass.set_bind_group(1, &material.bind_group, &[]);
                 pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
                 pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-                pass.draw_indexed(0..mesh.index_count, 0, 0..1);
+                for (instances, mirrored) in mesh.split_mirrored(mesh.instances.clone()) {
+                    if instances.is_empty() {
+                        continue;
+                    }
+                    pass.set_pipeline(self.pipelines.get(material.double_sided, mirrored));
+                    pass.draw_indexed(0..mesh.index_count, 0, instances);
+                }
             }
         }
     }
@@ -159,6 +208,8 @@ impl ReloadableShaders for WriteGBuffers {
         _config: &wgpu::SurfaceConfiguration,
         shader_module: wgpu::ShaderModule,
     ) {
-        self.pipeline = WriteGBuffers::pipeline(device, &shader_module);
+        self.pipelines = MeshPipelines::new(|double_sided, mirrored| {
+            WriteGBuffers::pipeline(device, &shader_module, double_sided, mirrored)
+        });
     }
 }
@@ -1,53 +1,73 @@
-use std::num::NonZeroU64;
-
-use glam::{vec3, Mat4, Vec3};
 use wgpu::{
-    include_wgsl, Device, MultisampleState, PipelineLayoutDescriptor, PrimitiveState, Queue,
-    RenderPipeline, ShaderModule, VertexState,
+    include_wgsl, Device, FragmentState, MultisampleState, PipelineLayoutDescriptor,
+    PrimitiveState, RenderPipeline, ShaderModule, TextureView, VertexState,
 };
 
 use crate::{
-    bytemuck_impl,
     common::VertexAttributes,
     loader::Scene,
-    resources::Mesh,
-    shadowmap::{ShadowData, Shadowmap, Shadows},
+    resources::{AlphaMode, Material, MeshInstance},
+    shadowmap::{ShadowViewUniform, Shadows},
     texture::Texture,
-    uniform::Uniform,
 };
 
+use super::ReloadableShaders;
+
 pub struct WriteShadowmaps {
     pipeline: wgpu::RenderPipeline,
+    masked_pipeline: wgpu::RenderPipeline,
 }
 
 impl WriteShadowmaps {
-    pub fn new(device: &Device, queue: &Queue, data: ShadowData) -> Self {
+    pub fn new(device: &Device) -> Self {
         let shader =
             device.create_shader_module(include_wgsl!("../shaders/write_shadowmaps.wgsl", true));
 
-        let pipeline = WriteShadowmaps::pipeline(device, &shader);
+        let pipeline = WriteShadowmaps::pipeline(device, &shader, false);
+        let masked_pipeline = WriteShadowmaps::pipeline(device, &shader, true);
 
-        Self { pipeline }
+        Self {
+            pipeline,
+            masked_pipeline,
+        }
     }
 
-    pub fn pipeline(device: &wgpu::Device, shader: &ShaderModule) -> RenderPipeline {
+    /// Opaque meshes only write depth. Masked ones bind their material and discard texels below
+    /// the alpha cutoff, so cut-outs cast cut-out shadows.
+    pub fn pipeline(device: &wgpu::Device, shader: &ShaderModule, masked: bool) -> RenderPipeline {
+        let shadow_view_layout = ShadowViewUniform::bind_group_layout(device);
+        let material_layout = Material::bind_group_layout(device);
+        let bind_group_layouts: &[&wgpu::BindGroupLayout] = if masked {
+            &[&shadow_view_layout, &material_layout]
+        } else {
+            &[&shadow_view_layout]
+        };
+
         device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Write shadowmaps pipeline"),
+            label: Some(if masked {
+                "Write shadowmaps pipeline (masked)"
+            } else {
+                "Write shadowmaps pipeline"
+            }),
 
             layout: Some(&device.create_pipeline_layout(&PipelineLayoutDescriptor {
                 label: Some("Write shadowmaps pipeline layout"),
-                bind_group_layouts: &[
-                    &Uniform::<ShadowData>::bind_group_layout(device),
-                    &Mesh::bind_group_layout(device),
-                ],
+                bind_group_layouts,
                 push_constant_ranges: &[],
             })),
             vertex: VertexState {
                 module: &shader,
                 entry_point: "vs_main",
-                buffers: &[VertexAttributes::buffer_layout()],
+                buffers: &[
+                    VertexAttributes::buffer_layout(),
+                    MeshInstance::buffer_layout(),
+                ],
             },
-            fragment: None,
+            fragment: masked.then_some(FragmentState {
+                module: shader,
+                entry_point: "fs_masked",
+                targets: &[],
+            }),
             primitive: PrimitiveState {
                 topology: wgpu::PrimitiveTopology::TriangleList,
                 strip_index_format: None,
@@ -62,7 +82,12 @@ impl WriteShadowmaps {
                 depth_write_enabled: true,
                 depth_compare: wgpu::CompareFunction::Less,
                 stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
+                // Slope scaled bias to fight shadow acne on surfaces at grazing angles to the light
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
             }),
             multisample: MultisampleState {
                 count: 1,
@@ -74,32 +99,87 @@ impl WriteShadowmaps {
         })
     }
 
+    /// Renders every cascade into its own layer of the shadowmap, then the cube faces of
+    /// every point light that was given a shadow slot. Blended meshes don't cast shadows.
     pub fn pass(&self, scene: &Scene, shadows: &Shadows, encoder: &mut wgpu::CommandEncoder) {
+        for (layer_view, cascade) in shadows
+            .shadowmap
+            .layer_views
+            .iter()
+            .zip(shadows.cascades.iter())
+        {
+            self.draw_meshes(scene, layer_view, cascade, encoder);
+        }
+
+        let point_faces = 6 * shadows.point_count as usize;
+        for (face_view, face) in shadows.point_shadowmap.face_views[..point_faces]
+            .iter()
+            .zip(shadows.point_views.iter())
         {
-            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Write Shadowmaps pass"),
-                color_attachments: &[],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &shadows.shadowmap.texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
+            self.draw_meshes(scene, face_view, face, encoder);
+        }
+    }
+
+    fn draw_meshes(
+        &self,
+        scene: &Scene,
+        target: &TextureView,
+        shadow_view: &ShadowViewUniform,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Write Shadowmaps pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: target,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
                 }),
-                timestamp_writes: None,
-                occlusion_query_set: None,
-            });
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        pass.set_bind_group(0, &shadow_view.bind_group, &[]);
+        pass.set_vertex_buffer(1, scene.instances.buffer.slice(..));
 
-            pass.set_pipeline(&self.pipeline);
-            pass.set_bind_group(0, &shadows.uniform.bind_group, &[]);
+        // Opaque meshes first, then masked, so the pipeline only changes once
+        for (pipeline, alpha_mode) in [
+            (&self.pipeline, AlphaMode::Opaque),
+            (&self.masked_pipeline, AlphaMode::Mask),
+        ] {
+            pass.set_pipeline(pipeline);
 
-            for mesh in &scene.meshes {
-                pass.set_bind_group(1, &mesh.bind_group, &[]);
+            for mesh in scene
+                .meshes
+                .iter()
+                .filter(|x| scene.materials[x.material_index].alpha_mode == alpha_mode)
+            {
+                if alpha_mode == AlphaMode::Mask {
+                    pass.set_bind_group(1, &scene.materials[mesh.material_index].bind_group, &[]);
+                }
                 pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
                 pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-                pass.draw_indexed(0..mesh.index_count, 0, 0..1);
+                pass.draw_indexed(0..mesh.index_count, 0, mesh.instances.clone());
             }
         }
     }
 }
+
+impl ReloadableShaders for WriteShadowmaps {
+    fn available_shaders() -> &'static [&'static str] {
+        &["../shaders/write_shadowmaps.wgsl"]
+    }
+
+    fn reload(
+        &mut self,
+        device: &Device,
+        _config: &wgpu::SurfaceConfiguration,
+        shader_module: wgpu::ShaderModule,
+    ) {
+        self.pipeline = WriteShadowmaps::pipeline(device, &shader_module, false);
+        self.masked_pipeline = WriteShadowmaps::pipeline(device, &shader_module, true);
+    }
+}
@@ -1,42 +1,213 @@
-use std::{fs, path::Path, time::Duration};
+use std::{
+    error::Error,
+    f32::consts::{FRAC_PI_2, FRAC_PI_4},
+    fmt, fs,
+    path::Path,
+    time::Duration,
+};
 
 use egui::{ClippedPrimitive, Color32, TexturesDelta};
 use egui_wgpu::renderer::ScreenDescriptor;
+use glam::Vec3;
 use pollster::block_on;
-use wgpu::{RenderPassDescriptor, ShaderModuleDescriptor, TextureUsages};
-use winit::{event::WindowEvent, window::Window};
+use wgpu::{RenderPassDescriptor, ShaderModuleDescriptor, TextureUsages, TextureView};
+use winit::{dpi::PhysicalSize, event::WindowEvent, window::Window};
 
 use crate::{
     camera::{Camera, CameraController, FlyingCamera},
+    capture::{self, CaptureError},
+    cubemap::Cubemap,
+    environment::Environment,
     gbuffers::GBuffers,
-    loader::Scene,
+    loader::{Scene, SceneLoadError},
     passes::{
-        self, Compose, Fxaa, FxaaParams, ReloadableShaders, Skybox, Tonemapping, WriteGBuffers,
+        self, Compose, Fxaa, FxaaParams, Gtao, GtaoParams, LightClusters, ReloadableShaders,
+        Skybox, SsaoParams, Tonemapping, WriteGBuffers, WriteShadowmaps, MAX_SSAO_SAMPLES, SSAO,
     },
-    resources::SceneUniformData,
-    shadowmap::{ShadowData, Shadows},
+    resources::{Light, LightKind, LightingUniformData, SceneUniformData, MAX_LIGHTS},
+    shadowmap::{self, CascadeSettings, ShadowData, Shadows, MAX_CASCADES, MAX_POINT_SHADOWS},
     texture::Texture,
     RendererConfig,
 };
 
-#[derive(Default)]
+/// Lumens of point, spot and area lights added from the UI
+const NEW_LIGHT_POWER: f32 = 100_000.0;
+/// Lux of directional lights added from the UI
+const NEW_LIGHT_ILLUMINANCE: f32 = 10_000.0;
+/// Light types the UI can add or switch a light to, with the sizes they start with
+const LIGHT_KINDS: [LightKind; 5] = [
+    LightKind::Point,
+    LightKind::Spot {
+        inner_cone_angle: 0.0,
+        outer_cone_angle: FRAC_PI_4,
+    },
+    LightKind::Directional,
+    LightKind::Rect {
+        width: 1.0,
+        height: 1.0,
+        two_sided: false,
+    },
+    LightKind::Disk {
+        radius: 0.5,
+        two_sided: false,
+    },
+];
+
+/// Bind groups Compose uses (scene, gbuffers, lighting, IBL and shadows), more than the 4 every
+/// adapter has
+const REQUIRED_BIND_GROUPS: u32 = 5;
+/// Downlevel capabilities the passes can't do without: compute for LightClusters and the
+/// environment bakes, light storage buffers in Compose's fragment shaders, comparison samplers
+/// for shadows, per target blending for weighted blended transparency and mipmapped glTF images
+/// of any size. Cube arrays aren't needed, point shadows sample their faces from a 2D array.
+const REQUIRED_DOWNLEVEL_FLAGS: wgpu::DownlevelFlags = wgpu::DownlevelFlags::COMPUTE_SHADERS
+    .union(wgpu::DownlevelFlags::FRAGMENT_STORAGE)
+    .union(wgpu::DownlevelFlags::COMPARISON_SAMPLERS)
+    .union(wgpu::DownlevelFlags::INDEPENDENT_BLEND)
+    .union(wgpu::DownlevelFlags::NON_POWER_OF_TWO_MIPMAPPED_TEXTURES);
+
+/// Why the renderer couldn't start: no device to render with, or a file it was asked to load
+/// couldn't be.
+#[derive(Debug)]
+pub enum StartupError {
+    /// No adapter at all, not even a software one
+    NoAdapter,
+    /// The adapter can't bind as many groups as the passes need
+    TooFewBindGroups {
+        adapter: String,
+        max_bind_groups: u32,
+    },
+    /// The adapter lacks some of REQUIRED_DOWNLEVEL_FLAGS
+    MissingDownlevelFlags {
+        adapter: String,
+        missing: wgpu::DownlevelFlags,
+    },
+    RequestDevice(wgpu::RequestDeviceError),
+    /// The --gltf scene
+    Scene {
+        path: String,
+        error: SceneLoadError,
+    },
+    /// The --environment panorama
+    Environment {
+        path: String,
+        error: image::ImageError,
+    },
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::NoAdapter => write!(f, "no adapter found (including software fallback)"),
+            StartupError::TooFewBindGroups {
+                adapter,
+                max_bind_groups,
+            } => write!(
+                f,
+                "adapter {} supports {} bind groups, {} are needed",
+                adapter, max_bind_groups, REQUIRED_BIND_GROUPS
+            ),
+            StartupError::MissingDownlevelFlags { adapter, missing } => {
+                write!(f, "adapter {} doesn't support {:?}", adapter, missing)
+            }
+            StartupError::RequestDevice(x) => write!(f, "couldn't create device: {}", x),
+            StartupError::Scene { path, error } => {
+                write!(f, "failed to load glTF {}: {}", path, error)
+            }
+            StartupError::Environment { path, error } => {
+                write!(f, "failed to load environment {}: {}", path, error)
+            }
+        }
+    }
+}
+
+impl Error for StartupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StartupError::RequestDevice(x) => Some(x),
+            StartupError::Scene { error, .. } => Some(error),
+            StartupError::Environment { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// How meshes with alpha blended materials are drawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transparency {
+    /// Sorted back to front per mesh
+    Sorted,
+    /// Weighted blended order independent transparency
+    WeightedBlended,
+}
+
+/// Which pass fills the occlusion gbuffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AmbientOcclusion {
+    Off,
+    Ssao,
+    Gtao,
+}
+
 pub struct RendererUIState {
     shader_error_message: String,
     loader_error_message: String,
 
-    // Debugging shadows
+    // Directional light & shadows, for scenes without a directional light of their own
     shadow_theta: f32,
     shadow_phi: f32,
-    shadow_dist: f32,
+    /// In lux
+    sun_illuminance: f32,
+    /// Luminance (cd/m²) of the skybox and IBL maps
+    environment_intensity: f32,
+    cascades: CascadeSettings,
+    /// How many point lights can cast shadows at once
+    point_shadow_budget: u32,
+
+    ambient_occlusion: AmbientOcclusion,
+    ssao_params: SsaoParams,
+    /// Seed for the SSAO sample kernel/noise, same seed gives the same image
+    ssao_seed: u64,
+    gtao_params: GtaoParams,
+
+    transparency: Transparency,
+
+    /// Show lights per cluster instead of the shaded image
+    light_heatmap: bool,
 
     fxaa_enabled: bool,
     fxaa_params: FxaaParams,
 }
 
+impl Default for RendererUIState {
+    fn default() -> Self {
+        Self {
+            shader_error_message: String::new(),
+            loader_error_message: String::new(),
+            shadow_theta: 45.0,
+            shadow_phi: 45.0,
+            sun_illuminance: 100_000.0,
+            environment_intensity: 30_000.0,
+            cascades: CascadeSettings::default(),
+            point_shadow_budget: 4,
+            ambient_occlusion: AmbientOcclusion::Ssao,
+            ssao_params: SsaoParams::default(),
+            ssao_seed: 0,
+            gtao_params: GtaoParams::default(),
+            transparency: Transparency::Sorted,
+            light_heatmap: false,
+            fxaa_enabled: false,
+            fxaa_params: FxaaParams::default(),
+        }
+    }
+}
+
 pub struct Renderer {
     // wgpu internals
-    surface: wgpu::Surface,
+    /// None when running headless
+    surface: Option<wgpu::Surface>,
     config: wgpu::SurfaceConfiguration,
+    adapter_info: wgpu::AdapterInfo,
     device: wgpu::Device,
     queue: wgpu::Queue,
 
@@ -53,6 +224,10 @@ pub struct Renderer {
 
     // passes
     write_gbuffers: passes::WriteGBuffers,
+    light_clusters: passes::LightClusters,
+    ssao: passes::SSAO,
+    gtao: passes::Gtao,
+    write_shadowmaps: passes::WriteShadowmaps,
     compose: passes::Compose,
     skybox: passes::Skybox,
     tonemapping: passes::Tonemapping,
@@ -62,32 +237,17 @@ pub struct Renderer {
 }
 
 impl Renderer {
-    pub fn new(window: &Window, renderer_config: &RendererConfig) -> Self {
+    pub fn new(window: &Window, renderer_config: &RendererConfig) -> Result<Self, StartupError> {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::DX12,
+            backends: renderer_config.backend.backends(),
             dx12_shader_compiler: Default::default(),
         });
 
         let surface = unsafe { instance.create_surface(window) }.unwrap();
 
-        let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::default(),
-            compatible_surface: Some(&surface),
-            force_fallback_adapter: false,
-        }))
-        .unwrap();
-
-        let (device, queue) = block_on(adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                label: Some("Device"),
-                features: wgpu::Features::TIMESTAMP_QUERY,
-                limits: wgpu::Limits::default(),
-            },
-            None,
-        ))
-        .unwrap();
+        let (adapter, device, queue) = Renderer::request_device(&instance, Some(&surface))?;
 
         let surface_caps = surface.get_capabilities(&adapter);
 
@@ -111,43 +271,160 @@ impl Renderer {
         };
         surface.configure(&device, &config);
 
+        Renderer::from_device(
+            &adapter,
+            device,
+            queue,
+            Some(surface),
+            config,
+            renderer_config,
+        )
+    }
+
+    /// Create a renderer with no window or surface, for rendering frames straight to disk.
+    /// There is no surface to query so the output format is always Rgba8UnormSrgb.
+    pub fn new_headless(
+        renderer_config: &RendererConfig,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, StartupError> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: renderer_config.backend.backends(),
+            dx12_shader_compiler: Default::default(),
+        });
+
+        let (adapter, device, queue) = Renderer::request_device(&instance, None)?;
+
+        // Never used to configure a surface, but every pass takes its size/format from here.
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+        };
+
+        let mut renderer =
+            Renderer::from_device(&adapter, device, queue, None, config, renderer_config)?;
+        renderer.egui_state.fxaa_enabled = true;
+        Ok(renderer)
+    }
+
+    /// Request an adapter (falling back to a software one, e.g. lavapipe/llvmpipe/WARP, if
+    /// no hardware adapter is available) and a device with whatever optional features it has.
+    fn request_device(
+        instance: &wgpu::Instance,
+        compatible_surface: Option<&wgpu::Surface>,
+    ) -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue), StartupError> {
+        let request = |force_fallback_adapter| {
+            block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface,
+                force_fallback_adapter,
+            }))
+        };
+
+        let adapter = match request(false) {
+            Some(x) => x,
+            None => {
+                log::warn!("No hardware adapter found, falling back to software adapter");
+                request(true).ok_or(StartupError::NoAdapter)?
+            }
+        };
+        let info = adapter.get_info();
+        log::info!("Using adapter {} ({:?})", info.name, info.backend);
+
+        // Timestamp queries are nice-to-have (profiling), don't refuse to start without them
+        let features = adapter.features() & wgpu::Features::TIMESTAMP_QUERY;
+
+        let adapter_limits = adapter.limits();
+        if adapter_limits.max_bind_groups < REQUIRED_BIND_GROUPS {
+            return Err(StartupError::TooFewBindGroups {
+                adapter: info.name,
+                max_bind_groups: adapter_limits.max_bind_groups,
+            });
+        }
+
+        let missing = REQUIRED_DOWNLEVEL_FLAGS - adapter.get_downlevel_capabilities().flags;
+        if !missing.is_empty() {
+            return Err(StartupError::MissingDownlevelFlags {
+                adapter: info.name,
+                missing,
+            });
+        }
+
+        // Only ask for what downlevel and software adapters can do, apart from the bind groups,
+        // with textures as big as the adapter allows (for big windows). The downlevel flags
+        // checked above are all the passes need beyond that.
+        let limits = wgpu::Limits {
+            max_bind_groups: REQUIRED_BIND_GROUPS,
+            ..wgpu::Limits::downlevel_defaults().using_resolution(adapter_limits)
+        };
+
+        let (device, queue) = block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: Some("Device"),
+                features,
+                limits,
+            },
+            None,
+        ))
+        .map_err(StartupError::RequestDevice)?;
+
+        Ok((adapter, device, queue))
+    }
+
+    fn from_device(
+        adapter: &wgpu::Adapter,
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        surface: Option<wgpu::Surface>,
+        config: wgpu::SurfaceConfiguration,
+        renderer_config: &RendererConfig,
+    ) -> Result<Self, StartupError> {
         let camera = Camera::default();
         let camera_controller = Box::new(FlyingCamera::new());
         let scene = match &renderer_config.gltf {
-            Some(x) => Scene::from_gltf(&device, &queue, x).unwrap(),
+            Some(x) => {
+                Scene::from_gltf(&device, &queue, x).map_err(|error| StartupError::Scene {
+                    path: x.clone(),
+                    error,
+                })?
+            }
             None => Scene::new(&device),
         };
         let gbuffers = GBuffers::new(&device, &config);
-        let shadows = Shadows::new(&device, &config);
-        let compose_output = Texture::new(
-            &device,
-            config.width,
-            config.height,
-            wgpu::TextureFormat::Rgba16Float,
-            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
-            Some("Compose output/Tonemapping input"),
-            false,
-        );
-        let tonemapping_output = Texture::new(
+        let shadows = Shadows::new(
             &device,
-            config.width,
-            config.height,
-            config.format,
-            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
-            Some("Tonemapping out/Fxaa input"),
-            false,
+            CascadeSettings::default().count,
+            RendererUIState::default().point_shadow_budget,
         );
+        let compose_output = Renderer::compose_output(&device, &config);
+        let tonemapping_output = Renderer::tonemapping_output(&device, &config);
 
         let write_gbuffers = passes::WriteGBuffers::new(&device);
-        let compose = passes::Compose::new(&device, &queue, &renderer_config);
+        let light_clusters = passes::LightClusters::new(&device, &gbuffers);
+        let write_shadowmaps = passes::WriteShadowmaps::new(&device);
+        let compose = passes::Compose::new(&device, &queue, &config, &renderer_config);
         let skybox = passes::Skybox::new(&device, &queue, &renderer_config);
         let tonemapping = passes::Tonemapping::new(&device, &config, &compose_output);
         let fxaa = passes::Fxaa::new(&device, &config, &tonemapping_output);
+        let ssao = passes::SSAO::new(
+            &device,
+            &queue,
+            &config,
+            &gbuffers,
+            RendererUIState::default().ssao_seed,
+        );
+        let gtao = passes::Gtao::new(&device, &config, &gbuffers);
         let egui = egui_wgpu::renderer::Renderer::new(&device, config.format, None, 1);
 
-        Self {
+        let mut renderer = Self {
             surface,
             config,
+            adapter_info: adapter.get_info(),
             device,
             queue,
             camera,
@@ -157,14 +434,102 @@ impl Renderer {
             shadows,
             compose_output,
             write_gbuffers,
+            light_clusters,
+            write_shadowmaps,
             compose,
             skybox,
             tonemapping,
             tonemapping_output,
             egui,
             fxaa,
+            ssao,
+            gtao,
             egui_state: Default::default(),
+        };
+
+        if let Some(path) = &renderer_config.environment {
+            renderer
+                .load_environment(path)
+                .map_err(|error| StartupError::Environment {
+                    path: path.clone(),
+                    error,
+                })?;
+        }
+
+        Ok(renderer)
+    }
+
+    /// Bake the skybox and IBL maps from an equirectangular panorama (.hdr or .exr) and use them.
+    pub fn load_environment<P: AsRef<Path>>(&mut self, path: P) -> Result<(), image::ImageError> {
+        let environment = Environment::from_equirect(&self.device, &self.queue, path)?;
+        self.skybox.update_cubemap(&self.device, environment.skybox);
+        self.compose.ibl.update(
+            &self.device,
+            Some(environment.irradiance),
+            Some(environment.prefilter),
+            Some(environment.brdf_lookup),
+        );
+        Ok(())
+    }
+
+    fn compose_output(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Texture {
+        Texture::new(
+            device,
+            config.width,
+            config.height,
+            wgpu::TextureFormat::Rgba16Float,
+            TextureUsages::RENDER_ATTACHMENT
+                | TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_SRC,
+            Some("Compose output/Tonemapping input"),
+            false,
+        )
+    }
+
+    fn tonemapping_output(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Texture {
+        Texture::new(
+            device,
+            config.width,
+            config.height,
+            config.format,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            Some("Tonemapping out/Fxaa input"),
+            false,
+        )
+    }
+
+    pub fn size(&self) -> PhysicalSize<u32> {
+        PhysicalSize::new(self.config.width, self.config.height)
+    }
+
+    /// Reconfigure the surface and recreate every resource whose size depends on it.
+    pub fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        // Minimized windows report a zero size, which is not a valid surface/texture size
+        if new_size.width == 0 || new_size.height == 0 {
+            return;
+        }
+
+        self.config.width = new_size.width;
+        self.config.height = new_size.height;
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
         }
+
+        self.gbuffers = GBuffers::new(&self.device, &self.config);
+        self.ssao
+            .update_input(&self.device, &self.config, &self.gbuffers);
+        self.gtao
+            .update_input(&self.device, &self.config, &self.gbuffers);
+        self.light_clusters
+            .update_input(&self.device, &self.gbuffers);
+        self.compose.update_input(&self.device, &self.config);
+        self.compose_output = Renderer::compose_output(&self.device, &self.config);
+        self.tonemapping_output = Renderer::tonemapping_output(&self.device, &self.config);
+
+        self.tonemapping
+            .update_input(&self.device, &self.compose_output);
+        self.fxaa
+            .update_input(&self.device, &self.tonemapping_output);
     }
 
     pub fn input(&mut self, event: &WindowEvent) {
@@ -173,23 +538,95 @@ impl Renderer {
 
     pub fn update(&mut self, dt: Duration) {
         self.camera_controller.update(&mut self.camera, dt);
-        self.scene
-            .scene
-            .update(&self.queue, SceneUniformData::new_from_camera(&self.camera));
+        let aspect_ratio = self.config.width as f32 / self.config.height as f32;
+        self.scene.scene.update(
+            &self.queue,
+            SceneUniformData::new_from_camera(
+                &self.camera,
+                aspect_ratio,
+                self.egui_state.environment_intensity,
+            ),
+        );
+        if self.shadows.cascade_count() != self.egui_state.cascades.count {
+            self.shadows
+                .set_cascade_count(&self.device, self.egui_state.cascades.count);
+        }
+        let (sun_direction, sun_color) = match self.sun_light() {
+            Some(i) => {
+                let light = &self.scene.lights[i];
+                (light.direction, light.color * light.shader_intensity())
+            }
+            None => (
+                shadowmap::sun_direction(
+                    self.egui_state.shadow_theta.to_radians(),
+                    self.egui_state.shadow_phi.to_radians(),
+                ),
+                Vec3::splat(self.egui_state.sun_illuminance),
+            ),
+        };
         self.shadows.update_uniform(
             &self.queue,
             ShadowData::new(
-                self.camera.eye,
-                self.egui_state.shadow_dist,
-                self.egui_state.shadow_theta.to_radians(),
-                self.egui_state.shadow_phi.to_radians(),
+                &self.camera,
+                aspect_ratio,
+                sun_direction,
+                sun_color,
+                &self.egui_state.cascades,
             ),
         );
+
+        if self.shadows.point_budget() != self.egui_state.point_shadow_budget {
+            self.shadows
+                .set_point_budget(&self.device, self.egui_state.point_shadow_budget);
+        }
+        self.update_lights();
+        self.ssao.update(
+            &self.queue,
+            self.egui_state.ssao_params,
+            self.egui_state.ssao_seed,
+        );
+        self.gtao
+            .uniform
+            .update(&self.queue, self.egui_state.gtao_params);
         self.fxaa
             .uniform
             .update(&self.queue, self.egui_state.fxaa_params);
     }
 
+    /// The scene's first directional light, which is shaded as the shadowed sun in place of the
+    /// one set in the UI. None if it doesn't have one.
+    fn sun_light(&self) -> Option<usize> {
+        self.scene
+            .lights
+            .iter()
+            .position(|x| x.kind == LightKind::Directional)
+    }
+
+    /// Upload the scene's lights and their point shadow slots.
+    fn update_lights(&mut self) {
+        // The sun stays in the buffer dark rather than being left out, so indices still match
+        // scene.lights (Skybox draws area lights by index)
+        let mut lights = self.scene.lights.clone();
+        if let Some(i) = self.sun_light() {
+            lights[i].intensity = 0.0;
+        }
+
+        let slots = self.shadows.assign_point_slots(&lights);
+        self.scene.lighting.update(
+            &self.queue,
+            &lights,
+            &slots,
+            self.camera.exposure.exposure(),
+            LightingUniformData::new(
+                lights.len(),
+                self.egui_state.light_heatmap,
+                [self.config.width, self.config.height],
+            ),
+        );
+        self.shadows
+            .update_point_lights(&self.queue, &lights, &slots);
+    }
+
     // TODO: seems fragile?
     pub fn reload_shader<T: ReloadableShaders, U: AsRef<Path>>(
         device: &wgpu::Device,
@@ -217,7 +654,13 @@ impl Renderer {
     }
 
     pub fn ui(&mut self, ctx: &egui::Context) {
+        let lights = self.scene.lights.clone();
+        let scene_sun = self.sun_light().is_some();
         egui::Window::new("Renderer").show(ctx, |ui| {
+            ui.label(format!(
+                "Adapter: {} ({:?}, {:?})",
+                self.adapter_info.name, self.adapter_info.backend, self.adapter_info.device_type
+            ));
             self.camera_controller.ui(&mut self.camera, ui);
             // omfg are you fr
             macro_rules! shaders_helper {
@@ -248,6 +691,10 @@ impl Renderer {
             egui::CollapsingHeader::new("Shaders").show(ui, |ui| {
                 egui::Grid::new("shaders").show(ui, |ui| {
                     shaders_helper!(ui, write_gbuffers, WriteGBuffers);
+                    shaders_helper!(ui, light_clusters, LightClusters);
+                    shaders_helper!(ui, write_shadowmaps, WriteShadowmaps);
+                    shaders_helper!(ui, ssao, SSAO);
+                    shaders_helper!(ui, gtao, Gtao);
                     shaders_helper!(ui, compose, Compose);
                     shaders_helper!(ui, skybox, Skybox);
                     shaders_helper!(ui, tonemapping, Tonemapping);
@@ -271,10 +718,13 @@ impl Renderer {
                             &String::from(path.to_str().unwrap()),
                         );
                         match scene {
-                            Ok(_) => self.scene = scene.unwrap(),
-                            Err(_) => {
+                            Ok(_) => {
+                                self.scene = scene.unwrap();
+                                self.egui_state.loader_error_message = String::new();
+                            }
+                            Err(err) => {
                                 self.egui_state.loader_error_message =
-                                    String::from("Failed to load glTF.")
+                                    format!("Failed to load glTF: {}", err)
                             }
                         }
                     }
@@ -285,11 +735,8 @@ impl Renderer {
                         .add_filter("dds", &["dds"])
                         .pick_file()
                     {
-                        self.skybox.update_cubemap(
-                            &self.device,
-                            &self.queue,
-                            &String::from(path.to_str().unwrap()),
-                        );
+                        let cubemap = Cubemap::from_dds(&self.device, &self.queue, path);
+                        self.skybox.update_cubemap(&self.device, cubemap);
                     }
                 }
 
@@ -298,12 +745,10 @@ impl Renderer {
                         .add_filter("dds", &["dds"])
                         .pick_file()
                     {
-                        self.compose.ibl.update(
-                            &self.device,
-                            &self.queue,
-                            Some(&String::from(path.to_str().unwrap())),
-                            None,
-                        );
+                        let irradiance = Cubemap::from_dds(&self.device, &self.queue, path);
+                        self.compose
+                            .ibl
+                            .update(&self.device, Some(irradiance), None, None);
                     }
                 }
 
@@ -312,34 +757,229 @@ impl Renderer {
                         .add_filter("dds", &["dds"])
                         .pick_file()
                     {
-                        self.compose.ibl.update(
-                            &self.device,
-                            &self.queue,
-                            None,
-                            Some(&String::from(path.to_str().unwrap())),
-                        );
+                        let prefilter = Cubemap::from_dds(&self.device, &self.queue, path);
+                        self.compose
+                            .ibl
+                            .update(&self.device, None, Some(prefilter), None);
                     }
                 }
+
+                if ui.button("Load environment (equirect)").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("HDR image", &["hdr", "exr"])
+                        .pick_file()
+                    {
+                        self.egui_state.loader_error_message = match self.load_environment(path) {
+                            Ok(_) => String::new(),
+                            Err(err) => format!("Failed to load environment: {}", err),
+                        }
+                    }
+                }
+
+                ui.label(
+                    egui::RichText::new(&self.egui_state.loader_error_message).color(Color32::RED),
+                );
+            });
+
+            egui::CollapsingHeader::new("Exposure").show(ui, |ui| {
+                let exposure = &mut self.camera.exposure;
+                ui.add(
+                    egui::Slider::new(&mut exposure.aperture, 1.0..=32.0)
+                        .logarithmic(true)
+                        .text("Aperture (f-number)"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut exposure.shutter_speed, 1.0 / 8000.0..=1.0)
+                        .logarithmic(true)
+                        .text("Shutter speed (s)"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut exposure.iso, 50.0..=6400.0)
+                        .logarithmic(true)
+                        .text("ISO"),
+                );
+                ui.add(
+                    egui::Slider::new(&mut exposure.compensation, -5.0..=5.0)
+                        .step_by(1.0 / 3.0)
+                        .text("Compensation (stops)"),
+                );
+                ui.label(format!("EV100: {:.2}", exposure.ev100()));
+
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.environment_intensity, 0.0..=100_000.0)
+                        .logarithmic(true)
+                        .text("Environment luminance (nits)"),
+                );
             });
 
             egui::CollapsingHeader::new("Shadows").show(ui, |ui| {
+                if scene_sun {
+                    ui.label("Sun: the scene's first directional light (see Lights)");
+                } else {
+                    ui.add(
+                        egui::Slider::new(&mut self.egui_state.shadow_theta, 0.0..=360.0)
+                            .text("Theta")
+                            .show_value(true),
+                    );
+
+                    ui.add(
+                        egui::Slider::new(&mut self.egui_state.shadow_phi, 0.0..=360.0)
+                            .text("Phi")
+                            .show_value(true),
+                    );
+
+                    ui.add(
+                        egui::Slider::new(&mut self.egui_state.sun_illuminance, 0.0..=150_000.0)
+                            .logarithmic(true)
+                            .text("Illuminance (lux)")
+                            .show_value(true),
+                    );
+                }
+
                 ui.add(
-                    egui::Slider::new(&mut self.egui_state.shadow_theta, 0.0..=360.0)
-                        .text("Theta")
+                    egui::Slider::new(
+                        &mut self.egui_state.cascades.max_distance,
+                        0.0..=Camera::FAR,
+                    )
+                    .text("Distance")
+                    .show_value(true),
+                );
+
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.cascades.count, 2..=MAX_CASCADES as u32)
+                        .text("Cascades")
                         .show_value(true),
                 );
 
                 ui.add(
-                    egui::Slider::new(&mut self.egui_state.shadow_phi, 0.0..=360.0)
-                        .text("Phi")
+                    egui::Slider::new(&mut self.egui_state.cascades.split_lambda, 0.0..=1.0)
+                        .text("Split lambda")
+                        .show_value(true),
+                );
+
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.cascades.blend_width, 0.0..=0.5)
+                        .text("Cascade blend")
+                        .show_value(true),
+                );
+
+                ui.checkbox(&mut self.egui_state.cascades.debug, "Show cascades");
+
+                ui.add(
+                    egui::Slider::new(
+                        &mut self.egui_state.point_shadow_budget,
+                        0..=MAX_POINT_SHADOWS,
+                    )
+                    .text("Point shadow budget")
+                    .show_value(true),
+                );
+            });
+
+            egui::CollapsingHeader::new("Ambient occlusion").show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    let mode = &mut self.egui_state.ambient_occlusion;
+                    ui.radio_value(mode, AmbientOcclusion::Off, "Off");
+                    ui.radio_value(mode, AmbientOcclusion::Ssao, "SSAO");
+                    ui.radio_value(mode, AmbientOcclusion::Gtao, "GTAO");
+                });
+
+                ui.label(egui::RichText::new("SSAO").strong());
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.ssao_params.radius, 0.01..=2.0)
+                        .text("Radius")
+                        .show_value(true),
+                );
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.ssao_params.bias, 0.0..=0.1)
+                        .text("Bias")
+                        .show_value(true),
+                );
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.ssao_params.intensity, 0.0..=4.0)
+                        .text("Intensity")
                         .show_value(true),
                 );
+                ui.add(
+                    egui::Slider::new(
+                        &mut self.egui_state.ssao_params.sample_count,
+                        1..=MAX_SSAO_SAMPLES,
+                    )
+                    .text("Samples")
+                    .show_value(true),
+                );
+                ui.horizontal(|ui| {
+                    ui.label("Seed");
+                    ui.add(egui::DragValue::new(&mut self.egui_state.ssao_seed));
+                });
 
+                ui.label(egui::RichText::new("GTAO").strong());
                 ui.add(
-                    egui::Slider::new(&mut self.egui_state.shadow_dist, 0.0..=360.0)
-                        .text("Distance")
+                    egui::Slider::new(&mut self.egui_state.gtao_params.radius, 0.01..=2.0)
+                        .text("Radius")
                         .show_value(true),
                 );
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.gtao_params.intensity, 0.0..=4.0)
+                        .text("Intensity")
+                        .show_value(true),
+                );
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.gtao_params.slice_count, 1..=8)
+                        .text("Slices")
+                        .show_value(true),
+                );
+                ui.add(
+                    egui::Slider::new(&mut self.egui_state.gtao_params.step_count, 1..=16)
+                        .text("Steps")
+                        .show_value(true),
+                );
+                let mut specular_occlusion = self.egui_state.gtao_params.specular_occlusion != 0;
+                ui.checkbox(&mut specular_occlusion, "Specular occlusion");
+                self.egui_state.gtao_params.specular_occlusion = specular_occlusion as u32;
+            });
+
+            egui::CollapsingHeader::new("Transparency").show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    let mode = &mut self.egui_state.transparency;
+                    ui.radio_value(mode, Transparency::Sorted, "Sorted");
+                    ui.radio_value(mode, Transparency::WeightedBlended, "Weighted blended OIT");
+                });
+            });
+
+            egui::CollapsingHeader::new("Lights").show(ui, |ui| {
+                ui.label(format!(
+                    "{} lights (up to {} shaded)",
+                    self.scene.lights.len(),
+                    MAX_LIGHTS
+                ));
+                ui.checkbox(&mut self.egui_state.light_heatmap, "Cluster heatmap");
+
+                ui.horizontal_wrapped(|ui| {
+                    for kind in LIGHT_KINDS {
+                        if ui
+                            .button(format!("Add {}", kind.name().to_lowercase()))
+                            .clicked()
+                        {
+                            self.scene.lights.push(new_light(kind, &self.camera));
+                        }
+                    }
+                });
+
+                let exposure = self.camera.exposure.exposure();
+                let mut removed = None;
+                for (i, light) in self.scene.lights.iter_mut().enumerate() {
+                    egui::CollapsingHeader::new(format!("{}: {}", i, light.kind.name()))
+                        .id_source(("light", i))
+                        .show(ui, |ui| {
+                            light_ui(ui, light, exposure);
+                            if ui.button("Remove").clicked() {
+                                removed = Some(i);
+                            }
+                        });
+                }
+                if let Some(i) = removed {
+                    self.scene.lights.remove(i);
+                }
             });
 
             egui::CollapsingHeader::new("FXAA").show(ui, |ui| {
@@ -367,47 +1007,87 @@ impl Renderer {
                 );
             })
         });
-    }
 
-    pub fn render(
-        &mut self,
-        egui_textures_delta: &TexturesDelta,
-        egui_clipped_primitves: &Vec<ClippedPrimitive>,
-        egui_screen_descriptor: &ScreenDescriptor,
-    ) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Command encoder"),
-            });
+        // Don't wait for the next update, so the frame being rendered already has the changes
+        if self.scene.lights != lights {
+            self.update_lights();
+        }
+    }
 
+    /// Record the scene passes (everything but UI) into encoder, ending in output.
+    fn encode_passes(&self, output: &TextureView, encoder: &mut wgpu::CommandEncoder) {
         self.write_gbuffers
-            .pass(&self.scene, &self.gbuffers, &mut encoder);
+            .pass(&self.scene, &self.gbuffers, encoder);
+        match self.egui_state.ambient_occlusion {
+            AmbientOcclusion::Off => self.gbuffers.clear_occlusion(encoder),
+            AmbientOcclusion::Ssao => self.ssao.pass(&self.scene, &self.gbuffers, encoder),
+            AmbientOcclusion::Gtao => self.gtao.pass(&self.scene, &self.gbuffers, encoder),
+        }
+        self.write_shadowmaps
+            .pass(&self.scene, &self.shadows, encoder);
+        self.light_clusters.pass(&self.scene, encoder);
         self.compose.pass(
             &self.scene,
             &self.gbuffers,
+            &self.shadows,
             &self.compose_output.view,
-            &mut encoder,
+            encoder,
         );
         self.skybox.pass(
             &self.scene,
             &self.compose_output.view,
             &self.gbuffers.depth.view,
-            &mut encoder,
+            encoder,
         );
+        match self.egui_state.transparency {
+            Transparency::Sorted => self.compose.forward_pass(
+                &self.scene,
+                &self.shadows,
+                self.camera.eye,
+                &self.compose_output.view,
+                &self.gbuffers.depth.view,
+                encoder,
+            ),
+            Transparency::WeightedBlended => self.compose.oit_pass(
+                &self.scene,
+                &self.shadows,
+                &self.compose_output.view,
+                &self.gbuffers.depth.view,
+                encoder,
+            ),
+        }
 
         if self.egui_state.fxaa_enabled {
             self.tonemapping
-                .pass(&self.tonemapping_output.view, &mut encoder);
-            self.fxaa.pass(&view, &mut encoder);
+                .pass(&self.tonemapping_output.view, encoder);
+            self.fxaa.pass(output, encoder);
         } else {
-            self.tonemapping.pass(&view, &mut encoder);
+            self.tonemapping.pass(output, encoder);
         }
+    }
+
+    pub fn render(
+        &mut self,
+        egui_textures_delta: &TexturesDelta,
+        egui_clipped_primitves: &Vec<ClippedPrimitive>,
+        egui_screen_descriptor: &ScreenDescriptor,
+    ) -> Result<(), wgpu::SurfaceError> {
+        let output = self
+            .surface
+            .as_ref()
+            .expect("render() needs a surface, use render_to_file() when headless")
+            .get_current_texture()?;
+        let view = output
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Command encoder"),
+            });
+
+        self.encode_passes(&view, &mut encoder);
 
         // TODO: put into its own pass/make nicer
         for delta in &egui_textures_delta.set {
@@ -452,4 +1132,168 @@ impl Renderer {
         output.present();
         Ok(())
     }
+
+    /// Render one frame offscreen and write it to path. An .exr path gets the HDR compose
+    /// output (before tonemapping), anything else gets the final tonemapped (and FXAA'd) frame.
+    pub fn render_to_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), CaptureError> {
+        let output = Texture::new(
+            &self.device,
+            self.config.width,
+            self.config.height,
+            self.config.format,
+            TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            Some("Headless output"),
+            false,
+        );
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Command encoder"),
+            });
+        self.encode_passes(&output.view, &mut encoder);
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let hdr = path
+            .as_ref()
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("exr"));
+        let texture = if hdr { &self.compose_output } else { &output };
+        capture::save_texture(&self.device, &self.queue, texture, path)
+    }
+}
+
+/// A light of the given kind two meters in front of the camera. Area lights face the camera,
+/// the others shine the way it looks.
+fn new_light(kind: LightKind, camera: &Camera) -> Light {
+    let position = camera.eye + camera.front() * 2.0;
+    match kind {
+        LightKind::Rect { .. } | LightKind::Disk { .. } => {
+            Light::area(kind, position, -camera.front(), Vec3::ONE, NEW_LIGHT_POWER)
+        }
+        LightKind::Directional => Light {
+            kind,
+            direction: camera.front(),
+            intensity: NEW_LIGHT_ILLUMINANCE,
+            casts_shadows: false,
+            ..Light::point(position, Vec3::ONE, NEW_LIGHT_POWER)
+        },
+        _ => Light {
+            kind,
+            direction: camera.front(),
+            ..Light::point(position, Vec3::ONE, NEW_LIGHT_POWER)
+        },
+    }
+}
+
+/// Editor for one light. exposure is the camera's, to start the range slider at the range the
+/// light had without one.
+fn light_ui(ui: &mut egui::Ui, light: &mut Light, exposure: f32) {
+    let directional = light.kind == LightKind::Directional;
+
+    egui::ComboBox::from_label("Type")
+        .selected_text(light.kind.name())
+        .show_ui(ui, |ui| {
+            for kind in LIGHT_KINDS {
+                let selected = std::mem::discriminant(&kind) == std::mem::discriminant(&light.kind);
+                if ui.selectable_label(selected, kind.name()).clicked() && !selected {
+                    // Power and illuminance don't convert, start over when switching between them
+                    if directional != (kind == LightKind::Directional) {
+                        light.intensity = if directional {
+                            NEW_LIGHT_POWER
+                        } else {
+                            NEW_LIGHT_ILLUMINANCE
+                        };
+                        light.range = None;
+                    }
+                    light.casts_shadows = matches!(kind, LightKind::Point | LightKind::Spot { .. });
+                    light.kind = kind;
+                }
+            }
+        });
+
+    match &mut light.kind {
+        LightKind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } => {
+            ui.horizontal(|ui| {
+                ui.label("Outer cone");
+                ui.drag_angle(outer_cone_angle);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Inner cone");
+                ui.drag_angle(inner_cone_angle);
+            });
+            *outer_cone_angle = outer_cone_angle.clamp(0.0, FRAC_PI_2);
+            *inner_cone_angle = inner_cone_angle.clamp(0.0, *outer_cone_angle);
+        }
+        LightKind::Rect {
+            width,
+            height,
+            two_sided,
+        } => {
+            ui.add(egui::Slider::new(width, 0.01..=10.0).text("Width"));
+            ui.add(egui::Slider::new(height, 0.01..=10.0).text("Height"));
+            ui.checkbox(two_sided, "Two sided");
+        }
+        LightKind::Disk { radius, two_sided } => {
+            ui.add(egui::Slider::new(radius, 0.01..=5.0).text("Radius"));
+            ui.checkbox(two_sided, "Two sided");
+        }
+        LightKind::Point | LightKind::Directional => {}
+    }
+
+    // The kind may have just changed
+    let directional = light.kind == LightKind::Directional;
+    if !directional {
+        drag_vec3(ui, "Position", &mut light.position);
+    }
+    if light.kind != LightKind::Point {
+        drag_vec3(ui, "Direction", &mut light.direction);
+    }
+    ui.horizontal(|ui| {
+        ui.label("Color");
+        ui.color_edit_button_rgb(light.color.as_mut());
+    });
+
+    if directional {
+        ui.add(
+            egui::Slider::new(&mut light.intensity, 0.0..=150_000.0)
+                .logarithmic(true)
+                .text("Illuminance (lux)"),
+        );
+    } else {
+        ui.add(
+            egui::Slider::new(&mut light.intensity, 0.0..=10_000_000.0)
+                .logarithmic(true)
+                .text("Power (lm)"),
+        );
+
+        let mut limited = light.range.is_some();
+        if ui.checkbox(&mut limited, "Limit range").changed() {
+            light.range = limited.then(|| light.cutoff_radius(exposure));
+        }
+        if let Some(range) = &mut light.range {
+            ui.add(
+                egui::Slider::new(range, 0.01..=Camera::FAR)
+                    .logarithmic(true)
+                    .text("Range (m)"),
+            );
+        }
+    }
+
+    if matches!(light.kind, LightKind::Point | LightKind::Spot { .. }) {
+        ui.checkbox(&mut light.casts_shadows, "Casts shadows");
+    }
+}
+
+/// Label and x/y/z drag values for a vector.
+fn drag_vec3(ui: &mut egui::Ui, label: &str, value: &mut Vec3) {
+    ui.horizontal(|ui| {
+        ui.label(label);
+        ui.add(egui::DragValue::new(&mut value.x).speed(0.05).prefix("x "));
+        ui.add(egui::DragValue::new(&mut value.y).speed(0.05).prefix("y "));
+        ui.add(egui::DragValue::new(&mut value.z).speed(0.05).prefix("z "));
+    });
 }
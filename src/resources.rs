@@ -1,10 +1,10 @@
-use std::num::NonZeroU64;
+use std::{f32::consts::PI, num::NonZeroU64, ops::Range, rc::Rc};
 
-use glam::{vec3, vec4, Mat4, Vec3, Vec4};
+use glam::{vec4, Mat3, Mat4, Vec3, Vec4};
 use wgpu::util::DeviceExt;
 
 use crate::{
-    camera::Camera,
+    camera::{Camera, Exposure},
     texture::{Sampler, Texture},
 };
 
@@ -23,6 +23,12 @@ pub struct SceneUniformData {
     pub view: Mat4,
     pub inverse_perspective_view: Mat4,
     pub camera_position: Vec4,
+    /// Scale from luminance (cd/m²) to the [0, 1] range tonemapping expects, see
+    /// camera::Exposure
+    pub exposure: f32,
+    /// Luminance (cd/m²) of a texel of value 1 in the skybox and IBL maps
+    pub environment_intensity: f32,
+    pub padding: [f32; 2],
 }
 bytemuck_impl!(SceneUniformData);
 
@@ -33,11 +39,14 @@ impl SceneUniformData {
             view: Mat4::IDENTITY,
             inverse_perspective_view: Mat4::IDENTITY.inverse(),
             camera_position: vec4(0.0, 0.0, 0.0, 1.0),
+            exposure: 1.0,
+            environment_intensity: 1.0,
+            padding: [0.0; 2],
         }
     }
 
-    pub fn new_from_camera(camera: &Camera) -> Self {
-        let perspective = Mat4::perspective_lh(45.0_f32.to_radians(), 1600.0 / 900.0, 0.01, 100.0);
+    pub fn new_from_camera(camera: &Camera, aspect_ratio: f32, environment_intensity: f32) -> Self {
+        let perspective = camera.build_projection(aspect_ratio);
         let (view, camera_position) = camera.build_uniforms();
 
         let inverse_perspective_view = (perspective * view).inverse();
@@ -46,70 +55,41 @@ impl SceneUniformData {
             view,
             inverse_perspective_view,
             camera_position,
-        }
-    }
-
-    pub fn shadow(orthographic_projection_size: Vec3, lighting_direction: Vec3) -> Self {
-        // to make the multiplication read easier
-        let ops = orthographic_projection_size;
-        let perspective_view = Mat4::orthographic_lh(-ops.x, ops.x, -ops.y, ops.y, -ops.z, ops.z)
-            * Mat4::look_to_lh(vec3(0.0, 0.0, 0.0), lighting_direction, vec3(0.0, 1.0, 0.0));
-
-        // FIXME: super broken
-        Self {
-            perspective: perspective_view,
-            view: perspective_view,
-            inverse_perspective_view: perspective_view.inverse(),
-            camera_position: vec4(0.0, 0.0, 0.0, 1.0),
+            exposure: camera.exposure.exposure(),
+            environment_intensity,
+            padding: [0.0; 2],
         }
     }
 }
 
-/// SceneUniform has two SceneUniformData's.
-/// SceneUniformData at slot 0 is for scene pass (rendering to camera),
-/// SceneUniformData at slot 1 is for shadow pass (rendering to shadowmap)
+/// SceneUniform holds the SceneUniformData for rendering to the camera. Shadow passes have
+/// their own uniform (see shadowmap.rs).
 pub struct SceneUniform {
     pub uniform_buffer_0: wgpu::Buffer,
-    pub uniform_buffer_1: wgpu::Buffer,
     pub uniform_bind_group: wgpu::BindGroup,
 }
 
 impl SceneUniform {
-    pub fn new(device: &wgpu::Device, zero: SceneUniformData, one: SceneUniformData) -> Self {
+    pub fn new(device: &wgpu::Device, zero: SceneUniformData) -> Self {
         let uniform_buffer_0 = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Scene uniform buffer - scene pass uniform"),
             contents: bytemuck::cast_slice(&[zero]),
             usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
         });
 
-        let uniform_buffer_1 = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Scene uniform buffer - shadow pass uniform"),
-            contents: bytemuck::cast_slice(&[one]),
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
-        });
-
         let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Scene uniform bind group"),
             layout: &SceneUniform::bind_group_layout(device),
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(
-                        uniform_buffer_0.as_entire_buffer_binding(),
-                    ),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Buffer(
-                        uniform_buffer_1.as_entire_buffer_binding(),
-                    ),
-                },
-            ],
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(
+                    uniform_buffer_0.as_entire_buffer_binding(),
+                ),
+            }],
         });
 
         SceneUniform {
             uniform_buffer_0,
-            uniform_buffer_1,
             uniform_bind_group,
         }
     }
@@ -121,97 +101,424 @@ impl SceneUniform {
     pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
         device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Scene uniform bind group layout"),
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::all(),
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: NonZeroU64::new(
-                            std::mem::size_of::<SceneUniformData>() as u64
-                        ),
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::all(),
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: NonZeroU64::new(
-                            std::mem::size_of::<SceneUniformData>() as u64
-                        ),
-                    },
-                    count: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::all(),
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: NonZeroU64::new(
+                        std::mem::size_of::<SceneUniformData>() as u64
+                    ),
                 },
-            ],
+                count: None,
+            }],
         })
     }
 }
 
+/// The light types of KHR_lights_punctual, plus rectangle and disk area lights. Cone angles are
+/// in radians from the spot direction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    Point,
+    Spot {
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+    Directional,
+    /// Lambertian emitter facing the light's direction (both ways if two_sided), oriented like a
+    /// camera looking down it with width horizontal
+    Rect {
+        width: f32,
+        height: f32,
+        two_sided: bool,
+    },
+    /// Same as Rect, but a disk
+    Disk {
+        radius: f32,
+        two_sided: bool,
+    },
+}
+
+impl LightKind {
+    /// Value of the type in the lighting uniform, matches the LIGHT_ constants in compose.wgsl
+    fn index(&self) -> f32 {
+        match self {
+            LightKind::Point => 0.0,
+            LightKind::Spot { .. } => 1.0,
+            LightKind::Directional => 2.0,
+            LightKind::Rect { .. } => 3.0,
+            LightKind::Disk { .. } => 4.0,
+        }
+    }
+
+    /// Name of the type for the UI
+    pub fn name(&self) -> &'static str {
+        match self {
+            LightKind::Point => "Point",
+            LightKind::Spot { .. } => "Spot",
+            LightKind::Directional => "Directional",
+            LightKind::Rect { .. } => "Rect",
+            LightKind::Disk { .. } => "Disk",
+        }
+    }
+
+    /// Area of one side of an area light, 0 for other lights
+    pub fn area(&self) -> f32 {
+        match self {
+            LightKind::Rect { width, height, .. } => width * height,
+            LightKind::Disk { radius, .. } => PI * radius * radius,
+            _ => 0.0,
+        }
+    }
+
+    /// What a light's luminous power is divided by for the shaders: the solid angle point and
+    /// spot lights spread it over, π times the emitting area for area lights (giving luminance),
+    /// 1 for directional lights since their intensity is already illuminance
+    fn intensity_scale(&self) -> f32 {
+        match self {
+            LightKind::Point => 4.0 * PI,
+            LightKind::Spot {
+                outer_cone_angle, ..
+            } => (2.0 * PI * (1.0 - outer_cone_angle.cos())).max(1e-6),
+            LightKind::Directional => 1.0,
+            LightKind::Rect { two_sided, .. } | LightKind::Disk { two_sided, .. } => {
+                let sides = if *two_sided { 2.0 } else { 1.0 };
+                (PI * self.area() * sides).max(1e-6)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    /// Unused for directional lights
+    pub position: Vec3,
+    /// Direction the light shines in, unused for point lights
+    pub direction: Vec3,
+    pub color: Vec3,
+    /// Luminous power in lumens for point, spot and area lights, illuminance in lux for
+    /// directional lights
+    pub intensity: f32,
+    /// Distance at which the light has faded out, None to derive it from the intensity
+    pub range: Option<f32>,
+    /// Whether the light gets a cube shadowmap (if there's room for it in the budget), directional
+    /// and area lights never do
+    pub casts_shadows: bool,
+}
+
+impl Light {
+    /// A point light giving off luminous_power lumens
+    pub fn point(position: Vec3, color: Vec3, luminous_power: f32) -> Self {
+        Self {
+            kind: LightKind::Point,
+            position,
+            direction: Vec3::NEG_Z,
+            color,
+            intensity: luminous_power,
+            range: None,
+            casts_shadows: true,
+        }
+    }
+
+    /// An area light (kind is Rect or Disk) giving off luminous_power lumens
+    pub fn area(
+        kind: LightKind,
+        position: Vec3,
+        direction: Vec3,
+        color: Vec3,
+        luminous_power: f32,
+    ) -> Self {
+        Self {
+            kind,
+            position,
+            direction,
+            color,
+            intensity: luminous_power,
+            range: None,
+            casts_shadows: false,
+        }
+    }
+
+    /// Intensity in the units the shaders work with: candela for point and spot lights, lux for
+    /// directional lights and luminance (nits) for area lights. Spot lights keep their power as
+    /// the cone narrows, so they get brighter, and area lights as they shrink.
+    pub fn shader_intensity(&self) -> f32 {
+        self.intensity / self.kind.intensity_scale()
+    }
+
+    /// Set the intensity from candela (lux for directional lights), as KHR_lights_punctual
+    /// gives it
+    pub fn set_candela(&mut self, candela: f32) {
+        self.intensity = candela * self.kind.intensity_scale();
+    }
+
+    /// Half extents of an area light along its width and height, zero for other lights.
+    /// cross(x, y) points against the light's direction, the winding compose.wgsl expects.
+    pub fn area_extents(&self) -> (Vec3, Vec3) {
+        let (half_width, half_height) = match self.kind {
+            LightKind::Rect { width, height, .. } => (width / 2.0, height / 2.0),
+            LightKind::Disk { radius, .. } => (radius, radius),
+            _ => return (Vec3::ZERO, Vec3::ZERO),
+        };
+
+        let direction = self.direction.normalize_or_zero();
+        let up = if direction.y.abs() > 0.999 {
+            Vec3::Z
+        } else {
+            Vec3::Y
+        };
+        let x = up.cross(direction).normalize_or_zero();
+        let y = x.cross(direction);
+        (x * half_width, y * half_height)
+    }
+
+    /// Distance past which the light stops visibly lighting things at this exposure (see
+    /// LightData::new), what it's windowed to without a range. Infinite for directional lights.
+    pub fn cutoff_radius(&self, exposure: f32) -> f32 {
+        let intensity = self.color.max_element() * self.shader_intensity();
+        match self.kind {
+            LightKind::Directional => f32::INFINITY,
+            // Where 1/d² falloff drops below the cutoff. Area lights fall off like a point light
+            // of their peak intensity (luminance times area) placed at their edge.
+            LightKind::Rect { .. } | LightKind::Disk { .. } => {
+                let (extent_x, extent_y) = self.area_extents();
+                let peak_intensity = intensity * self.kind.area();
+                (peak_intensity * exposure / LIGHT_CUTOFF).sqrt() + (extent_x + extent_y).length()
+            }
+            _ => (intensity * exposure / LIGHT_CUTOFF).sqrt(),
+        }
+    }
+}
+
+/// Most lights the lighting buffer holds, lights past this are ignored
+pub const MAX_LIGHTS: usize = 1024;
+/// Number of view space clusters (froxels) lights are binned into: screen tiles in x and y,
+/// exponential depth slices in z. Matches the CLUSTERS_ constants in compose.wgsl and
+/// light_clusters.wgsl.
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+/// Most lights shaded in one cluster, matches MAX_LIGHTS_PER_CLUSTER in the shaders
+pub const MAX_LIGHTS_PER_CLUSTER: u32 = 128;
+/// Exposed illuminance at which lights without a range are cut off, about one step of an 8 bit
+/// display
+const LIGHT_CUTOFF: f32 = 1.0 / 256.0;
+
+/// One light in the lighting storage buffer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct LightData {
+    /// rgb is color * Light::shader_intensity
+    pub color: Vec4,
+    /// w is the index of the light's cube shadowmap, or -1 if it doesn't have one
+    pub position: Vec4,
+    /// w is the radius of the light's bounding sphere for binning, -1 for directional lights
+    pub direction: Vec4,
+    /// x = type (0 point, 1 spot, 2 directional, 3 rect, 4 disk), y = radius the light is
+    /// windowed to, z/w = spot cone scale and offset, so the falloff is
+    /// saturate(cos(angle) * z + w). For area lights z is 1 if they're two sided.
+    pub parameters: Vec4,
+    /// xyz is an area light's half extent along its width (see Light::area_extents), w unused
+    pub extent_x: Vec4,
+    /// xyz is an area light's half extent along its height, w unused
+    pub extent_y: Vec4,
+}
+bytemuck_impl!(LightData);
+
+impl LightData {
+    /// exposure is SceneUniformData::exposure, lights without a range reach as far as they
+    /// visibly light things at that exposure.
+    pub fn new(light: &Light, shadow_slot: Option<u32>, exposure: f32) -> Self {
+        // From the KHR_lights_punctual spec's recommended spot falloff
+        let (cone_scale, cone_offset) = match light.kind {
+            LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => {
+                let scale = 1.0 / (inner_cone_angle.cos() - outer_cone_angle.cos()).max(0.001);
+                (scale, -outer_cone_angle.cos() * scale)
+            }
+            LightKind::Rect { two_sided, .. } | LightKind::Disk { two_sided, .. } => {
+                (two_sided as u32 as f32, 0.0)
+            }
+            _ => (0.0, 1.0),
+        };
+
+        let color = light.color * light.shader_intensity();
+        let (extent_x, extent_y) = light.area_extents();
+        let radius = match (light.kind, light.range) {
+            (LightKind::Directional, _) => -1.0,
+            (_, Some(range)) => range,
+            (_, None) => light.cutoff_radius(exposure),
+        };
+
+        Self {
+            color: (color, 1.0).into(),
+            position: (light.position, shadow_slot.map_or(-1.0, |x| x as f32)).into(),
+            direction: (light.direction.normalize_or_zero(), radius).into(),
+            parameters: vec4(light.kind.index(), radius.max(0.0), cone_scale, cone_offset),
+            extent_x: (extent_x, 0.0).into(),
+            extent_y: (extent_y, 0.0).into(),
+        }
+    }
+}
+
 #[repr(C, align(16))]
 #[derive(Clone, Copy, Debug)]
 pub struct LightingUniformData {
     pub count: u32,
-    pub colors: [Vec4; 16],
-    pub positions: [Vec4; 16],
+    /// Show how many lights each cluster has instead of shading (1) or not (0)
+    pub heatmap: u32,
+    /// Size of the screen in pixels, for finding a pixel's cluster
+    pub screen_size: [u32; 2],
+    /// View depth range the cluster slices span
+    pub near: f32,
+    pub far: f32,
 }
 bytemuck_impl!(LightingUniformData);
 
 impl LightingUniformData {
-    // 0th element is position, 1st element is color
-    pub fn new(lighting: Vec<(Vec3, Vec3)>) -> Self {
-        let count = lighting.len().min(16) as u32;
-        let mut colors: [Vec4; 16] = [vec4(0.0, 0.0, 0.0, 0.0); 16];
-        let mut positions: [Vec4; 16] = [vec4(0.0, 0.0, 0.0, 0.0); 16];
-
-        for i in 0..(count as usize) {
-            positions[i] = (lighting[i].0, 1.0).into();
-            colors[i] = (lighting[i].1, 1.0).into();
-        }
-
+    pub fn new(count: usize, heatmap: bool, screen_size: [u32; 2]) -> Self {
         Self {
-            colors,
-            positions,
-            count,
+            count: count.min(MAX_LIGHTS) as u32,
+            heatmap: heatmap as u32,
+            screen_size,
+            near: Camera::NEAR,
+            far: Camera::FAR,
         }
     }
 }
 
+/// Every light in the scene (in a storage buffer) and the clusters LightClusters bins them into.
+/// Compose reads the clusters to only shade lights near each pixel. The cluster buffers (the
+/// number of lights in each cluster, then MAX_LIGHTS_PER_CLUSTER light indices per cluster) are
+/// only ever used on the GPU, so they're only held by the bind groups.
 pub struct LightingUniform {
     pub uniform_buffer: wgpu::Buffer,
+    pub light_buffer: wgpu::Buffer,
+    /// Read only, for shading
     pub uniform_bind_group: wgpu::BindGroup,
+    /// Clusters writable, for LightClusters
+    pub cluster_bind_group: wgpu::BindGroup,
 }
 
 impl LightingUniform {
-    pub fn new(device: &wgpu::Device, data: LightingUniformData) -> Self {
+    pub fn new(device: &wgpu::Device, lights: &[Light]) -> Self {
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Lighting uniform buffer"),
-            contents: bytemuck::cast_slice(&[data]),
+            contents: bytemuck::cast_slice(&[LightingUniformData::new(
+                lights.len(),
+                false,
+                [1, 1],
+            )]),
             usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
         });
 
+        let mut data = lights
+            .iter()
+            .take(MAX_LIGHTS)
+            .map(|x| LightData::new(x, None, Exposure::default().exposure()))
+            .collect::<Vec<_>>();
+        data.resize(MAX_LIGHTS, bytemuck::Zeroable::zeroed());
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Lighting light buffer"),
+            contents: bytemuck::cast_slice(data.as_slice()),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        });
+
+        let cluster_count = (CLUSTER_GRID[0] * CLUSTER_GRID[1] * CLUSTER_GRID[2]) as u64;
+        let cluster_counts = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lighting cluster counts"),
+            size: cluster_count * 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let cluster_lights = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lighting cluster lights"),
+            size: cluster_count * MAX_LIGHTS_PER_CLUSTER as u64 * 4,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let entries = [
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: light_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: cluster_counts.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: cluster_lights.as_entire_binding(),
+            },
+        ];
         let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Lighting uniform bind group"),
             layout: &LightingUniform::bind_group_layout(device),
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.as_entire_binding(),
-            }],
+            entries: &entries,
+        });
+        let cluster_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Lighting cluster bind group"),
+            layout: &LightingUniform::cluster_bind_group_layout(device),
+            entries: &entries,
         });
 
         LightingUniform {
             uniform_buffer,
+            light_buffer,
             uniform_bind_group,
+            cluster_bind_group,
         }
     }
 
-    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Lighting uniform bind group layout"),
-            entries: &[wgpu::BindGroupLayoutEntry {
+    /// shadow_slots[i] is the cube shadowmap of lights[i], lights past the end of shadow_slots
+    /// don't have one. exposure is the camera's, see LightData::new.
+    pub fn update(
+        &self,
+        queue: &wgpu::Queue,
+        lights: &[Light],
+        shadow_slots: &[Option<u32>],
+        exposure: f32,
+        data: LightingUniformData,
+    ) {
+        let lights = lights
+            .iter()
+            .take(MAX_LIGHTS)
+            .enumerate()
+            .map(|(i, x)| LightData::new(x, shadow_slots.get(i).copied().flatten(), exposure))
+            .collect::<Vec<_>>();
+        queue.write_buffer(
+            &self.light_buffer,
+            0,
+            bytemuck::cast_slice(lights.as_slice()),
+        );
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[data]));
+    }
+
+    fn layout_entries(
+        stages: wgpu::ShaderStages,
+        clusters_read_only: bool,
+    ) -> [wgpu::BindGroupLayoutEntry; 4] {
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: stages,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+
+        [
+            wgpu::BindGroupLayoutEntry {
                 binding: 0,
                 visibility: wgpu::ShaderStages::all(),
                 ty: wgpu::BindingType::Buffer {
@@ -222,7 +529,25 @@ impl LightingUniform {
                     ),
                 },
                 count: None,
-            }],
+            },
+            storage(1, true),
+            storage(2, clusters_read_only),
+            storage(3, clusters_read_only),
+        ]
+    }
+
+    /// Also visible to vertex shaders, Skybox reads the lights to draw area light emitters.
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Lighting uniform bind group layout"),
+            entries: &LightingUniform::layout_entries(wgpu::ShaderStages::VERTEX_FRAGMENT, true),
+        })
+    }
+
+    pub fn cluster_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Lighting cluster bind group layout"),
+            entries: &LightingUniform::layout_entries(wgpu::ShaderStages::COMPUTE, false),
         })
     }
 }
@@ -233,6 +558,12 @@ pub struct MaterialUniformData {
     pub ambient: Vec4,
     pub diffuse: Vec4,
     pub specular: Vec4,
+    /// x = occlusion texture strength, y = UV set the occlusion texture uses (0 or 1)
+    pub occlusion: Vec4,
+    /// rgb = emissive factor (already multiplied by KHR_materials_emissive_strength)
+    pub emissive: Vec4,
+    /// x = alpha cutoff, y = alpha mode (0 = opaque, 1 = mask, 2 = blend, see AlphaMode)
+    pub alpha: Vec4,
 }
 bytemuck_impl!(MaterialUniformData);
 
@@ -242,28 +573,54 @@ impl Default for MaterialUniformData {
             ambient: Vec4::ONE,
             diffuse: Vec4::ONE,
             specular: Vec4::ONE,
+            occlusion: vec4(1.0, 0.0, 0.0, 0.0),
+            emissive: Vec4::ZERO,
+            alpha: vec4(0.5, 0.0, 0.0, 0.0),
         }
     }
 }
 
+/// How a material's alpha is used. Opaque and Mask go through the gbuffers (Mask discards below
+/// the cutoff), Blend is drawn afterwards in Compose's forward pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlphaMode {
+    Opaque = 0,
+    Mask = 1,
+    Blend = 2,
+}
+
 pub struct Material {
+    pub alpha_mode: AlphaMode,
+    /// Back faces are culled unless this is set, in which case they're drawn with a flipped
+    /// normal
+    pub double_sided: bool,
     pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
-    pub diffuse_texture: Texture,
-    pub diffuse_texture_sampler: Sampler,
-    pub normal_texture: Texture,
-    pub normal_texture_sampler: Sampler,
-    pub metal_roughness_texture: Texture,
-    pub metal_roughness_texture_sampler: Sampler,
+    // The textures and samplers are only read through bind_group, these keep them alive. They're
+    // shared with other materials, samplers between textures with the same glTF sampler settings
+    _diffuse_texture: Rc<Texture>,
+    _diffuse_texture_sampler: Rc<Sampler>,
+    _normal_texture: Rc<Texture>,
+    _normal_texture_sampler: Rc<Sampler>,
+    _metal_roughness_texture: Rc<Texture>,
+    _metal_roughness_texture_sampler: Rc<Sampler>,
+    _occlusion_texture: Rc<Texture>,
+    _occlusion_texture_sampler: Rc<Sampler>,
+    _emissive_texture: Rc<Texture>,
+    _emissive_texture_sampler: Rc<Sampler>,
 }
 
 impl Material {
     pub fn new(
         device: &wgpu::Device,
         data: MaterialUniformData,
-        diffuse_texture: Texture,
-        normal_texture: Texture,
-        metal_roughness_texture: Texture,
+        (diffuse_texture, diffuse_texture_sampler): (Rc<Texture>, Rc<Sampler>),
+        (normal_texture, normal_texture_sampler): (Rc<Texture>, Rc<Sampler>),
+        (metal_roughness_texture, metal_roughness_texture_sampler): (Rc<Texture>, Rc<Sampler>),
+        (occlusion_texture, occlusion_texture_sampler): (Rc<Texture>, Rc<Sampler>),
+        (emissive_texture, emissive_texture_sampler): (Rc<Texture>, Rc<Sampler>),
+        alpha_mode: AlphaMode,
+        double_sided: bool,
     ) -> Self {
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Material uniform buffer"),
@@ -271,10 +628,6 @@ impl Material {
             usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
         });
 
-        let diffuse_texture_sampler = Sampler::diffuse_texture_sampler(&device);
-        let normal_texture_sampler = Sampler::normal_texture_sampler(&device);
-        let metal_roughness_texture_sampler = Sampler::normal_texture_sampler(&device);
-
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Material bind group"),
             layout: &Material::bind_group_layout(device),
@@ -309,18 +662,40 @@ impl Material {
                         &metal_roughness_texture_sampler.sampler,
                     ),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::TextureView(&occlusion_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::Sampler(&occlusion_texture_sampler.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::TextureView(&emissive_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::Sampler(&emissive_texture_sampler.sampler),
+                },
             ],
         });
 
         Self {
+            alpha_mode,
+            double_sided,
             uniform_buffer,
             bind_group,
-            diffuse_texture,
-            diffuse_texture_sampler,
-            normal_texture,
-            normal_texture_sampler,
-            metal_roughness_texture,
-            metal_roughness_texture_sampler,
+            _diffuse_texture: diffuse_texture,
+            _diffuse_texture_sampler: diffuse_texture_sampler,
+            _normal_texture: normal_texture,
+            _normal_texture_sampler: normal_texture_sampler,
+            _metal_roughness_texture: metal_roughness_texture,
+            _metal_roughness_texture_sampler: metal_roughness_texture_sampler,
+            _occlusion_texture: occlusion_texture,
+            _occlusion_texture_sampler: occlusion_texture_sampler,
+            _emissive_texture: emissive_texture,
+            _emissive_texture_sampler: emissive_texture_sampler,
         }
     }
 
@@ -386,86 +761,147 @@ impl Material {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
             ],
         })
     }
 }
 
+/// World transform of one instance of a mesh, read per instance from vertex buffer slot 1.
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
-pub struct MeshUniformData {
+pub struct MeshInstance {
     pub world: Mat4,
+    /// Columns of the inverse transpose of world's upper 3x3 (w unused), which keeps normals
+    /// perpendicular to the surface under non-uniform scale
+    pub normal: [Vec4; 3],
 }
-bytemuck_impl!(MeshUniformData);
+bytemuck_impl!(MeshInstance);
+
+impl MeshInstance {
+    const BUFFER_LAYOUT: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
+        5 => Float32x4, 6 => Float32x4, 7 => Float32x4, 8 => Float32x4,
+        9 => Float32x4, 10 => Float32x4, 11 => Float32x4
+    ];
 
-impl MeshUniformData {
     pub fn new(world: Mat4) -> Self {
-        MeshUniformData {
-            world: world.into(),
+        let linear = Mat3::from_mat4(world);
+        // A node scaled to zero has nothing to light, any normal will do
+        let normal = if linear.determinant() == 0.0 {
+            linear
+        } else {
+            linear.inverse().transpose()
+        };
+        MeshInstance {
+            world,
+            normal: [
+                normal.x_axis.extend(0.0),
+                normal.y_axis.extend(0.0),
+                normal.z_axis.extend(0.0),
+            ],
+        }
+    }
+
+    pub fn buffer_layout() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<MeshInstance>() as u64,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &MeshInstance::BUFFER_LAYOUT,
         }
     }
 }
 
+/// Every mesh instance in the scene, meshes draw a range of these.
+pub struct MeshInstances {
+    /// Kept on the CPU for sorting transparent instances
+    pub data: Vec<MeshInstance>,
+    pub buffer: wgpu::Buffer,
+}
+
+impl MeshInstances {
+    pub fn new(device: &wgpu::Device, data: Vec<MeshInstance>) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Mesh instance buffer"),
+            contents: bytemuck::cast_slice(data.as_slice()),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::VERTEX,
+        });
+
+        Self { data, buffer }
+    }
+}
+
+/// One glTF primitive, uploaded once and drawn for each node that references its mesh.
 pub struct Mesh {
+    /// Local space center of the mesh's bounding box, used to sort transparent instances
+    pub center: Vec3,
     pub vertex_buffer: wgpu::Buffer,
-    pub vertex_count: u32,
     pub index_buffer: wgpu::Buffer,
     pub index_count: u32,
-    pub uniform_buffer: wgpu::Buffer,
-    pub bind_group: wgpu::BindGroup,
     pub material_index: usize,
+    /// Range of Scene::instances to draw
+    pub instances: Range<u32>,
+    /// Start of the instances whose transform mirrors the mesh, which wind the other way
+    pub first_mirrored: u32,
 }
 
 impl Mesh {
     pub fn new(
-        device: &wgpu::Device,
         vertex_buffer: wgpu::Buffer,
         index_buffer: wgpu::Buffer,
-        vertex_count: u32,
         index_count: u32,
-        data: MeshUniformData,
         material_index: usize,
-        name: Option<&str>,
+        center: Vec3,
+        instances: Range<u32>,
+        first_mirrored: u32,
     ) -> Self {
-        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(format!("Uniform buffer for {}", name.unwrap_or("Mesh")).as_str()),
-            contents: bytemuck::cast_slice(&[data]),
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::UNIFORM,
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some(format!("Bind group {}", name.unwrap_or("Mesh")).as_str()),
-            layout: &Mesh::bind_group_layout(device),
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.as_entire_binding(),
-            }],
-        });
-
         Self {
+            center,
             vertex_buffer,
-            vertex_count,
             index_buffer,
             index_count,
-            uniform_buffer,
-            bind_group,
             material_index,
+            instances,
+            first_mirrored,
         }
     }
 
-    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("Mesh uniform bind group layout"),
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::all(),
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: NonZeroU64::new(std::mem::size_of::<MeshUniformData>() as u64),
-                },
-                count: None,
-            }],
-        })
+    /// Split a range of this mesh's instances into its unmirrored and mirrored parts.
+    pub fn split_mirrored(&self, instances: Range<u32>) -> [(Range<u32>, bool); 2] {
+        let split = self.first_mirrored.clamp(instances.start, instances.end);
+        [
+            (instances.start..split, false),
+            (split..instances.end, true),
+        ]
     }
 }
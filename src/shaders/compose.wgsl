@@ -2,7 +2,9 @@ struct SceneUniforms {
 	perspective: mat4x4<f32>,
     view: mat4x4<f32>,
 	inverse_perspective_view: mat4x4<f32>,
-	camera_pos: vec4<f32>
+	camera_pos: vec4<f32>,
+	exposure: f32,
+	environment_intensity: f32,
 }
 
 @group(0) @binding(0) var<uniform> scene: SceneUniforms;
@@ -11,19 +13,87 @@ struct SceneUniforms {
 @group(1) @binding(1) var albedo_gb: texture_2d<f32>;
 @group(1) @binding(2) var normal_gb: texture_2d<f32>;
 @group(1) @binding(3) var material_gb: texture_2d<f32>;
+// r = diffuse visibility, g = specular visibility, ba = bent normal (see below)
+@group(1) @binding(4) var occlusion_gb: texture_2d<f32>;
+@group(1) @binding(5) var emissive_gb: texture_2d<f32>;
 
 struct LightingUniforms {
 	count: u32,
-	colors: array<vec4<f32>, 16>,
-	positions: array<vec4<f32>, 16>,
+	// Show lights per cluster instead of shading
+	heatmap: u32,
+	screen_size: vec2<u32>,
+	near: f32,
+	far: f32,
 }
 
+struct Light {
+	// rgb = color * intensity (candela, lux for directional lights, nits for area lights)
+	color: vec4<f32>,
+	// w is the light's cube shadowmap index, -1 if it doesn't have one
+	position: vec4<f32>,
+	// w = bounding sphere radius (only used for binning)
+	direction: vec4<f32>,
+	// x = type (LIGHT_*), y = radius the light fades out at, z/w = spot cone scale/offset (z = two
+	// sided for area lights)
+	parameters: vec4<f32>,
+	// Half extents of area lights
+	extent_x: vec4<f32>,
+	extent_y: vec4<f32>,
+}
+
+const LIGHT_POINT: u32 = 0u;
+const LIGHT_SPOT: u32 = 1u;
+const LIGHT_DIRECTIONAL: u32 = 2u;
+const LIGHT_RECT: u32 = 3u;
+const LIGHT_DISK: u32 = 4u;
+
+// Lights are binned into clusters by LightClusters (light_clusters.wgsl), matches the constants
+// there
+const CLUSTERS_X: u32 = 16u;
+const CLUSTERS_Y: u32 = 9u;
+const CLUSTERS_Z: u32 = 24u;
+const MAX_LIGHTS_PER_CLUSTER: u32 = 128u;
+
 @group(2) @binding(0) var<uniform> lighting: LightingUniforms;
+@group(2) @binding(1) var<storage, read> lights: array<Light>;
+@group(2) @binding(2) var<storage, read> cluster_counts: array<u32>;
+@group(2) @binding(3) var<storage, read> cluster_lights: array<u32>;
 
 @group(3) @binding(0) var brdf_lut: texture_2d<f32>;
 @group(3) @binding(1) var diffuse_irradiance: texture_cube<f32>;
 @group(3) @binding(2) var specular_prefilter: texture_cube<f32>;
 @group(3) @binding(3) var ibl_s: sampler;
+// Area light lookup tables, indexed by roughness and sqrt(1 - n.v)
+@group(3) @binding(4) var ltc_matrix: texture_2d<f32>;
+@group(3) @binding(5) var ltc_amplitude: texture_2d<f32>;
+
+// Sample the centers of the edge texels of the 64x64 LTC tables
+const LTC_LUT_SCALE: f32 = 0.984375;
+const LTC_LUT_BIAS: f32 = 0.0078125;
+
+struct ShadowUniforms {
+	view_projections: array<mat4x4<f32>, 4>,
+	splits: vec4<f32>,
+	texel_sizes: vec4<f32>,
+	direction: vec4<f32>,
+	color: vec4<f32>,
+	cascade_count: u32,
+	debug_cascades: u32,
+	blend_width: f32,
+}
+
+@group(4) @binding(0) var<uniform> shadow: ShadowUniforms;
+@group(4) @binding(1) var shadow_map: texture_depth_2d_array;
+@group(4) @binding(2) var shadow_s: sampler_comparison;
+
+struct PointShadowUniforms {
+	near: f32,
+	far: f32,
+}
+
+@group(4) @binding(3) var<uniform> point_shadows: PointShadowUniforms;
+// Six faces per cube, see cube_face
+@group(4) @binding(4) var point_shadow_maps: texture_depth_2d_array;
 
 fn screen_to_world_coord(coord: vec2<f32>, depth_sample: f32) -> vec3<f32> {
 	let pos_clip = vec4<f32>(coord.x * 2.0 - 1.0, (1.0 - coord.y) * 2.0 - 1.0, depth_sample, 1.0);
@@ -89,7 +159,499 @@ fn schlick_fresnel_roughness(cosTheta: f32, f0: vec3<f32>, roughness: f32) -> ve
 	return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
 }
 
+// Cook-Torrance BRDF for one light (l points towards the light), already multiplied by n.l
+fn brdf(n: vec3<f32>, v: vec3<f32>, l: vec3<f32>, albedo: vec3<f32>, metalness: f32, roughness: f32, f0: vec3<f32>) -> vec3<f32> {
+	let h = normalize(v + l);
+
+	let ndf = distribution_ggx(n, h, roughness);
+	let g = smith(n, v, l, roughness);
+	let f = schlick_fresnel(max(dot(h, v), 0.0), f0);
+
+	var kD = (vec3<f32>(1.0) - f) * (1.0 - metalness);
+	let nDotL = max(dot(n, l), 0.0);
+
+	let numerator = ndf * g * f;
+	let denominator = max(4.0 * max(dot(n, v), 0.0) * nDotL, 0.0001);
+	let specular = numerator / vec3<f32>(denominator);
+
+	let Fd = albedo / PI;
+
+	return (kD * Fd + specular) * nDotL;
+}
+
+// Index of the cascade covering view_depth, shadow.cascade_count if past the last one
+fn select_cascade(view_depth: f32) -> u32 {
+	var cascade = 0u;
+	for (var i = 0u; i < shadow.cascade_count; i++) {
+		if (view_depth > shadow.splits[i]) {
+			cascade = i + 1u;
+		}
+	}
+	return cascade;
+}
+
+// Fraction of the directional light reaching world_position in one cascade, 3x3 PCF
+fn cascade_shadow(cascade: u32, world_position: vec3<f32>, n: vec3<f32>) -> f32 {
+	let map_size = vec2<f32>(textureDimensions(shadow_map));
+	let texel_size = 1.0 / map_size;
+
+	// Normal offset: push the lookup out along the normal by about a shadowmap texel (in
+	// world units), more so at grazing angles where acne is worst
+	let nDotL = clamp(dot(n, -shadow.direction.xyz), 0.0, 1.0);
+	let offset_position = world_position + n * shadow.texel_sizes[cascade] * (1.0 + 2.0 * (1.0 - nDotL));
+
+	let light_clip = shadow.view_projections[cascade] * vec4<f32>(offset_position, 1.0);
+	let light_ndc = light_clip.xyz / light_clip.w;
+	let uv = vec2<f32>(light_ndc.x * 0.5 + 0.5, light_ndc.y * -0.5 + 0.5);
+
+	// Outside of the shadowmap, treat as lit
+	if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || light_ndc.z > 1.0) {
+		return 1.0;
+	}
+
+	var lit = 0.0;
+	for (var x = -1; x <= 1; x++) {
+		for (var y = -1; y <= 1; y++) {
+			let offset = vec2<f32>(f32(x), f32(y)) * texel_size;
+			lit += textureSampleCompareLevel(shadow_map, shadow_s, uv + offset, cascade, light_ndc.z);
+		}
+	}
+
+	return lit / 9.0;
+}
+
+// Fraction of the directional light reaching world_position (1.0 = fully lit). Near the far
+// end of a cascade it's blended with the next one so the switch isn't visible; the last
+// cascade fades out to lit instead.
+fn directional_shadow(cascade: u32, view_depth: f32, world_position: vec3<f32>, n: vec3<f32>) -> f32 {
+	if (cascade >= shadow.cascade_count) {
+		return 1.0;
+	}
+
+	var lit = cascade_shadow(cascade, world_position, n);
+
+	var cascade_start = 0.0;
+	if (cascade > 0u) {
+		cascade_start = shadow.splits[cascade - 1u];
+	}
+	let cascade_end = shadow.splits[cascade];
+	let blend_start = cascade_end - (cascade_end - cascade_start) * shadow.blend_width;
+
+	if (view_depth > blend_start) {
+		let t = (view_depth - blend_start) / max(cascade_end - blend_start, 0.0001);
+		var next = 1.0;
+		if (cascade + 1u < shadow.cascade_count) {
+			next = cascade_shadow(cascade + 1u, world_position, n);
+		}
+		lit = mix(lit, next, t);
+	}
+
+	return lit;
+}
+
+struct CubeFace {
+	index: i32,
+	uv: vec2<f32>,
+}
+
+// Face (+x, -x, +y, -y, +z, -z) of a cube direction points at and where, picked the way cube
+// sampling does. The point shadowmaps are plain 2D arrays, not every backend has cube arrays.
+fn cube_face(d: vec3<f32>) -> CubeFace {
+	let a = abs(d);
+	var index: i32;
+	var major: f32;
+	var st: vec2<f32>;
+	if (a.x >= a.y && a.x >= a.z) {
+		index = select(1, 0, d.x > 0.0);
+		major = a.x;
+		st = vec2<f32>(select(d.z, -d.z, d.x > 0.0), -d.y);
+	} else if (a.y >= a.z) {
+		index = select(3, 2, d.y > 0.0);
+		major = a.y;
+		st = vec2<f32>(d.x, select(-d.z, d.z, d.y > 0.0));
+	} else {
+		index = select(5, 4, d.z > 0.0);
+		major = a.z;
+		st = vec2<f32>(select(-d.x, d.x, d.z > 0.0), -d.y);
+	}
+	return CubeFace(index, st / major * 0.5 + 0.5);
+}
+
+// Fraction of a point light reaching world_position from its cube shadowmap
+fn point_shadow(slot: i32, light_position: vec3<f32>, world_position: vec3<f32>, n: vec3<f32>) -> f32 {
+	// Same normal offset as the directional light, a texel covers about 2 * distance / size
+	// world units at this distance from the light
+	let distance = length(world_position - light_position);
+	let world_texel = 2.0 * distance / f32(textureDimensions(point_shadow_maps).x);
+	let nDotL = clamp(dot(n, normalize(light_position - world_position)), 0.0, 1.0);
+	let to_fragment = world_position + n * world_texel * (1.0 + 2.0 * (1.0 - nDotL)) - light_position;
+
+	// Depth the fragment has in the cube face it falls in, same projection as WriteShadowmaps
+	let major = max(abs(to_fragment.x), max(abs(to_fragment.y), abs(to_fragment.z)));
+	if (major >= point_shadows.far) {
+		return 1.0;
+	}
+	let near = point_shadows.near;
+	let far = point_shadows.far;
+	let depth = far / (far - near) * (1.0 - near / major);
+
+	let face = cube_face(to_fragment);
+	return textureSampleCompareLevel(point_shadow_maps, shadow_s, face.uv, slot * 6 + face.index, depth);
+}
+
+// Debug tint for pixels shadowed by each cascade
+fn cascade_tint(cascade: u32) -> vec3<f32> {
+	switch cascade {
+		case 0u: { return vec3<f32>(1.0, 0.25, 0.25); }
+		case 1u: { return vec3<f32>(0.25, 1.0, 0.25); }
+		case 2u: { return vec3<f32>(0.25, 0.25, 1.0); }
+		case 3u: { return vec3<f32>(1.0, 1.0, 0.25); }
+		default: { return vec3<f32>(1.0, 1.0, 1.0); }
+	}
+}
+
+// Cluster a pixel (framebuffer coordinates) at some view depth falls in, see light_clusters.wgsl
+fn cluster_index(pixel: vec2<f32>, view_depth: f32) -> u32 {
+	let grid = vec2<u32>(CLUSTERS_X, CLUSTERS_Y);
+	let tile = min(vec2<u32>(pixel) * grid / lighting.screen_size, grid - 1u);
+	let slice_f = log(view_depth / lighting.near) / log(lighting.far / lighting.near) * f32(CLUSTERS_Z);
+	let slice = u32(clamp(slice_f, 0.0, f32(CLUSTERS_Z - 1u)));
+	return (slice * CLUSTERS_Y + tile.y) * CLUSTERS_X + tile.x;
+}
+
+// Blue (no lights) to green to red (16 or more) for the cluster heatmap
+fn heatmap(count: u32) -> vec3<f32> {
+	let t = saturate(f32(count) / 16.0);
+	return select(
+		mix(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, 1.0, 0.0), t * 2.0),
+		mix(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), t * 2.0 - 1.0),
+		t > 0.5
+	);
+}
+
+// Area lights are shaded with linearly transformed cosines (Heitz et al. 2016, "Real-Time
+// Polygonal-Light Shading with Linearly Transformed Cosines"): transformed by the inverse of the
+// matrix fitted to GGX, the lit area's integral against the BRDF is just its form factor.
+
+// Rotation into the space the LTC tables are fitted in: n is z and v is in the xz plane
+fn ltc_basis(n: vec3<f32>, v: vec3<f32>) -> mat3x3<f32> {
+	var t1 = v - n * dot(v, n);
+	if (dot(t1, t1) < 0.000001) {
+		// Looking straight down n, the lobe is symmetric so any tangent works
+		t1 = cross(n, select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), abs(n.x) > 0.9));
+	}
+	t1 = normalize(t1);
+	let t2 = cross(n, t1);
+	return transpose(mat3x3<f32>(t1, t2, n));
+}
+
+// Vector form factor of the arc between two unit vectors (with a fitted acos(x)/sin(x))
+fn integrate_edge(v1: vec3<f32>, v2: vec3<f32>) -> vec3<f32> {
+	let x = dot(v1, v2);
+	let y = abs(x);
+	let a = 0.8543985 + (0.4965155 + 0.0145206 * y) * y;
+	let b = 3.4175940 + (4.1616724 + y) * y;
+	let v = a / b;
+	let theta_sin_theta = select(0.5 * inverseSqrt(max(1.0 - x * x, 1e-7)) - v, v, x > 0.0);
+	return cross(v1, v2) * theta_sin_theta;
+}
+
+// Roots of c.w x³ + c.z x² + c.y x + c.x with three real roots, middle one in y (Blinn, "How
+// to Solve a Cubic Equation")
+fn solve_cubic(coefficients: vec4<f32>) -> vec3<f32> {
+	var c = coefficients;
+	c = vec4<f32>(c.xyz / c.w, c.w);
+	c = vec4<f32>(c.x, c.yz / 3.0, c.w);
+
+	let a = c.w;
+	let b = c.z;
+	let cc = c.y;
+	let d = c.x;
+
+	let delta = vec3<f32>(-c.z * c.z + c.y, -c.y * c.z + c.x, dot(vec2<f32>(c.z, -c.y), c.xy));
+	let discriminant = dot(vec2<f32>(4.0 * delta.x, -delta.y), delta.zy);
+
+	// Largest root
+	var xlc: vec2<f32>;
+	{
+		let c_a = delta.x;
+		let d_a = -2.0 * b * delta.x + delta.y;
+		let theta = atan2(sqrt(discriminant), -d_a) / 3.0;
+		let x_1a = 2.0 * sqrt(-c_a) * cos(theta);
+		let x_3a = 2.0 * sqrt(-c_a) * cos(theta + (2.0 / 3.0) * PI);
+		let xl = select(x_3a, x_1a, (x_1a + x_3a) > 2.0 * b);
+		xlc = vec2<f32>(xl - b, a);
+	}
+
+	// Smallest root
+	var xsc: vec2<f32>;
+	{
+		let c_d = delta.z;
+		let d_d = -d * delta.y + 2.0 * cc * delta.z;
+		let theta = atan2(d * sqrt(discriminant), -d_d) / 3.0;
+		let x_1d = 2.0 * sqrt(-c_d) * cos(theta);
+		let x_3d = 2.0 * sqrt(-c_d) * cos(theta + (2.0 / 3.0) * PI);
+		let xs = select(x_3d, x_1d, x_1d + x_3d < 2.0 * cc);
+		xsc = vec2<f32>(-d, xs + cc);
+	}
+
+	let e = xlc.y * xsc.y;
+	let f = -xlc.x * xsc.y - xlc.y * xsc.x;
+	let g = xlc.x * xsc.x;
+	let xmc = vec2<f32>(cc * f - b * g, -b * f + cc * e);
+
+	var root = vec3<f32>(xsc.x / xsc.y, xmc.x / xmc.y, xlc.x / xlc.y);
+	if (root.x < root.y && root.x < root.z) {
+		root = root.yxz;
+	} else if (root.z < root.x && root.z < root.y) {
+		root = root.xzy;
+	}
+	return root;
+}
+
+// Vector form factor of the ellipse center ± v1 ± v2 (Heitz and Hill 2017, "Real-Time Line- and
+// Disk-Light Shading with Linearly Transformed Cosines"): the ellipse is seen as a sphere with
+// the same solid angle, so the result can be horizon clipped like a rect's
+fn ellipse_form_factor(center: vec3<f32>, v1_in: vec3<f32>, v2_in: vec3<f32>) -> vec3<f32> {
+	var v1 = v1_in;
+	var v2 = v2_in;
+
+	// Principal axes of the ellipse
+	var a: f32;
+	var b: f32;
+	let d11 = dot(v1, v1);
+	let d22 = dot(v2, v2);
+	let d12 = dot(v1, v2);
+	if (abs(d12) / sqrt(d11 * d22) > 0.0001) {
+		let tr = d11 + d22;
+		let det = sqrt(-d12 * d12 + d11 * d22);
+		let u = 0.5 * sqrt(tr - 2.0 * det);
+		let v = 0.5 * sqrt(tr + 2.0 * det);
+		let e_max = (u + v) * (u + v);
+		let e_min = (u - v) * (u - v);
+
+		var v1_: vec3<f32>;
+		var v2_: vec3<f32>;
+		if (d11 > d22) {
+			v1_ = d12 * v1 + (e_max - d11) * v2;
+			v2_ = d12 * v1 + (e_min - d11) * v2;
+		} else {
+			v1_ = d12 * v2 + (e_max - d22) * v1;
+			v2_ = d12 * v2 + (e_min - d22) * v1;
+		}
+
+		a = 1.0 / e_max;
+		b = 1.0 / e_min;
+		v1 = normalize(v1_);
+		v2 = normalize(v2_);
+	} else {
+		a = 1.0 / d11;
+		b = 1.0 / d22;
+		v1 *= sqrt(a);
+		v2 *= sqrt(b);
+	}
+
+	var v3 = cross(v1, v2);
+	if (dot(center, v3) < 0.0) {
+		v3 *= -1.0;
+	}
+
+	let l = dot(v3, center);
+	let x0 = dot(v1, center) / l;
+	let y0 = dot(v2, center) / l;
+
+	a *= l * l;
+	b *= l * l;
+
+	let c0 = a * b;
+	let c1 = a * b * (1.0 + x0 * x0 + y0 * y0) - a - b;
+	let c2 = 1.0 - a * (1.0 + x0 * x0) - b * (1.0 + y0 * y0);
+	let roots = solve_cubic(vec4<f32>(c0, c1, c2, 1.0));
+
+	let average_direction = normalize(mat3x3<f32>(v1, v2, v3) * vec3<f32>(a * x0 / (a - roots.y), b * y0 / (b - roots.y), 1.0));
+
+	let l1 = sqrt(-roots.y / roots.z);
+	let l2 = sqrt(-roots.y / roots.x);
+	let form_factor = l1 * l2 * inverseSqrt((1.0 + l1 * l1) * (1.0 + l2 * l2));
+	return average_direction * form_factor;
+}
+
+// Form factor of an area light seen from p after transforming it by minv, clipped to the
+// horizon. minv = ltc_basis gives the diffuse (cosine) response, with the LTC matrix on top the
+// GGX one.
+fn area_light_form_factor(light: Light, p: vec3<f32>, minv: mat3x3<f32>) -> f32 {
+	let behind = dot(p - light.position.xyz, light.direction.xyz) < 0.0;
+	if (behind && light.parameters.z == 0.0) {
+		return 0.0;
+	}
+
+	let center = minv * (light.position.xyz - p);
+	let x = minv * light.extent_x.xyz;
+	let y = minv * light.extent_y.xyz;
+
+	var form_factor: vec3<f32>;
+	if (u32(light.parameters.x) == LIGHT_RECT) {
+		let p0 = normalize(center - x - y);
+		let p1 = normalize(center + x - y);
+		let p2 = normalize(center + x + y);
+		let p3 = normalize(center - x + y);
+		form_factor = integrate_edge(p0, p1) + integrate_edge(p1, p2) + integrate_edge(p2, p3) + integrate_edge(p3, p0);
+		// The winding flips seen from behind
+		if (behind) {
+			form_factor = -form_factor;
+		}
+	} else {
+		form_factor = ellipse_form_factor(center, x, y);
+	}
+
+	let len = length(form_factor);
+	if (len <= 0.0) {
+		return 0.0;
+	}
+	// Clip to the horizon like a sphere with the same form factor and average direction
+	let uv = vec2<f32>(form_factor.z / len * 0.5 + 0.5, len) * LTC_LUT_SCALE + LTC_LUT_BIAS;
+	return len * textureSampleLevel(ltc_amplitude, ibl_s, uv, 0.0).w;
+}
+
+// Octahedral normal encoding. The occlusion gbuffer stores the bent normal as
+// oct_encode(bent normal) - oct_encode(normal), so techniques without one (SSAO, AO off) can
+// just write 0
+fn oct_wrap(v: vec2<f32>) -> vec2<f32> {
+	return (1.0 - abs(v.yx)) * select(vec2<f32>(-1.0), vec2<f32>(1.0), v >= vec2<f32>(0.0));
+}
+
+fn oct_encode(n: vec3<f32>) -> vec2<f32> {
+	var p = n.xy / (abs(n.x) + abs(n.y) + abs(n.z));
+	if (n.z < 0.0) {
+		p = oct_wrap(p);
+	}
+	return p;
+}
+
+fn oct_decode(p: vec2<f32>) -> vec3<f32> {
+	var n = vec3<f32>(p, 1.0 - abs(p.x) - abs(p.y));
+	let t = clamp(-n.z, 0.0, 1.0);
+	n.x += select(t, -t, n.x >= 0.0);
+	n.y += select(t, -t, n.y >= 0.0);
+	return normalize(n);
+}
+
+// Lit color of a surface: the lights in its cluster (pixel is its framebuffer position), the
+// sun and IBL. Shared by the deferred path (fs_main) and the forward pass for blended materials
+// (fs_forward). occlusion is what the occlusion gbuffer holds, material_occlusion the baked
+// occlusion
 // thank you learnopengl - PBR!!!!
+fn shade(pixel: vec2<f32>, world_position: vec3<f32>, n: vec3<f32>, albedo: vec3<f32>, metalness: f32, roughness: f32, occlusion: vec4<f32>, material_occlusion: f32) -> vec3<f32> {
+	let v = normalize(scene.camera_pos.xyz - world_position);
+	
+	var f0 = vec3<f32>(0.04, 0.04, 0.04);
+	f0 = mix(f0, albedo,  metalness);
+	
+	let view_depth = (scene.view * vec4<f32>(world_position, 1.0)).z;
+
+	// Only the lights binned into this pixel's cluster
+	let cluster = cluster_index(pixel, view_depth);
+	let cluster_count = cluster_counts[cluster];
+	if (lighting.heatmap != 0u) {
+		return heatmap(cluster_count);
+	}
+
+	// Area light lobes for this roughness and view angle
+	let ltc_uv = vec2<f32>(roughness, sqrt(1.0 - saturate(dot(n, v)))) * LTC_LUT_SCALE + LTC_LUT_BIAS;
+	let ltc_1 = textureSampleLevel(ltc_matrix, ibl_s, ltc_uv, 0.0);
+	let ltc_2 = textureSampleLevel(ltc_amplitude, ibl_s, ltc_uv, 0.0);
+	let ltc_diffuse = ltc_basis(n, v);
+	let ltc_specular = mat3x3<f32>(
+		vec3<f32>(ltc_1.x, 0.0, ltc_1.y),
+		vec3<f32>(0.0, 1.0, 0.0),
+		vec3<f32>(ltc_1.z, 0.0, ltc_1.w)
+	) * ltc_diffuse;
+	// Split sum style Fresnel for the whole lobe
+	let ltc_fresnel = f0 * ltc_2.x + (1.0 - f0) * ltc_2.y;
+
+	var l0 = vec3<f32>(0.0, 0.0, 0.0);
+	for (var j: u32 = 0u; j < cluster_count; j++) {
+		let light = lights[cluster_lights[cluster * MAX_LIGHTS_PER_CLUSTER + j]];
+		let parameters = light.parameters;
+		let kind = u32(parameters.x);
+		let direction = light.direction.xyz;
+
+		if (kind == LIGHT_DIRECTIONAL) {
+			l0 += brdf(n, v, -direction, albedo, metalness, roughness, f0) * light.color.rgb;
+			continue;
+		}
+
+		// Windowed so it smoothly reaches zero at the light's radius (from Real Shading in Unreal
+		// Engine 4)
+		let to_light = light.position.xyz - world_position;
+		let distance_squared = dot(to_light, to_light);
+		let distance_ratio = distance_squared / (parameters.y * parameters.y);
+		let window = saturate(1.0 - distance_ratio * distance_ratio);
+
+		if (kind == LIGHT_RECT || kind == LIGHT_DISK) {
+			let diffuse = albedo * (1.0 - metalness) * area_light_form_factor(light, world_position, ltc_diffuse);
+			let specular = ltc_fresnel * area_light_form_factor(light, world_position, ltc_specular);
+			l0 += (diffuse + specular) * light.color.rgb * window * window;
+			continue;
+		}
+
+		// Inverse square falloff, clamped to 1cm so it doesn't blow up up close
+		let l = normalize(to_light);
+		let attenuation = window * window / max(distance_squared, 0.0001);
+		var radiance = light.color.rgb * attenuation;
+
+		if (kind == LIGHT_SPOT) {
+			let cone = saturate(dot(direction, -l) * parameters.z + parameters.w);
+			radiance *= cone * cone;
+		}
+
+		let shadow_slot = i32(light.position.w);
+		if (shadow_slot >= 0) {
+			radiance *= point_shadow(shadow_slot, light.position.xyz, world_position, n);
+		}
+
+		l0 += brdf(n, v, l, albedo, metalness, roughness, f0) * radiance;
+	}
+
+	// Shadow casting sun, the scene's first directional light (which is dark in lights) or the
+	// UI's if it has none
+	let sun_l = -shadow.direction.xyz;
+	let cascade = select_cascade(view_depth);
+	let sun_radiance = shadow.color.rgb * directional_shadow(cascade, view_depth, world_position, n);
+	l0 += brdf(n, v, sun_l, albedo, metalness, roughness, f0) * sun_radiance;
+	
+	let nDotV = max(dot(n, v), 0.0);
+	let r = reflect(-v, n);
+	
+	let kS = schlick_fresnel_roughness(nDotV, f0, roughness);
+	let kD = (1.0 - kS) * (1.0 - metalness);
+
+	let bent_normal = oct_decode(oct_encode(n) + occlusion.ba);
+
+	let irradiance = textureSample(diffuse_irradiance, ibl_s, bent_normal).rgb * scene.environment_intensity;
+	let diffuse = irradiance * albedo;
+
+	let roughness_level = f32(textureNumLevels(specular_prefilter)) * roughness * (2.0 - roughness);
+	let prefiltered_color = textureSampleLevel(specular_prefilter, ibl_s, r, roughness_level).rgb * scene.environment_intensity;
+	// The lookup table's first row is roughness 1
+	let brdf = textureSample(brdf_lut, ibl_s, vec2<f32>(nDotV, 1.0 - roughness)).rg;
+	let specular = prefiltered_color * (f0 * brdf.x + brdf.y);
+	
+	// Ambient occlusion only applies to the ambient/IBL term, direct lights have shadows. Baked
+	// (material) and screen space occlusion mostly capture the same thing, so take the darker
+	let diffuse_occlusion = min(occlusion.r, material_occlusion);
+	let specular_occlusion = min(occlusion.g, material_occlusion);
+	let ambient = kD * diffuse * diffuse_occlusion + specular * specular_occlusion;
+	
+	// Lights and the environment are in photometric units up to here
+	var color = (ambient + l0) * scene.exposure;
+	if (shadow.debug_cascades != 0u) {
+		color *= cascade_tint(cascade);
+	}
+
+	return color;
+}
+
 @fragment
 fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
 	let depth = textureLoad(
@@ -124,59 +686,160 @@ fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
 	let coordUV = position.xy / vec2<f32>(bufferSize);
 	let world_position = screen_to_world_coord(coordUV, depth);
 
-	let v = normalize(scene.camera_pos.xyz - world_position);
-	let n = normal;
-	
-	var f0 = vec3<f32>(0.04, 0.04, 0.04);
-	f0 = mix(f0, albedo,  metalness);
-	
-	var l0 = vec3<f32>(0.0, 0.0, 0.0);
-	for (var i: u32 = 0u; i < lighting.count; i++) {
-		let l = normalize(lighting.positions[i].xyz - world_position);
-		let h = normalize(v + l);
-
-		let distance = length(lighting.positions[i].xyz - world_position);
-		let attenuation = 1.0 / (distance * distance);
-		let radiance = lighting.colors[i].rgb * attenuation;
-
-		// BRDF
-		let ndf = distribution_ggx(n, h, roughness);
-		let g = smith(n, v, l, roughness);
-		let f = schlick_fresnel(max(dot(h, v), 0.0), f0);
-
-		var kD = (vec3<f32>(1.0) - f) * (1.0 - metalness);
-		let nDotL = max(dot(n, l), 0.0);
-		
-		let numerator = ndf * g * f;
-		let denominator = max(4.0 * max(dot(n, v), 0.0) * nDotL, 0.0001);
-		let specular = numerator / vec3<f32>(denominator);
-		
-		let Fd = albedo / PI;
-		
-		l0 += (kD * Fd + specular) * radiance * nDotL;
-	}
-	
-	let nDotV = max(dot(n, v), 0.0);
-	let r = reflect(-v, n);
-	
-	let kS = schlick_fresnel_roughness(nDotV, f0, roughness);
-	let kD = (1.0 - kS) * (1.0 - metalness);
+	let occlusion = textureLoad(occlusion_gb, vec2<i32>(floor(position.xy)), 0);
 
-	let irradiance = textureSample(diffuse_irradiance, ibl_s, n).rgb;
-	let diffuse = irradiance * albedo;
+	// Emission isn't lit, occluded or exposed, it's just added (it can be > 1.0, tonemapping
+	// handles it)
+	let emissive = textureLoad(emissive_gb, vec2<i32>(floor(position.xy)), 0).rgb;
 
-	let roughness_level = f32(textureNumLevels(specular_prefilter)) * roughness * (2.0 - roughness);
-	let prefiltered_color = textureSampleLevel(specular_prefilter, ibl_s, r, roughness_level).rgb;
-	let brdf = textureSample(brdf_lut, ibl_s, vec2<f32>(nDotV, roughness)).rg;
-	let specular = prefiltered_color * (f0 * brdf.x + brdf.y);
-	
-	let ambient = (kD * diffuse + specular);
-	
-	let color = ambient + l0;
+	let color = shade(position.xy, world_position, normal, albedo, metalness, roughness, occlusion, material.b) + emissive;
 	
 	if (depth == 1.0) {
 		return vec4<f32>(0.0, 0.0, 0.0, 1.0);
 	} 
 	
 	return vec4<f32>(color,1.0);
-}
\ No newline at end of file
+}
+
+// Forward pass for alpha blended materials, drawn after Skybox on top of the composed image.
+// Bind groups are the same as the deferred path, except group 1 holds the material instead of
+// the gbuffers. Material/vertex/instance layout matches write_gbuffers.wgsl.
+struct MaterialUniforms {
+	ambient: vec4<f32>,
+	diffuse: vec4<f32>,
+	specular: vec4<f32>,
+	// x = strength, y = uv set
+	occlusion: vec4<f32>,
+	// rgb = factor * strength
+	emissive: vec4<f32>,
+	// x = cutoff, y = mode (0 = opaque, 1 = mask, 2 = blend)
+	alpha: vec4<f32>,
+}
+
+@group(1) @binding(0) var<uniform> forward_material: MaterialUniforms;
+@group(1) @binding(1) var diffuse_texture: texture_2d<f32>;
+@group(1) @binding(2) var diffuse_texture_sampler: sampler;
+@group(1) @binding(3) var normal_texture: texture_2d<f32>;
+@group(1) @binding(4) var normal_texture_sampler: sampler;
+@group(1) @binding(5) var metal_roughness_texture: texture_2d<f32>;
+@group(1) @binding(6) var metal_roughness_texture_sampler: sampler;
+@group(1) @binding(7) var occlusion_texture: texture_2d<f32>;
+@group(1) @binding(8) var occlusion_texture_sampler: sampler;
+@group(1) @binding(9) var emissive_texture: texture_2d<f32>;
+@group(1) @binding(10) var emissive_texture_sampler: sampler;
+
+struct ForwardVertexInput {
+	@location(0) position: vec3<f32>,
+	@location(1) normal: vec3<f32>,
+	@location(2) uv: vec2<f32>,
+	@location(3) tangent: vec3<f32>,
+	@location(4) uv_1: vec2<f32>,
+}
+
+struct ForwardInstanceInput {
+	@location(5) model_0: vec4<f32>,
+	@location(6) model_1: vec4<f32>,
+	@location(7) model_2: vec4<f32>,
+	@location(8) model_3: vec4<f32>,
+	@location(9) normal_0: vec4<f32>,
+	@location(10) normal_1: vec4<f32>,
+	@location(11) normal_2: vec4<f32>,
+}
+
+struct ForwardVertexOutput {
+	@builtin(position) clip_space_position: vec4<f32>,
+	@location(0) world_position: vec3<f32>,
+	@location(1) normal: vec3<f32>,
+	@location(2) uv: vec2<f32>,
+	@location(3) tangent: vec3<f32>,
+	@location(4) uv_1: vec2<f32>,
+}
+
+@vertex
+fn vs_forward(in: ForwardVertexInput, instance: ForwardInstanceInput) -> ForwardVertexOutput {
+	let model = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+	let normal_matrix = mat3x3<f32>(instance.normal_0.xyz, instance.normal_1.xyz, instance.normal_2.xyz);
+
+	var out: ForwardVertexOutput;
+	out.clip_space_position = scene.perspective * scene.view * model * vec4<f32>(in.position, 1.0);
+	out.world_position = (model * vec4<f32>(in.position, 1.0)).xyz;
+	out.normal = normal_matrix * in.normal;
+	out.uv = in.uv;
+	out.tangent = (model * vec4<f32>(in.tangent, 0.0)).xyz;
+	out.uv_1 = in.uv_1;
+	return out;
+}
+
+// Lit color and alpha of a blended surface (not premultiplied)
+fn forward_color(in: ForwardVertexOutput, front_facing: bool) -> vec4<f32> {
+	let diffuse = textureSample(diffuse_texture, diffuse_texture_sampler, in.uv);
+
+	var normal = textureSample(normal_texture, normal_texture_sampler, in.uv).rgb - 0.5;
+	let rotation = mat3x3<f32>(
+		normalize(in.tangent),
+		normalize(cross(in.tangent, in.normal)),
+		normalize(in.normal)
+	);
+	normal = normalize(rotation * normalize(normal));
+	// Back faces only get here for double sided materials
+	normal = select(-normal, normal, front_facing);
+
+	let metal_roughness = textureSample(metal_roughness_texture, metal_roughness_texture_sampler, in.uv);
+	let metalness = metal_roughness.b;
+	let roughness = max(0.01, metal_roughness.g);
+
+	let occlusion_uv = select(in.uv, in.uv_1, forward_material.occlusion.y > 0.5);
+	let occlusion_sample = textureSample(occlusion_texture, occlusion_texture_sampler, occlusion_uv).r;
+	let material_occlusion = 1.0 + forward_material.occlusion.x * (occlusion_sample - 1.0);
+
+	let emissive = textureSample(emissive_texture, emissive_texture_sampler, in.uv).rgb * forward_material.emissive.rgb;
+
+	// No screen space occlusion for transparent surfaces (they aren't in the depth buffer)
+	let no_occlusion = vec4<f32>(1.0, 1.0, 0.0, 0.0);
+	let color = shade(in.clip_space_position.xy, in.world_position, normal, diffuse.rgb, metalness, roughness, no_occlusion, material_occlusion) + emissive;
+
+	return vec4<f32>(color, diffuse.a * forward_material.ambient.a);
+}
+
+// Sorted blending, drawn back to front straight into the composed image
+@fragment
+fn fs_forward(in: ForwardVertexOutput, @builtin(front_facing) front_facing: bool) -> @location(0) vec4<f32> {
+	return forward_color(in, front_facing);
+}
+
+// Weighted blended order independent transparency (McGuire & Bavoil 2013). Surfaces are
+// accumulated in any order into accum (additive) and revealage (multiplicative, how much of the
+// background still shows through), fs_oit_resolve composites the result.
+struct OitOutput {
+	@location(0) accum: vec4<f32>,
+	@location(1) revealage: f32,
+}
+
+@fragment
+fn fs_forward_oit(in: ForwardVertexOutput, @builtin(front_facing) front_facing: bool) -> OitOutput {
+	let color = forward_color(in, front_facing);
+
+	// Weight falls off with view depth so nearer surfaces dominate (eq. 7 in the paper, with
+	// depth in view space units)
+	let view_depth = (scene.view * vec4<f32>(in.world_position, 1.0)).z;
+	let weight = color.a * clamp(0.03 / (1e-5 + pow(view_depth / 200.0, 4.0)), 1e-2, 3e3);
+
+	var out: OitOutput;
+	out.accum = vec4<f32>(color.rgb * color.a, color.a) * weight;
+	out.revealage = color.a;
+	return out;
+}
+
+// Bindings 11/12 so they don't collide with the gbuffers or material in group 1
+@group(1) @binding(11) var oit_accum: texture_2d<f32>;
+@group(1) @binding(12) var oit_revealage: texture_2d<f32>;
+
+@fragment
+fn fs_oit_resolve(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+	let revealage = textureLoad(oit_revealage, vec2<i32>(floor(position.xy)), 0).r;
+	let accum = textureLoad(oit_accum, vec2<i32>(floor(position.xy)), 0);
+
+	// Average color of the transparent surfaces, blended over the image by 1 - revealage
+	let average = accum.rgb / max(accum.a, 1e-5);
+	return vec4<f32>(average, 1.0 - revealage);
+}
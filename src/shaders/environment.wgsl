@@ -0,0 +1,200 @@
+// Bakes the skybox and IBL maps from an equirectangular panorama, see environment.rs. Cubemaps
+// are written a mip level at a time through 2D array views, one layer per face.
+
+struct BakeParams {
+	// Roughness the prefilter level is convolved for
+	roughness: f32,
+	sample_count: u32,
+}
+
+@group(0) @binding(0) var output: texture_storage_2d_array<rgba16float, write>;
+@group(0) @binding(1) var<uniform> params: BakeParams;
+// Only one of these is bound, depending on the entry point
+@group(0) @binding(2) var equirect: texture_2d<f32>;
+@group(0) @binding(3) var source_level: texture_2d_array<f32>;
+@group(0) @binding(4) var environment: texture_cube<f32>;
+@group(0) @binding(5) var environment_s: sampler;
+
+const PI: f32 = 3.14159265359;
+
+// Direction through a texel of a cube face, for uv in [0, 1] with v going down. Same face
+// order and orientation as cube sampling uses.
+fn face_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+	let p = uv * 2.0 - 1.0;
+	var direction: vec3<f32>;
+	switch face {
+		case 0u: { direction = vec3<f32>(1.0, -p.y, -p.x); }
+		case 1u: { direction = vec3<f32>(-1.0, -p.y, p.x); }
+		case 2u: { direction = vec3<f32>(p.x, 1.0, p.y); }
+		case 3u: { direction = vec3<f32>(p.x, -1.0, -p.y); }
+		case 4u: { direction = vec3<f32>(p.x, -p.y, 1.0); }
+		default: { direction = vec3<f32>(-p.x, -p.y, -1.0); }
+	}
+	return normalize(direction);
+}
+
+// Direction of the output texel, none if it's outside the output (the last workgroups overhang)
+fn texel_direction(id: vec3<u32>) -> vec3<f32> {
+	let size = textureDimensions(output);
+	return face_direction(id.z, (vec2<f32>(id.xy) + 0.5) / vec2<f32>(size));
+}
+
+fn in_output(id: vec3<u32>) -> bool {
+	return all(id.xy < textureDimensions(output));
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+	return vec2<f32>(f32(i) / f32(count), f32(reverseBits(i)) * 2.3283064365386963e-10);
+}
+
+// Orthonormal basis around n, z = n
+fn tangent_frame(n: vec3<f32>) -> mat3x3<f32> {
+	let up = select(vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 1.0), abs(n.z) < 0.999);
+	let tangent = normalize(cross(up, n));
+	let bitangent = cross(n, tangent);
+	return mat3x3<f32>(tangent, bitangent, n);
+}
+
+// GGX distributed half vector around +z, alpha = roughness²
+fn importance_sample_ggx(xi: vec2<f32>, alpha: f32) -> vec3<f32> {
+	let phi = 2.0 * PI * xi.x;
+	let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (alpha * alpha - 1.0) * xi.y));
+	let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+	return vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+}
+
+fn distribution_ggx(n_dot_h: f32, alpha: f32) -> f32 {
+	let a2 = alpha * alpha;
+	let denom = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+	return a2 / (PI * denom * denom);
+}
+
+// Environment mip level whose texels cover about the solid angle of a sample with this pdf,
+// so a few samples still see the whole lobe (filtered importance sampling)
+fn sample_level(pdf: f32) -> f32 {
+	let size = f32(textureDimensions(environment).x);
+	let sample_solid_angle = 1.0 / (f32(params.sample_count) * pdf + 0.0001);
+	let texel_solid_angle = 4.0 * PI / (6.0 * size * size);
+	return max(0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0, 0.0);
+}
+
+// Longitude along u (u = 0.5 looking down +z, increasing towards +x), latitude along v from
+// the top
+@compute @workgroup_size(8, 8)
+fn cs_equirect(@builtin(global_invocation_id) id: vec3<u32>) {
+	if (!in_output(id)) {
+		return;
+	}
+	let d = texel_direction(id);
+	let uv = vec2<f32>(0.5 + atan2(d.x, d.z) / (2.0 * PI), acos(clamp(d.y, -1.0, 1.0)) / PI);
+	textureStore(output, id.xy, id.z, textureSampleLevel(equirect, environment_s, uv, 0.0));
+}
+
+// Box filter the level above into the output level
+@compute @workgroup_size(8, 8)
+fn cs_downsample(@builtin(global_invocation_id) id: vec3<u32>) {
+	if (!in_output(id)) {
+		return;
+	}
+	let p = vec2<i32>(id.xy * 2u);
+	let face = i32(id.z);
+	let color = textureLoad(source_level, p, face, 0)
+		+ textureLoad(source_level, p + vec2<i32>(1, 0), face, 0)
+		+ textureLoad(source_level, p + vec2<i32>(0, 1), face, 0)
+		+ textureLoad(source_level, p + vec2<i32>(1, 1), face, 0);
+	textureStore(output, id.xy, id.z, color * 0.25);
+}
+
+// Cosine weighted average of the incoming radiance, 1/π ∫ L cos, so compose only multiplies
+// it by the albedo
+@compute @workgroup_size(8, 8)
+fn cs_irradiance(@builtin(global_invocation_id) id: vec3<u32>) {
+	if (!in_output(id)) {
+		return;
+	}
+	let frame = tangent_frame(texel_direction(id));
+
+	var irradiance = vec3<f32>(0.0);
+	for (var i = 0u; i < params.sample_count; i++) {
+		let xi = hammersley(i, params.sample_count);
+		let phi = 2.0 * PI * xi.x;
+		let cos_theta = sqrt(1.0 - xi.y);
+		let sin_theta = sqrt(xi.y);
+		let l = frame * vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+		// Cosine weighted, so the cosine and 1/π cancel with the pdf
+		let level = sample_level(cos_theta / PI);
+		irradiance += textureSampleLevel(environment, environment_s, l, level).rgb;
+	}
+	textureStore(output, id.xy, id.z, vec4<f32>(irradiance / f32(params.sample_count), 1.0));
+}
+
+// GGX prefiltered radiance for params.roughness, assuming n = v = r (split sum approximation)
+@compute @workgroup_size(8, 8)
+fn cs_prefilter(@builtin(global_invocation_id) id: vec3<u32>) {
+	if (!in_output(id)) {
+		return;
+	}
+	let n = texel_direction(id);
+
+	// A mirror just needs the environment at the output's resolution
+	if (params.roughness == 0.0) {
+		let level = log2(f32(textureDimensions(environment).x) / f32(textureDimensions(output).x));
+		textureStore(output, id.xy, id.z, textureSampleLevel(environment, environment_s, n, level));
+		return;
+	}
+
+	let frame = tangent_frame(n);
+	let alpha = params.roughness * params.roughness;
+	var color = vec3<f32>(0.0);
+	var weight = 0.0;
+	for (var i = 0u; i < params.sample_count; i++) {
+		let h_local = importance_sample_ggx(hammersley(i, params.sample_count), alpha);
+		let h = frame * h_local;
+		let l = reflect(-n, h);
+		let n_dot_l = dot(n, l);
+		if (n_dot_l > 0.0) {
+			// pdf of l is D * n.h / (4 v.h), and v.h = n.h here
+			let level = sample_level(distribution_ggx(h_local.z, alpha) / 4.0);
+			color += textureSampleLevel(environment, environment_s, l, level).rgb * n_dot_l;
+			weight += n_dot_l;
+		}
+	}
+	textureStore(output, id.xy, id.z, vec4<f32>(color / max(weight, 0.0001), 1.0));
+}
+
+// Height uncorrelated Smith masking for one direction
+fn smith_g1(n_dot_x: f32, alpha: f32) -> f32 {
+	let a2 = alpha * alpha;
+	return 2.0 * n_dot_x / (n_dot_x + sqrt(a2 + (1.0 - a2) * n_dot_x * n_dot_x));
+}
+
+// Split sum BRDF scale (r) and bias (g) for f0, n.v along x and roughness along y. Like the
+// OCEANMAN_BRDF.dds it replaces, the first row is roughness 1.
+@compute @workgroup_size(8, 8)
+fn cs_brdf(@builtin(global_invocation_id) id: vec3<u32>) {
+	if (!in_output(id)) {
+		return;
+	}
+	let size = vec2<f32>(textureDimensions(output));
+	let n_dot_v = (f32(id.x) + 0.5) / size.x;
+	let roughness = 1.0 - (f32(id.y) + 0.5) / size.y;
+	let alpha = roughness * roughness;
+	let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+
+	var scale = 0.0;
+	var bias = 0.0;
+	for (var i = 0u; i < params.sample_count; i++) {
+		let h = importance_sample_ggx(hammersley(i, params.sample_count), alpha);
+		let v_dot_h = dot(v, h);
+		let l = 2.0 * v_dot_h * h - v;
+		if (l.z > 0.0) {
+			let g = smith_g1(n_dot_v, alpha) * smith_g1(l.z, alpha);
+			let g_vis = g * max(v_dot_h, 0.0) / (h.z * n_dot_v);
+			let fc = pow(1.0 - max(v_dot_h, 0.0), 5.0);
+			scale += (1.0 - fc) * g_vis;
+			bias += fc * g_vis;
+		}
+	}
+	let count = f32(params.sample_count);
+	textureStore(output, id.xy, 0, vec4<f32>(scale / count, bias / count, 0.0, 1.0));
+}
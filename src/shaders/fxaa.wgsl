@@ -31,8 +31,12 @@ fn rgb_to_luma(rgb: vec3<f32>) -> f32 {
 	return sqrt(dot(rgb, vec3<f32>(0.299, 0.587, 0.114)));
 }
 
+fn get_rcp_frame() -> vec2<f32> {
+	return vec2<f32>(1.0) / vec2<f32>(textureDimensions(screen));
+}
+
 fn fb_to_uv(pos: vec2<f32>) -> vec2<f32> {
-	let rcp_frame = vec2<f32>(1.0 / 1600.0, 1.0 / 900.0);
+	let rcp_frame = get_rcp_frame();
 	return pos.xy * rcp_frame.xy;
 }
 
@@ -44,13 +48,13 @@ fn ts(t: texture_2d<f32>, s: sampler, uv: vec2<f32>) -> vec3<f32> {
 
 fn ts_offset(t: texture_2d<f32>, s: sampler, uv: vec2<f32>, offset: vec2<i32>) -> vec3<f32> {
 	// convert from linear -> srgb
-	var coords = uv + vec2<f32>(offset) * vec2<f32>(1.0 / 1600.0, 1.0 / 900.0);
+	var coords = uv + vec2<f32>(offset) * get_rcp_frame();
 	return ts(t, s, coords);	
 }
 
 @fragment
 fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
-	var rcp_frame = vec2<f32>(1.0 / 1600.0, 1.0 / 900.0);
+	var rcp_frame = get_rcp_frame();
 	// FXAA works in sRGB space but the -srgb textures were using do an automatic
 	// sRGB -> linear convert in the shader. So we need to sample the srgb texture
 	// and then do an linear -> sRGB conversion.
@@ -0,0 +1,253 @@
+struct SceneUniforms {
+	perspective: mat4x4<f32>,
+    view: mat4x4<f32>,
+	inverse_perspective_view: mat4x4<f32>,
+	camera_pos: vec4<f32>,
+	exposure: f32,
+	environment_intensity: f32,
+}
+
+@group(0) @binding(0) var<uniform> scene: SceneUniforms;
+
+struct GtaoParams {
+	radius: f32,
+	intensity: f32,
+	slice_count: u32,
+	step_count: u32,
+	specular_occlusion: u32,
+}
+
+@group(1) @binding(0) var depth_t: texture_depth_2d;
+@group(1) @binding(1) var normal_t: texture_2d<f32>;
+@group(1) @binding(2) var material_t: texture_2d<f32>;
+@group(1) @binding(3) var<uniform> params: GtaoParams;
+
+// Blur pass only (shares depth_t)
+@group(1) @binding(5) var raw_occlusion_t: texture_2d<f32>;
+
+const PI: f32 = 3.14159265359;
+const HALF_PI: f32 = 1.57079632679;
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+	var vertex_positions = array<vec2<f32>, 6>(
+		vec2<f32>(-1.0, -1.0),
+		vec2<f32>(1.0, 1.0),
+		vec2<f32>(-1.0, 1.0),
+		vec2<f32>(-1.0, -1.0),
+		vec2<f32>(1.0, -1.0),
+		vec2<f32>(1.0, 1.0)
+	);
+	
+	return vec4<f32>(vertex_positions[index], 0.0, 1.0);
+}
+
+// View space position of the gbuffer texel at coord
+fn vs_position_from_depth(coord: vec2<i32>) -> vec3<f32> {
+	let uv = (vec2<f32>(coord) + 0.5) / vec2<f32>(textureDimensions(depth_t));
+	let z = textureLoad(depth_t, coord, 0);
+	let x = uv.x * 2.0 - 1.0;
+	let y = (1.0 - uv.y) * 2.0 - 1.0;
+
+	let world_pos = scene.inverse_perspective_view * vec4<f32>(x, y, z, 1.0);
+	let vs_pos = scene.view * vec4<f32>(world_pos.xyz / world_pos.w, 1.0);
+
+	return vs_pos.xyz;
+}
+
+// Octahedral encoding, see compose.wgsl for how the bent normal is stored
+fn oct_wrap(v: vec2<f32>) -> vec2<f32> {
+	return (1.0 - abs(v.yx)) * select(vec2<f32>(-1.0), vec2<f32>(1.0), v >= vec2<f32>(0.0));
+}
+
+fn oct_encode(n: vec3<f32>) -> vec2<f32> {
+	var p = n.xy / (abs(n.x) + abs(n.y) + abs(n.z));
+	if (n.z < 0.0) {
+		p = oct_wrap(p);
+	}
+	return p;
+}
+
+// Rotation taking a to b (both unit length, not opposite)
+fn rotation_from_to(a: vec3<f32>, b: vec3<f32>) -> mat3x3<f32> {
+	let e = dot(a, b);
+	let v = cross(a, b);
+	let h = 1.0 / (1.0 + e);
+	let hvx = h * v.x;
+	let hvz = h * v.z;
+	let hvxy = hvx * v.y;
+	let hvxz = hvx * v.z;
+	let hvyz = hvz * v.y;
+
+	return mat3x3<f32>(
+		vec3<f32>(e + hvx * v.x, hvxy + v.z, hvxz - v.y),
+		vec3<f32>(hvxy - v.z, e + h * v.y * v.y, hvyz + v.x),
+		vec3<f32>(hvxz + v.y, hvyz - v.x, e + hvz * v.z),
+	);
+}
+
+// Approximate overlap of two cones (arc lengths are full apertures), used for specular occlusion
+fn cone_cone_intersection(arc_length_1: f32, arc_length_2: f32, angle_between: f32) -> f32 {
+	let angle_difference = abs(arc_length_1 - arc_length_2);
+	let blend = clamp((angle_between - angle_difference) / max(arc_length_1 + arc_length_2 - angle_difference, 0.0001), 0.0, 1.0);
+	return smoothstep(0.0, 1.0, 1.0 - blend);
+}
+
+// 4x4 ordered dither, so the blur (also 4x4) cancels the pattern out
+fn dither_4x4(coord: vec2<i32>) -> f32 {
+	var bayer = array<f32, 16>(
+		0.0, 8.0, 2.0, 10.0,
+		12.0, 4.0, 14.0, 6.0,
+		3.0, 11.0, 1.0, 9.0,
+		15.0, 7.0, 13.0, 5.0
+	);
+	let c = coord % vec2<i32>(4, 4);
+	return (bayer[c.y * 4 + c.x] + 0.5) / 16.0;
+}
+
+// Horizon based GTAO (Jimenez et al. 2016, structured like XeGTAO). Output is
+// r = diffuse visibility, g = specular visibility, ba = bent normal (see compose.wgsl)
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+	let coord = vec2<i32>(floor(position.xy));
+	let size = vec2<f32>(textureDimensions(depth_t));
+
+	// Nothing to occlude on the skybox
+	if (textureLoad(depth_t, coord, 0) == 1.0) {
+		return vec4<f32>(1.0, 1.0, 0.0, 0.0);
+	}
+
+	let origin = vs_position_from_depth(coord);
+	let world_normal = normalize(textureLoad(normal_t, coord, 0).xyz * 2.0 - 1.0);
+	let normal = normalize((scene.view * vec4<f32>(world_normal, 0.0)).xyz);
+	let v = normalize(-origin);
+
+	// Radius in pixels at this depth
+	let radius_pixels = params.radius * 0.5 * size.y * scene.perspective[1][1] / origin.z;
+	if (radius_pixels < 1.0) {
+		return vec4<f32>(1.0, 1.0, 0.0, 0.0);
+	}
+
+	// Samples fade out over the far part of the radius instead of stopping abruptly
+	let falloff_range = 0.615 * params.radius;
+	let falloff_mul = -1.0 / falloff_range;
+	let falloff_add = (params.radius - falloff_range) / falloff_range + 1.0;
+
+	let noise_slice = dither_4x4(coord);
+	let noise_step = dither_4x4(coord.yx + vec2<i32>(1, 2));
+
+	var visibility = 0.0;
+	var bent_normal = vec3<f32>(0.0);
+
+	for (var slice = 0u; slice < params.slice_count; slice++) {
+		let phi = (f32(slice) + noise_slice) * PI / f32(params.slice_count);
+		let direction = vec3<f32>(cos(phi), sin(phi), 0.0);
+		// Screen y goes down, view space y goes up
+		let omega = vec2<f32>(direction.x, -direction.y);
+
+		// Project the normal onto the slice plane
+		let ortho_direction = direction - dot(direction, v) * v;
+		let axis = normalize(cross(ortho_direction, v));
+		let projected_normal = normal - axis * dot(normal, axis);
+		let projected_normal_length = length(projected_normal);
+
+		let sign_n = sign(dot(ortho_direction, projected_normal));
+		let cos_n = clamp(dot(projected_normal, v) / projected_normal_length, 0.0, 1.0);
+		let n = sign_n * acos(cos_n);
+
+		let low_horizon_cos_0 = cos(n + HALF_PI);
+		let low_horizon_cos_1 = cos(n - HALF_PI);
+		var horizon_cos_0 = low_horizon_cos_0;
+		var horizon_cos_1 = low_horizon_cos_1;
+
+		for (var step = 0u; step < params.step_count; step++) {
+			var s = (f32(step) + noise_step) / f32(params.step_count);
+			// More samples close to the center
+			s = s * s;
+
+			let offset = omega * max(s * radius_pixels, 1.0);
+			let coord_0 = clamp(vec2<i32>(round(position.xy + offset)), vec2<i32>(0), vec2<i32>(size) - 1);
+			let coord_1 = clamp(vec2<i32>(round(position.xy - offset)), vec2<i32>(0), vec2<i32>(size) - 1);
+
+			let delta_0 = vs_position_from_depth(coord_0) - origin;
+			let delta_1 = vs_position_from_depth(coord_1) - origin;
+			let distance_0 = length(delta_0);
+			let distance_1 = length(delta_1);
+
+			let weight_0 = clamp(distance_0 * falloff_mul + falloff_add, 0.0, 1.0);
+			let weight_1 = clamp(distance_1 * falloff_mul + falloff_add, 0.0, 1.0);
+			let shc_0 = mix(low_horizon_cos_0, dot(delta_0 / distance_0, v), weight_0);
+			let shc_1 = mix(low_horizon_cos_1, dot(delta_1 / distance_1, v), weight_1);
+
+			horizon_cos_0 = max(horizon_cos_0, shc_0);
+			horizon_cos_1 = max(horizon_cos_1, shc_1);
+		}
+
+		// Horizon angles, clamped to the hemisphere around the projected normal
+		var h0 = -acos(clamp(horizon_cos_1, -1.0, 1.0));
+		var h1 = acos(clamp(horizon_cos_0, -1.0, 1.0));
+		h0 = n + clamp(h0 - n, -HALF_PI, HALF_PI);
+		h1 = n + clamp(h1 - n, -HALF_PI, HALF_PI);
+
+		// Cosine weighted visibility between the horizons
+		let arc_0 = (cos_n + 2.0 * h0 * sin(n) - cos(2.0 * h0 - n)) / 4.0;
+		let arc_1 = (cos_n + 2.0 * h1 * sin(n) - cos(2.0 * h1 - n)) / 4.0;
+		visibility += projected_normal_length * (arc_0 + arc_1);
+
+		// Bent normal of the slice, in slice space then rotated to view space
+		let t0 = (6.0 * sin(h0 - n) - sin(3.0 * h0 - n) + 6.0 * sin(h1 - n) - sin(3.0 * h1 - n)
+			+ 16.0 * sin(n) - 3.0 * (sin(h0 + n) + sin(h1 + n))) / 12.0;
+		let t1 = (-cos(3.0 * h0 - n) - cos(3.0 * h1 - n) + 8.0 * cos(n)
+			- 3.0 * (cos(h0 + n) + cos(h1 + n))) / 12.0;
+		let local_bent_normal = vec3<f32>(direction.x * t0, direction.y * t0, -t1);
+		bent_normal += rotation_from_to(vec3<f32>(0.0, 0.0, -1.0), v) * local_bent_normal * projected_normal_length;
+	}
+
+	visibility = pow(clamp(visibility / f32(params.slice_count), 0.0, 1.0), params.intensity);
+	bent_normal = normalize(bent_normal);
+
+	// Visibility cone around the bent normal vs. the specular lobe around the reflection
+	var specular_visibility = visibility;
+	if (params.specular_occlusion != 0u) {
+		let roughness = max(0.01, textureLoad(material_t, coord, 0).g);
+		let r = reflect(-v, normal);
+		let unoccluded_angle = visibility * PI * 0.5;
+		let specular_angle = max(roughness * roughness, 0.1) * PI * 0.5;
+		let angle_between = acos(clamp(dot(bent_normal, r), -1.0, 1.0));
+		specular_visibility = cone_cone_intersection(specular_angle, unoccluded_angle, angle_between);
+		specular_visibility = mix(0.0, specular_visibility, clamp((unoccluded_angle - 0.1) / 0.2, 0.0, 1.0));
+	}
+
+	// World space, stored relative to the gbuffer normal
+	let view_rotation = mat3x3<f32>(scene.view[0].xyz, scene.view[1].xyz, scene.view[2].xyz);
+	let world_bent_normal = normalize(transpose(view_rotation) * bent_normal);
+	let bent_offset = oct_encode(world_bent_normal) - oct_encode(world_normal);
+
+	return vec4<f32>(visibility, specular_visibility, bent_offset);
+}
+
+// 4x4 depth-aware blur, same as SSAO's but keeps all four channels
+@fragment
+fn fs_blur(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+	let coord = vec2<i32>(floor(position.xy));
+	let size = vec2<i32>(textureDimensions(raw_occlusion_t));
+	let center_depth = vs_position_from_depth(coord).z;
+
+	var sum = vec4<f32>(0.0);
+	var weight_sum = 0.0;
+	for (var x = -2; x < 2; x++) {
+		for (var y = -2; y < 2; y++) {
+			let texel = clamp(coord + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+			let depth = vs_position_from_depth(texel).z;
+
+			// Within ~5% of the center's depth counts as the same surface
+			let difference = (depth - center_depth) / (0.05 * center_depth);
+			let weight = exp(-difference * difference);
+
+			sum += textureLoad(raw_occlusion_t, texel, 0) * weight;
+			weight_sum += weight;
+		}
+	}
+
+	return sum / max(weight_sum, 0.0001);
+}
@@ -0,0 +1,135 @@
+struct SceneUniforms {
+	perspective: mat4x4<f32>,
+	view: mat4x4<f32>,
+	inverse_perspective_view: mat4x4<f32>,
+	camera_pos: vec4<f32>,
+	exposure: f32,
+	environment_intensity: f32,
+}
+
+@group(0) @binding(0) var<uniform> scene: SceneUniforms;
+
+@group(1) @binding(0) var depth_gb: texture_depth_2d;
+
+struct LightingUniforms {
+	count: u32,
+	heatmap: u32,
+	screen_size: vec2<u32>,
+	near: f32,
+	far: f32,
+}
+
+struct Light {
+	// rgb = color * intensity (candela, lux for directional lights, nits for area lights)
+	color: vec4<f32>,
+	// w = cube shadowmap index, -1 if it doesn't have one
+	position: vec4<f32>,
+	// w = bounding sphere radius, -1 for directional lights
+	direction: vec4<f32>,
+	// x = type, y = radius the light fades out at, z/w = spot cone scale/offset
+	parameters: vec4<f32>,
+	extent_x: vec4<f32>,
+	extent_y: vec4<f32>,
+}
+
+@group(2) @binding(0) var<uniform> lighting: LightingUniforms;
+@group(2) @binding(1) var<storage, read> lights: array<Light>;
+@group(2) @binding(2) var<storage, read_write> cluster_counts: array<u32>;
+@group(2) @binding(3) var<storage, read_write> cluster_lights: array<u32>;
+
+// Matches CLUSTER_GRID/MAX_LIGHTS_PER_CLUSTER in resources.rs
+const CLUSTERS_X: u32 = 16u;
+const CLUSTERS_Y: u32 = 9u;
+const CLUSTERS_Z: u32 = 24u;
+const MAX_LIGHTS_PER_CLUSTER: u32 = 128u;
+
+const WORKGROUP_SIZE: u32 = 64u;
+
+// Furthest gbuffer depth in the tile (as bits, depth is positive so they order the same)
+var<workgroup> tile_max_depth: atomic<u32>;
+var<workgroup> slice_counts: array<atomic<u32>, 24>;
+
+fn view_depth(depth: f32) -> f32 {
+	return scene.perspective[3][2] / (depth - scene.perspective[2][2]);
+}
+
+// View depth where a cluster slice starts, slices are spaced exponentially from near to far
+fn slice_depth(slice: u32) -> f32 {
+	return lighting.near * pow(lighting.far / lighting.near, f32(slice) / f32(CLUSTERS_Z));
+}
+
+// One workgroup per screen tile: find how far the tile's geometry goes, then bin every light
+// into the tile's depth slices up to there. Slices past the furthest geometry stay empty.
+@compute @workgroup_size(64)
+fn cs_main(@builtin(workgroup_id) tile: vec3<u32>, @builtin(local_invocation_index) thread: u32) {
+	if (thread == 0u) {
+		atomicStore(&tile_max_depth, 0u);
+	}
+	if (thread < CLUSTERS_Z) {
+		atomicStore(&slice_counts[thread], 0u);
+	}
+	workgroupBarrier();
+
+	// Pixels p with p * CLUSTERS / screen_size == tile, same as cluster_index in compose.wgsl
+	let grid = vec2<u32>(CLUSTERS_X, CLUSTERS_Y);
+	let start = (tile.xy * lighting.screen_size + grid - 1u) / grid;
+	let end = min(((tile.xy + 1u) * lighting.screen_size + grid - 1u) / grid, lighting.screen_size);
+	let tile_size = max(end, start) - start;
+
+	var max_depth = 0.0;
+	for (var i = thread; i < tile_size.x * tile_size.y; i += WORKGROUP_SIZE) {
+		let pixel = start + vec2<u32>(i % tile_size.x, i / tile_size.x);
+		max_depth = max(max_depth, textureLoad(depth_gb, pixel, 0));
+	}
+	atomicMax(&tile_max_depth, bitcast<u32>(max_depth));
+	workgroupBarrier();
+	let far_depth = view_depth(bitcast<f32>(atomicLoad(&tile_max_depth)));
+
+	// NDC extent of the tile, tile 0 is at the top
+	let ndc_min = vec2<f32>(
+		f32(tile.x) / f32(CLUSTERS_X) * 2.0 - 1.0,
+		1.0 - f32(tile.y + 1u) / f32(CLUSTERS_Y) * 2.0
+	);
+	let ndc_max = vec2<f32>(
+		f32(tile.x + 1u) / f32(CLUSTERS_X) * 2.0 - 1.0,
+		1.0 - f32(tile.y) / f32(CLUSTERS_Y) * 2.0
+	);
+	let projection_scale = vec2<f32>(scene.perspective[0][0], scene.perspective[1][1]);
+
+	for (var i = thread; i < lighting.count; i += WORKGROUP_SIZE) {
+		let light = lights[i];
+		let radius = light.direction.w;
+		let center = (scene.view * vec4<f32>(light.position.xyz, 1.0)).xyz;
+
+		for (var slice = 0u; slice < CLUSTERS_Z; slice++) {
+			let z_near = slice_depth(slice);
+			let z_far = slice_depth(slice + 1u);
+			if (z_near > far_depth) {
+				break;
+			}
+
+			// Directional lights reach every cluster, others if their bounding sphere touches
+			// the cluster's view space bounding box
+			if (radius >= 0.0) {
+				let bounds_min = vec3<f32>(min(ndc_min * z_near, ndc_min * z_far) / projection_scale, z_near);
+				let bounds_max = vec3<f32>(max(ndc_max * z_near, ndc_max * z_far) / projection_scale, z_far);
+				let offset = clamp(center, bounds_min, bounds_max) - center;
+				if (dot(offset, offset) > radius * radius) {
+					continue;
+				}
+			}
+
+			let index = atomicAdd(&slice_counts[slice], 1u);
+			if (index < MAX_LIGHTS_PER_CLUSTER) {
+				let cluster = (slice * CLUSTERS_Y + tile.y) * CLUSTERS_X + tile.x;
+				cluster_lights[cluster * MAX_LIGHTS_PER_CLUSTER + index] = i;
+			}
+		}
+	}
+	workgroupBarrier();
+
+	if (thread < CLUSTERS_Z) {
+		let cluster = (thread * CLUSTERS_Y + tile.y) * CLUSTERS_X + tile.x;
+		cluster_counts[cluster] = min(atomicLoad(&slice_counts[thread]), MAX_LIGHTS_PER_CLUSTER);
+	}
+}
@@ -0,0 +1,68 @@
+// Source is the mip level above the one being written
+@group(0) @binding(0) var source: texture_2d<f32>;
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+	var vertex_positions = array<vec2<f32>, 6>(
+		vec2<f32>(-1.0, -1.0),
+		vec2<f32>(1.0, 1.0),
+		vec2<f32>(-1.0, 1.0),
+		vec2<f32>(-1.0, -1.0),
+		vec2<f32>(1.0, -1.0),
+		vec2<f32>(1.0, 1.0)
+	);
+	
+	return vec4<f32>(vertex_positions[index], 0.0, 1.0);
+}
+
+// Weights of source texels 2i, 2i + 1 and 2i + 2 along one axis for output texel i. An odd
+// source of 2n + 1 texels goes down to n, so each output texel covers 2 + 1/n of them, partly
+// overlapping the texels at either end.
+fn footprint(i: i32, source_size: i32) -> vec3<f32> {
+	if (source_size == 1) {
+		return vec3<f32>(1.0, 0.0, 0.0);
+	}
+	if (source_size % 2 == 0) {
+		return vec3<f32>(0.5, 0.5, 0.0);
+	}
+	let n = source_size / 2;
+	return vec3<f32>(f32(n - i), f32(n), f32(i + 1)) / f32(source_size);
+}
+
+// Box filter over the texels covering this one in the source, every source texel counting
+// the same however the level size divides. Loads from (and writes to) sRGB textures are decoded
+// (and encoded), so the average is taken in linear space.
+fn box_filter(position: vec4<f32>) -> vec4<f32> {
+	let size = vec2<i32>(textureDimensions(source));
+	let texel = vec2<i32>(floor(position.xy));
+	let base = texel * 2;
+	let weights_x = footprint(texel.x, size.x);
+	let weights_y = footprint(texel.y, size.y);
+
+	var sum = vec4<f32>(0.0);
+	for (var y = 0; y < 3; y++) {
+		for (var x = 0; x < 3; x++) {
+			let weight = weights_x[x] * weights_y[y];
+			if (weight > 0.0) {
+				sum += textureLoad(source, min(base + vec2<i32>(x, y), size - 1), 0) * weight;
+			}
+		}
+	}
+	return sum;
+}
+
+@fragment
+fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+	return box_filter(position);
+}
+
+// Normal maps: averaging shortens the normals, so renormalize (same 0..1 encoding as the source).
+// Normals that cancel out entirely become flat.
+@fragment
+fn fs_normal(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+	let average = box_filter(position);
+	let v = average.rgb * 2.0 - 1.0;
+	let len = length(v);
+	let n = select(vec3<f32>(0.0, 0.0, 1.0), v / len, len > 0.0001);
+	return vec4<f32>(n * 0.5 + 0.5, average.a);
+}
@@ -2,7 +2,9 @@ struct SceneUniforms {
 	perspective: mat4x4<f32>,
     view: mat4x4<f32>,
     inverse_perspective_view: mat4x4<f32>,
-	camera_pos: vec4<f32>
+	camera_pos: vec4<f32>,
+	exposure: f32,
+	environment_intensity: f32,
 }
 
 @group(0) @binding(0) var<uniform> scene: SceneUniforms;
@@ -86,6 +88,77 @@ fn vs_main(in: VertexInput) -> VertexOutput {
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-	let color = textureSample(skybox, skybox_sampler, in.local_position).rgb;
+	// Exposed like the IBL maps in compose.wgsl
+	let color = textureSample(skybox, skybox_sampler, in.local_position).rgb * scene.environment_intensity * scene.exposure;
 	return vec4<f32>(color, 1.0);
 }
+
+// Area light emitters, drawn as their shape with the light's luminance
+
+struct Light {
+	// rgb = color * luminance for area lights
+	color: vec4<f32>,
+	position: vec4<f32>,
+	direction: vec4<f32>,
+	// x = type, z = two sided for area lights
+	parameters: vec4<f32>,
+	extent_x: vec4<f32>,
+	extent_y: vec4<f32>,
+}
+
+@group(2) @binding(1) var<storage, read> lights: array<Light>;
+
+// Matches compose.wgsl
+const LIGHT_RECT: u32 = 3u;
+
+// Disks are a fan of this many triangles, matches Skybox::DISK_SEGMENTS
+const DISK_SEGMENTS: u32 = 32u;
+
+struct EmitterOutput {
+	@builtin(position) clip_space_position: vec4<f32>,
+	@location(0) color: vec3<f32>,
+}
+
+// One instance per area light, indexing the lighting buffer
+@vertex
+fn vs_emitter(@builtin(vertex_index) index: u32, @builtin(instance_index) light_index: u32) -> EmitterOutput {
+	let light = lights[light_index];
+
+	var offset: vec2<f32>;
+	if (u32(light.parameters.x) == LIGHT_RECT) {
+		var corners = array<vec2<f32>, 6>(
+			vec2<f32>(-1.0, -1.0),
+			vec2<f32>(1.0, -1.0),
+			vec2<f32>(1.0, 1.0),
+			vec2<f32>(-1.0, -1.0),
+			vec2<f32>(1.0, 1.0),
+			vec2<f32>(-1.0, 1.0)
+		);
+		offset = corners[index];
+	} else {
+		let segment = index / 3u;
+		let corner = index % 3u;
+		if (corner == 0u) {
+			offset = vec2<f32>(0.0, 0.0);
+		} else {
+			let angle = f32(segment + corner - 1u) / f32(DISK_SEGMENTS) * 6.2831853;
+			offset = vec2<f32>(cos(angle), sin(angle));
+		}
+	}
+
+	let world_position = light.position.xyz + light.extent_x.xyz * offset.x + light.extent_y.xyz * offset.y;
+
+	// One sided emitters are dark from behind
+	let front = dot(scene.camera_pos.xyz - light.position.xyz, light.direction.xyz) > 0.0;
+	let emits = front || light.parameters.z != 0.0;
+
+	var out: EmitterOutput;
+	out.clip_space_position = scene.perspective * scene.view * vec4<f32>(world_position, 1.0);
+	out.color = select(vec3<f32>(0.0), light.color.rgb * scene.exposure, emits);
+	return out;
+}
+
+@fragment
+fn fs_emitter(in: EmitterOutput) -> @location(0) vec4<f32> {
+	return vec4<f32>(in.color, 1.0);
+}
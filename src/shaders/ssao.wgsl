@@ -2,15 +2,28 @@ struct SceneUniforms {
 	perspective: mat4x4<f32>,
     view: mat4x4<f32>,
 	inverse_perspective_view: mat4x4<f32>,
-	camera_pos: vec4<f32>
+	camera_pos: vec4<f32>,
+	exposure: f32,
+	environment_intensity: f32,
 }
 
 @group(0) @binding(0) var<uniform> scene: SceneUniforms;
 
+struct SsaoParams {
+	radius: f32,
+	bias: f32,
+	intensity: f32,
+	sample_count: u32,
+}
+
 @group(1) @binding(0) var depth_t: texture_depth_2d;
 @group(1) @binding(1) var normal_t: texture_2d<f32>;
 @group(1) @binding(2) var sample_kernel_t: texture_2d<f32>;
 @group(1) @binding(3) var random_noise_t: texture_2d<f32>;
+@group(1) @binding(4) var<uniform> params: SsaoParams;
+
+// Blur pass only (shares depth_t)
+@group(1) @binding(5) var raw_occlusion_t: texture_2d<f32>;
 
 @vertex
 fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
@@ -26,38 +39,36 @@ fn vs_main(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
 	return vec4<f32>(vertex_positions[index], 0.0, 1.0);
 }
 
-fn framebuffer_to_uv(texcoord: vec2<f32>) -> vec2<f32> {
-	return texcoord / vec2<f32>(textureDimensions(depth_t));
-}
-
-fn vs_position_from_depth(texcoord: vec2<f32>) -> vec3<f32> {
-	let snapped_texcoords = vec2<u32>(texcoord);
-	let uv = framebuffer_to_uv(texcoord);
-	let z = textureLoad(depth_t, snapped_texcoords, 0);
+// View space position of the gbuffer texel at coord
+fn vs_position_from_depth(coord: vec2<i32>) -> vec3<f32> {
+	let uv = (vec2<f32>(coord) + 0.5) / vec2<f32>(textureDimensions(depth_t));
+	let z = textureLoad(depth_t, coord, 0);
 	let x = uv.x * 2.0 - 1.0;
 	let y = (1.0 - uv.y) * 2.0 - 1.0;
 
-	let projected_pos = vec4<f32>(x, y, z, 1.0);
-	let vs_pos = scene.view * scene.inverse_perspective_view * projected_pos;
-
-	return vs_pos.xyz / vs_pos.www;
-}
+	let world_pos = scene.inverse_perspective_view * vec4<f32>(x, y, z, 1.0);
+	let vs_pos = scene.view * vec4<f32>(world_pos.xyz / world_pos.w, 1.0);
 
-fn texcoord_wrap(texcoord: vec2<f32>, scale: vec2<f32>) -> vec2<u32> {
-	return vec2<u32>(texcoord % scale);
+	return vs_pos.xyz;
 }
 
 @fragment
 fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
-	let radius = 0.01;
+	let coord = vec2<i32>(floor(position.xy));
+	let size = vec2<i32>(textureDimensions(depth_t));
+
+	// Nothing to occlude on the skybox
+	if (textureLoad(depth_t, coord, 0) == 1.0) {
+		return vec4<f32>(1.0, 1.0, 0.0, 0.0);
+	}
 
-	// Get view space position of the fragment as well as view space normal	
-	let origin = vs_position_from_depth(position.xy);
-	var normal = (scene.view * vec4<f32>(textureLoad(normal_t, vec2<u32>(position.xy), 0).xyz * 2.0 - 1.0, 1.0)).xyz;
+	// Get view space position of the fragment as well as view space normal
+	let origin = vs_position_from_depth(coord);
+	var normal = (scene.view * vec4<f32>(textureLoad(normal_t, coord, 0).xyz * 2.0 - 1.0, 0.0)).xyz;
 	normal = normalize(normal);
 
 	// Create TBN matrix for hemisphere using Gram-Schmidt
-	let rotation = textureLoad(random_noise_t, texcoord_wrap(position.xy, vec2<f32>(4.0, 4.0)), 0).xyz;
+	let rotation = textureLoad(random_noise_t, coord % vec2<i32>(4, 4), 0).xyz;
 	let tangent = normalize(rotation - normal * dot(rotation, normal));
 	let bitangent = cross(normal, tangent);
 	let tbn = mat3x3<f32>(tangent, bitangent, normal);
@@ -65,31 +76,55 @@ fn fs_main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
 	var occlusion = 0.0;
 
 	// Iterate through our sample table and use each to calculate an occlusion value
-	for (var x = 0.0; x < 4.0; x += 1.0) {
-		for (var y = 0.0; y < 4.0; y += 1.0) {
-			// Load one of the samples, multiply by TBN to get its position in hemisphere/view-space,
-			// Then add to origin to get our final sample position
-			var sample = textureLoad(sample_kernel_t, vec2<u32>(u32(x), u32(y)), 0).xyz;
-			sample = tbn * sample;
-			sample = sample * radius + origin;
-
-			// Convert the offset to framebuffer coordinates which we can use to sample
-			var offset = vec4<f32>(sample, 1.0);
-			offset = scene.perspective * offset;
-			offset.x = (offset.x / offset.w) * 0.5 + 0.5;
-			offset.y = (offset.y / offset.w) * -0.5 + 0.5;
-			var sample_depth_coords = offset.xy * vec2<f32>(textureDimensions(depth_t));
-
-			// Sample depth
-			var sample_depth = textureLoad(depth_t, vec2<u32>(sample_depth_coords), 0);
-
-			// Calculate occlusion
-			var range_check = select(1.0, 0.0, abs(origin.z - sample_depth) < radius);
-			occlusion += (select(0.0, 1.0, sample_depth <= sample.z)) * range_check; 		
+	for (var i = 0u; i < params.sample_count; i++) {
+		// Load one of the samples, multiply by TBN to get its position in hemisphere/view-space,
+		// Then add to origin to get our final sample position
+		let kernel_sample = textureLoad(sample_kernel_t, vec2<u32>(i % 8u, i / 8u), 0).xyz;
+		let sample = origin + tbn * kernel_sample * params.radius;
+
+		// Convert the offset to framebuffer coordinates which we can use to sample
+		let offset = scene.perspective * vec4<f32>(sample, 1.0);
+		let uv = vec2<f32>(offset.x / offset.w * 0.5 + 0.5, offset.y / offset.w * -0.5 + 0.5);
+		if (any(uv < vec2<f32>(0.0)) || any(uv >= vec2<f32>(1.0))) {
+			continue;
 		}
+
+		// Depth of the surface visible at the sample (view space, +z away from the camera)
+		let sample_depth = vs_position_from_depth(vec2<i32>(uv * vec2<f32>(size))).z;
+
+		// Occluded if that surface is in front of the sample, fading out contributions from
+		// surfaces much further away than the radius (avoids dark halos around silhouettes)
+		let range_check = smoothstep(0.0, 1.0, params.radius / abs(origin.z - sample_depth));
+		occlusion += select(0.0, 1.0, sample_depth <= sample.z - params.bias) * range_check;
 	}
 
-	occlusion = 1.0 - (occlusion / 16.0);
-	return vec4<f32>(occlusion, 0.0, 0.0, 1.0);
+	let ao = pow(1.0 - occlusion / f32(max(params.sample_count, 1u)), params.intensity);
+	return vec4<f32>(ao, ao, 0.0, 0.0);
 }
 
+// 4x4 blur (the size of the noise texture, so its pattern averages out) that skips texels
+// across depth discontinuities, so occlusion doesn't bleed between objects
+@fragment
+fn fs_blur(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+	let coord = vec2<i32>(floor(position.xy));
+	let size = vec2<i32>(textureDimensions(raw_occlusion_t));
+	let center_depth = vs_position_from_depth(coord).z;
+
+	var sum = vec4<f32>(0.0);
+	var weight_sum = 0.0;
+	for (var x = -2; x < 2; x++) {
+		for (var y = -2; y < 2; y++) {
+			let texel = clamp(coord + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+			let depth = vs_position_from_depth(texel).z;
+
+			// Within ~5% of the center's depth counts as the same surface
+			let difference = (depth - center_depth) / (0.05 * center_depth);
+			let weight = exp(-difference * difference);
+
+			sum += textureLoad(raw_occlusion_t, texel, 0) * weight;
+			weight_sum += weight;
+		}
+	}
+
+	return sum / max(weight_sum, 0.0001);
+}
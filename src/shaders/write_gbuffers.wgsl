@@ -2,7 +2,9 @@ struct SceneUniforms {
 	perspective: mat4x4<f32>,
     view: mat4x4<f32>,
     inverse_perspective_view: mat4x4<f32>,
-	camera_pos: vec4<f32>
+	camera_pos: vec4<f32>,
+	exposure: f32,
+	environment_intensity: f32,
 }
 
 @group(0) @binding(0) var<uniform> scene: SceneUniforms;
@@ -11,6 +13,12 @@ struct MaterialUniforms {
     ambient: vec4<f32>,
     diffuse: vec4<f32>,
     specular: vec4<f32>,
+    // x = strength, y = uv set
+    occlusion: vec4<f32>,
+    // rgb = factor * strength
+    emissive: vec4<f32>,
+    // x = cutoff, y = mode (0 = opaque, 1 = mask, 2 = blend)
+    alpha: vec4<f32>,
 } 
 
 @group(1) @binding(0) var<uniform> material: MaterialUniforms;
@@ -20,12 +28,10 @@ struct MaterialUniforms {
 @group(1) @binding(4) var normal_texture_sampler: sampler;
 @group(1) @binding(5) var metal_roughness_texture: texture_2d<f32>;
 @group(1) @binding(6) var metal_roughness_texture_sampler: sampler;
-
-struct MeshUniforms {
-    model: mat4x4<f32>,
-}
-
-@group(2) @binding(0) var<uniform> mesh: MeshUniforms;
+@group(1) @binding(7) var occlusion_texture: texture_2d<f32>;
+@group(1) @binding(8) var occlusion_texture_sampler: sampler;
+@group(1) @binding(9) var emissive_texture: texture_2d<f32>;
+@group(1) @binding(10) var emissive_texture_sampler: sampler;
 
 struct VertexInput {
     @builtin(vertex_index) index: u32,
@@ -33,6 +39,18 @@ struct VertexInput {
     @location(1) normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
     @location(3) tangent: vec3<f32>,
+    @location(4) uv_1: vec2<f32>,
+}
+
+// Per-instance world transform and normal matrix (see MeshInstance), one column per attribute
+struct InstanceInput {
+    @location(5) model_0: vec4<f32>,
+    @location(6) model_1: vec4<f32>,
+    @location(7) model_2: vec4<f32>,
+    @location(8) model_3: vec4<f32>,
+    @location(9) normal_0: vec4<f32>,
+    @location(10) normal_1: vec4<f32>,
+    @location(11) normal_2: vec4<f32>,
 }
 
 struct VertexOutput {
@@ -41,17 +59,22 @@ struct VertexOutput {
     @location(1) normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
     @location(3) tangent: vec3<f32>,
+    @location(4) uv_1: vec2<f32>,
 }
 
 
 @vertex
-fn vs_main(in: VertexInput) -> VertexOutput {
+fn vs_main(in: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+    let normal_matrix = mat3x3<f32>(instance.normal_0.xyz, instance.normal_1.xyz, instance.normal_2.xyz);
+
     var out: VertexOutput;
-    out.clip_space_position = scene.perspective * scene.view * mesh.model * vec4<f32>(in.position, 1.0);
-    out.world_position = (mesh.model * vec4<f32>(in.position, 1.0)).xyz;
-    out.normal = (mesh.model * vec4<f32>(in.normal, 0.0)).xyz;    
+    out.clip_space_position = scene.perspective * scene.view * model * vec4<f32>(in.position, 1.0);
+    out.world_position = (model * vec4<f32>(in.position, 1.0)).xyz;
+    out.normal = normal_matrix * in.normal;
     out.uv = in.uv;
-    out.tangent = (mesh.model * vec4<f32>(in.tangent, 0.0)).xyz;
+    out.tangent = (model * vec4<f32>(in.tangent, 0.0)).xyz;
+    out.uv_1 = in.uv_1;
     return out;
 }
 
@@ -59,15 +82,17 @@ struct FragmentOutput {
 	@location(0) albedo: vec4<f32>,
 	@location(1) normal: vec4<f32>,
 	@location(2) material: vec4<f32>,
+	@location(3) emissive: vec4<f32>,
 }
 
 
 @fragment
-fn fs_main(in: VertexOutput) -> FragmentOutput {
+fn fs_main(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> FragmentOutput {
 	var output: FragmentOutput;
 
     // Diffuse texture is stored in rgba8unormsrgb, so srgb->linear conversion happens automatically 
-    output.albedo = vec4<f32>(textureSample(diffuse_texture, diffuse_texture_sampler, in.uv).rgb, 1.0);
+    let diffuse = textureSample(diffuse_texture, diffuse_texture_sampler, in.uv);
+    output.albedo = vec4<f32>(diffuse.rgb, 1.0);
     
 	var normal = 
         textureSample(normal_texture, normal_texture_sampler, in.uv).rgb;
@@ -77,11 +102,30 @@ fn fs_main(in: VertexOutput) -> FragmentOutput {
         normalize(cross(in.tangent, in.normal)),
         normalize(in.normal)
     );
-	normal = (normalize(rotation * normalize(normal)) * 0.5) + 0.5;
+	normal = normalize(rotation * normalize(normal));
+    // Back faces only get here for double sided materials, shade them with the normal facing
+    // the camera
+    normal = select(-normal, normal, front_facing);
+	normal = (normal * 0.5) + 0.5;
 	output.normal = vec4<f32>(normal, 1.0);
 
-    // red -> metal, green -> roughness
-	output.material = vec4<f32>(textureSample(metal_roughness_texture, metal_roughness_texture_sampler, in.uv).bg, 1.0, 1.0);
+    // Baked ambient occlusion (red channel), scaled towards 1.0 by strength
+    let occlusion_uv = select(in.uv, in.uv_1, material.occlusion.y > 0.5);
+    let occlusion_sample = textureSample(occlusion_texture, occlusion_texture_sampler, occlusion_uv).r;
+    let occlusion = 1.0 + material.occlusion.x * (occlusion_sample - 1.0);
+
+    // red -> metal, green -> roughness, blue -> occlusion
+	output.material = vec4<f32>(textureSample(metal_roughness_texture, metal_roughness_texture_sampler, in.uv).bg, occlusion, 1.0);
+
+    // Emissive texture is srgb like the diffuse texture, factor can go above 1.0 (emissive strength)
+    let emissive = textureSample(emissive_texture, emissive_texture_sampler, in.uv).rgb * material.emissive.rgb;
+    output.emissive = vec4<f32>(emissive, 1.0);
+
+    // Alpha mask: cut out anything below the cutoff (ambient.a is the base color factor's alpha).
+    // Done last, all texture sampling has to happen in uniform control flow
+    if (material.alpha.y == 1.0 && diffuse.a * material.ambient.a < material.alpha.x) {
+        discard;
+    }
 	
 	return output;	
 }
\ No newline at end of file
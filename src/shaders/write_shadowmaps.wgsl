@@ -1,15 +1,23 @@
-struct ShadowUniforms {
-    view: mat4x4<f32>,
-    perspective: mat4x4<f32>
+struct ShadowViewUniforms {
+    view_projection: mat4x4<f32>,
 }
 
-@group(0) @binding(0) var<uniform> shadow: ShadowUniforms;
+@group(0) @binding(0) var<uniform> shadow_view: ShadowViewUniforms;
 
-struct MeshUniforms {
-    model: mat4x4<f32>
+// Same as write_gbuffers.wgsl, only bound for masked materials
+struct MaterialUniforms {
+    ambient: vec4<f32>,
+    diffuse: vec4<f32>,
+    specular: vec4<f32>,
+    occlusion: vec4<f32>,
+    emissive: vec4<f32>,
+    // x = cutoff, y = mode (0 = opaque, 1 = mask, 2 = blend)
+    alpha: vec4<f32>,
 }
 
-@group(1) @binding(0) var<uniform> mesh: MeshUniforms;
+@group(1) @binding(0) var<uniform> material: MaterialUniforms;
+@group(1) @binding(1) var diffuse_texture: texture_2d<f32>;
+@group(1) @binding(2) var diffuse_texture_sampler: sampler;
 
 struct VertexInput {
     @builtin(vertex_index) index: u32,
@@ -19,13 +27,33 @@ struct VertexInput {
     @location(3) tangent: vec3<f32>,
 }
 
+struct InstanceInput {
+    @location(5) model_0: vec4<f32>,
+    @location(6) model_1: vec4<f32>,
+    @location(7) model_2: vec4<f32>,
+    @location(8) model_3: vec4<f32>,
+}
+
 struct VertexOutput {
     @builtin(position) clip_space_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
 }
 
 @vertex
-fn vs_main(in: VertexInput) -> VertexOutput {
+fn vs_main(in: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model = mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+
     var out: VertexOutput;
-    out.clip_space_position = shadow.perspective * shadow.view * mesh.model * vec4<f32>(in.position, 1.0);
+    out.clip_space_position = shadow_view.view_projection * model * vec4<f32>(in.position, 1.0);
+    out.uv = in.uv;
     return out;
 }
+
+// Alpha mask, the same test as the gbuffers (ambient.a is the base color factor's alpha)
+@fragment
+fn fs_masked(in: VertexOutput) {
+    let alpha = textureSample(diffuse_texture, diffuse_texture_sampler, in.uv).a * material.ambient.a;
+    if (alpha < material.alpha.x) {
+        discard;
+    }
+}
@@ -1,76 +1,559 @@
-use glam::{vec3, Mat4, Vec3};
-use wgpu::{TextureFormat, TextureUsages};
+use glam::{vec3, Mat4, Vec3, Vec4};
+use wgpu::{
+    BindGroupLayout, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource,
+    SamplerBindingType, ShaderStages, TextureUsages,
+};
 
-use crate::{bytemuck_impl, texture::Texture, uniform::Uniform};
+use crate::{
+    bytemuck_impl,
+    camera::Camera,
+    resources::{Light, LightKind},
+    texture::{Sampler, Texture},
+    uniform::Uniform,
+};
+
+/// How far behind each cascade (towards the light) casters are still rendered into the
+/// shadowmap, so objects outside the view can still shadow what's in it.
+const CASTER_MARGIN: f32 = 50.0;
+
+pub const MAX_CASCADES: usize = 4;
+
+/// Most point lights that can have a cube shadowmap at once
+pub const MAX_POINT_SHADOWS: u32 = 8;
+/// Depth range of the cube shadowmaps, anything further than POINT_SHADOW_FAR from a light
+/// isn't shadowed by it
+pub const POINT_SHADOW_NEAR: f32 = 0.05;
+pub const POINT_SHADOW_FAR: f32 = 50.0;
+
+/// User facing knobs for the cascades, see ShadowData::new.
+#[derive(Clone, Copy, Debug)]
+pub struct CascadeSettings {
+    /// Number of cascades, 2..=MAX_CASCADES
+    pub count: u32,
+    /// Shadows are only rendered this far (view space depth) from the camera
+    pub max_distance: f32,
+    /// Practical split scheme blend, 0 = uniform splits, 1 = logarithmic splits
+    pub split_lambda: f32,
+    /// Fraction of each cascade (at its far end) that is blended into the next one
+    pub blend_width: f32,
+    /// Tint pixels by the cascade they sample from
+    pub debug: bool,
+}
+
+impl Default for CascadeSettings {
+    fn default() -> Self {
+        Self {
+            count: 4,
+            max_distance: 50.0,
+            split_lambda: 0.75,
+            blend_width: 0.1,
+            debug: false,
+        }
+    }
+}
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct ShadowData {
-    pub view: Mat4,
-    pub perspective: Mat4,
+    /// Light space view-projection of each cascade
+    pub view_projections: [Mat4; MAX_CASCADES],
+    /// View space depth each cascade ends at
+    pub splits: Vec4,
+    /// World space size of a shadowmap texel in each cascade (used for normal offset bias)
+    pub texel_sizes: Vec4,
+    /// Direction light travels in (xyz), w unused
+    pub direction: Vec4,
+    /// Color of light premultiplied by its illuminance in lux (rgb), w unused
+    pub color: Vec4,
+    pub cascade_count: u32,
+    pub debug_cascades: u32,
+    pub blend_width: f32,
+    pub padding: f32,
 }
 bytemuck_impl!(ShadowData);
 
+/// Direction a light coming from theta and phi travels in, looking from the center of the unit
+/// sphere (phi is measured from +y).
+pub fn sun_direction(theta: f32, phi: f32) -> Vec3 {
+    -vec3(
+        f32::sin(phi) * f32::cos(theta),
+        f32::cos(phi),
+        f32::sin(phi) * f32::sin(theta),
+    )
+}
+
 impl ShadowData {
-    /// pos should be camera pos, theta and phi are where direcitonal light originates from,
-    /// looking from center of unit sphere.
-    pub fn new(pos: Vec3, dist: f32, theta: f32, phi: f32) -> Self {
-        let dir = -vec3(
-            f32::sin(phi) * f32::cos(theta),
-            f32::sin(phi) * f32::sin(theta),
-            f32::cos(phi),
-        );
+    /// direction is the one the directional light travels in.
+    ///
+    /// The camera frustum (up to cascades.max_distance) is split into cascades with the
+    /// "practical" split scheme. Each cascade's projection is fitted to the bounding sphere of
+    /// its slice and snapped to whole shadowmap texels so it doesn't shimmer as the camera moves.
+    pub fn new(
+        camera: &Camera,
+        aspect_ratio: f32,
+        direction: Vec3,
+        color: Vec3,
+        cascades: &CascadeSettings,
+    ) -> Self {
+        let dir = direction.try_normalize().unwrap_or(Vec3::NEG_Y);
+
+        // look_to breaks down when looking straight up/down
+        let up = if dir.y.abs() > 0.99 {
+            vec3(0.0, 0.0, 1.0)
+        } else {
+            vec3(0.0, 1.0, 0.0)
+        };
+        // Fixed origin so light space only changes when the light does (needed for snapping)
+        let light_view = Mat4::look_to_lh(Vec3::ZERO, dir, up);
+
+        let count = cascades.count.clamp(1, MAX_CASCADES as u32);
+        let near = Camera::NEAR;
+        let far = cascades.max_distance.clamp(near * 2.0, Camera::FAR);
+
+        let mut view_projections = [Mat4::IDENTITY; MAX_CASCADES];
+        let mut splits = Vec4::splat(far);
+        let mut texel_sizes = Vec4::ZERO;
+
+        let mut cascade_near = near;
+        for i in 0..count as usize {
+            let t = (i + 1) as f32 / count as f32;
+            let log_split = near * (far / near).powf(t);
+            let uniform_split = near + (far - near) * t;
+            let cascade_far =
+                cascades.split_lambda * log_split + (1.0 - cascades.split_lambda) * uniform_split;
+
+            let corners = camera.frustum_corners(aspect_ratio, cascade_near, cascade_far);
+            let center = corners.iter().sum::<Vec3>() / 8.0;
+            let radius = corners
+                .iter()
+                .map(|x| x.distance(center))
+                .fold(0.0, f32::max);
+            // Quantize the radius too, otherwise float error changes the extent frame to frame
+            let radius = (radius * 16.0).ceil() / 16.0;
+
+            let texel_size = 2.0 * radius / Shadowmap::SIZE as f32;
+            let mut center = light_view.transform_point3(center);
+            center.x = (center.x / texel_size).floor() * texel_size;
+            center.y = (center.y / texel_size).floor() * texel_size;
 
-        let up = vec3(0.0, 1.0, 0.0);
+            let projection = Mat4::orthographic_lh(
+                center.x - radius,
+                center.x + radius,
+                center.y - radius,
+                center.y + radius,
+                center.z - radius - CASTER_MARGIN,
+                center.z + radius,
+            );
 
-        let eye = pos + (-dir * dist);
+            view_projections[i] = projection * light_view;
+            splits[i] = cascade_far;
+            texel_sizes[i] = texel_size;
+            cascade_near = cascade_far;
+        }
 
-        let view = Mat4::look_to_lh(eye, dir, up);
-        let perspective = Mat4::perspective_lh(70.0, 1600.0 / 900.0, 0.01, 100.0);
-        Self { view, perspective }
+        Self {
+            view_projections,
+            splits,
+            texel_sizes,
+            direction: (dir, 0.0).into(),
+            color: (color, 1.0).into(),
+            cascade_count: count,
+            debug_cascades: cascades.debug as u32,
+            blend_width: cascades.blend_width,
+            padding: 0.0,
+        }
     }
 }
 
+/// What the WriteShadowmaps pass renders one cascade or cube face with.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowViewData {
+    pub view_projection: Mat4,
+}
+bytemuck_impl!(ShadowViewData);
+
+pub type ShadowViewUniform = Uniform<ShadowViewData>;
+
+/// Depth texture array, one layer per cascade.
 pub struct Shadowmap {
-    pub texture: Texture,
+    pub texture: wgpu::Texture,
+    /// All layers, for sampling
+    pub view: wgpu::TextureView,
+    /// One view per layer, for rendering into
+    pub layer_views: Vec<wgpu::TextureView>,
 }
 
 impl Shadowmap {
-    pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Self {
-        let map = Texture::new(
-            device,
-            config.width,
-            config.height,
-            TextureFormat::Depth32Float,
-            TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
-            Some("Shadowmap"),
-            false,
+    /// Resolution of each (square) cascade, independent of the window size
+    pub const SIZE: u32 = 2048;
+
+    pub fn new(device: &wgpu::Device, cascade_count: u32) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Shadowmap"),
+            size: wgpu::Extent3d {
+                width: Shadowmap::SIZE,
+                height: Shadowmap::SIZE,
+                depth_or_array_layers: cascade_count,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::DEPTH_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Shadowmap - all cascades"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let layer_views = (0..cascade_count)
+            .map(|i| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some(format!("Shadowmap - cascade {}", i).as_str()),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: i,
+                    array_layer_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        Self {
+            texture,
+            view,
+            layer_views,
+        }
+    }
+}
+
+/// Depth texture array of cubes for point lights, six layers per shadow casting light. Sampled
+/// as a 2D array (compose.wgsl picks the face) rather than a cube array, which GL and some
+/// downlevel adapters don't have. budget is how many lights can cast shadows at once.
+pub struct PointShadowmap {
+    pub texture: wgpu::Texture,
+    /// All cubes, for sampling
+    pub view: wgpu::TextureView,
+    /// One view per cube face (slot * 6 + face), for rendering into
+    pub face_views: Vec<wgpu::TextureView>,
+    pub budget: u32,
+}
+
+impl PointShadowmap {
+    pub const SIZE: u32 = 512;
+
+    pub fn new(device: &wgpu::Device, budget: u32) -> Self {
+        // Bindings can't be empty, keep one cube around even with no budget
+        let layers = 6 * budget.max(1);
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Point shadowmap"),
+            size: wgpu::Extent3d {
+                width: PointShadowmap::SIZE,
+                height: PointShadowmap::SIZE,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::DEPTH_FORMAT,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Point shadowmap - all cubes"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let face_views = (0..layers)
+            .map(|i| {
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some(format!("Point shadowmap - face {}", i).as_str()),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_array_layer: i,
+                    array_layer_count: Some(1),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        Self {
+            texture,
+            view,
+            face_views,
+            budget,
+        }
+    }
+
+    /// View-projections of the six faces of a cube shadowmap at position, in wgpu's cube face
+    /// order (+x, -x, +y, -y, +z, -z).
+    pub fn face_view_projections(position: Vec3) -> [Mat4; 6] {
+        let projection = Mat4::perspective_lh(
+            90.0f32.to_radians(),
+            1.0,
+            POINT_SHADOW_NEAR,
+            POINT_SHADOW_FAR,
         );
+        let faces = [
+            (Vec3::X, Vec3::Y),
+            (Vec3::NEG_X, Vec3::Y),
+            (Vec3::Y, Vec3::NEG_Z),
+            (Vec3::NEG_Y, Vec3::Z),
+            (Vec3::Z, Vec3::Y),
+            (Vec3::NEG_Z, Vec3::Y),
+        ];
 
-        Self { texture: map }
+        faces.map(|(dir, up)| projection * Mat4::look_to_lh(position, dir, up))
     }
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct PointShadowData {
+    pub near: f32,
+    pub far: f32,
+    pub padding: [f32; 2],
+}
+bytemuck_impl!(PointShadowData);
+
+pub type PointShadowUniform = Uniform<PointShadowData>;
+
 pub type ShadowUniform = Uniform<ShadowData>;
 
+/// Everything needed for the directional and point light shadows. cascades and point_views
+/// are what the WriteShadowmaps pass renders with, bind_group is for passes sampling the
+/// shadowmaps (Compose).
 pub struct Shadows {
     pub shadowmap: Shadowmap,
     pub uniform: ShadowUniform,
+    pub cascades: Vec<ShadowViewUniform>,
+
+    pub point_shadowmap: PointShadowmap,
+    pub point_uniform: PointShadowUniform,
+    /// Six per cube slot, same order as point_shadowmap.face_views
+    pub point_views: Vec<ShadowViewUniform>,
+    /// Cube slots in use this frame (always the first point_count slots)
+    pub point_count: u32,
+
+    pub sampler: Sampler,
+    pub bind_group: wgpu::BindGroup,
 }
 
 impl Shadows {
-    pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Self {
-        let shadowmap = Shadowmap::new(device, config);
-        let uniform = ShadowUniform::new(
+    pub fn new(device: &wgpu::Device, cascade_count: u32, point_budget: u32) -> Self {
+        let settings = CascadeSettings {
+            count: cascade_count,
+            ..Default::default()
+        };
+        let data = ShadowData::new(&Camera::default(), 1.0, Vec3::NEG_Y, Vec3::ZERO, &settings);
+
+        let shadowmap = Shadowmap::new(device, cascade_count);
+        let uniform = ShadowUniform::new(device, Some("Shadows"), data);
+        let cascades = (0..MAX_CASCADES)
+            .map(|i| {
+                ShadowViewUniform::new(
+                    device,
+                    Some(format!("Shadow cascade {}", i).as_str()),
+                    ShadowViewData {
+                        view_projection: data.view_projections[i],
+                    },
+                )
+            })
+            .collect();
+
+        let point_shadowmap = PointShadowmap::new(device, point_budget);
+        let point_uniform = PointShadowUniform::new(
             device,
-            None,
-            ShadowData::new(vec3(0.0, 0.0, 0.0), 10.0, 0.0, 0.0),
+            Some("Point shadows"),
+            PointShadowData {
+                near: POINT_SHADOW_NEAR,
+                far: POINT_SHADOW_FAR,
+                padding: [0.0; 2],
+            },
         );
+        let point_views = (0..6 * MAX_POINT_SHADOWS)
+            .map(|i| {
+                ShadowViewUniform::new(
+                    device,
+                    Some(format!("Point shadow face {}", i).as_str()),
+                    ShadowViewData {
+                        view_projection: Mat4::IDENTITY,
+                    },
+                )
+            })
+            .collect();
+
+        let sampler = Sampler::shadow_map_sampler(device);
+        let bind_group = Shadows::bind_group(
+            device,
+            &uniform,
+            &shadowmap,
+            &point_uniform,
+            &point_shadowmap,
+            &sampler,
+        );
+
+        Self {
+            shadowmap,
+            uniform,
+            cascades,
+            point_shadowmap,
+            point_uniform,
+            point_views,
+            point_count: 0,
+            sampler,
+            bind_group,
+        }
+    }
 
-        Self { shadowmap, uniform }
+    pub fn cascade_count(&self) -> u32 {
+        self.shadowmap.layer_views.len() as u32
+    }
+
+    /// Reallocate the shadowmap with a different number of layers.
+    pub fn set_cascade_count(&mut self, device: &wgpu::Device, cascade_count: u32) {
+        self.shadowmap = Shadowmap::new(device, cascade_count);
+        self.rebuild_bind_group(device);
+    }
+
+    pub fn point_budget(&self) -> u32 {
+        self.point_shadowmap.budget
+    }
+
+    /// Reallocate the point shadowmaps to fit a different number of lights.
+    pub fn set_point_budget(&mut self, device: &wgpu::Device, budget: u32) {
+        self.point_shadowmap = PointShadowmap::new(device, budget.min(MAX_POINT_SHADOWS));
+        self.rebuild_bind_group(device);
+    }
+
+    /// Hand out cube slots to shadow casting lights in order until the budget runs out, lights
+    /// past that (and ones that don't cast shadows, or are directional or area lights) get None.
+    pub fn assign_point_slots(&self, lights: &[Light]) -> Vec<Option<u32>> {
+        let mut next = 0;
+        lights
+            .iter()
+            .map(|light| {
+                let shadowable = matches!(light.kind, LightKind::Point | LightKind::Spot { .. });
+                if light.casts_shadows && shadowable && next < self.point_budget() {
+                    next += 1;
+                    Some(next - 1)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Point the cube faces of every slot handed out by assign_point_slots at its light.
+    pub fn update_point_lights(
+        &mut self,
+        queue: &wgpu::Queue,
+        lights: &[Light],
+        slots: &[Option<u32>],
+    ) {
+        self.point_count = 0;
+        for (light, slot) in lights.iter().zip(slots) {
+            let Some(slot) = slot else { continue };
+            let faces = PointShadowmap::face_view_projections(light.position);
+            for (face, view_projection) in faces.into_iter().enumerate() {
+                self.point_views[*slot as usize * 6 + face]
+                    .update(queue, ShadowViewData { view_projection });
+            }
+            self.point_count = self.point_count.max(slot + 1);
+        }
+    }
+
+    fn rebuild_bind_group(&mut self, device: &wgpu::Device) {
+        self.bind_group = Shadows::bind_group(
+            device,
+            &self.uniform,
+            &self.shadowmap,
+            &self.point_uniform,
+            &self.point_shadowmap,
+            &self.sampler,
+        );
     }
 
     pub fn update_uniform(&mut self, queue: &wgpu::Queue, data: ShadowData) {
         self.uniform.update(queue, data);
+        for (i, cascade) in self.cascades.iter_mut().enumerate() {
+            cascade.update(
+                queue,
+                ShadowViewData {
+                    view_projection: data.view_projections[i],
+                },
+            );
+        }
+    }
+
+    fn bind_group(
+        device: &wgpu::Device,
+        uniform: &ShadowUniform,
+        shadowmap: &Shadowmap,
+        point_uniform: &PointShadowUniform,
+        point_shadowmap: &PointShadowmap,
+        sampler: &Sampler,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Shadows bind group"),
+            layout: &Shadows::bind_group_layout(device),
+            entries: &[
+                uniform.bind_group_entry(0),
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&shadowmap.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::Sampler(&sampler.sampler),
+                },
+                point_uniform.bind_group_entry(3),
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(&point_shadowmap.view),
+                },
+            ],
+        })
+    }
+
+    pub fn bind_group_layout(device: &wgpu::Device) -> BindGroupLayout {
+        device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("Shadows bind group layout"),
+            entries: &[
+                ShadowUniform::bind_group_layout_entry(0),
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(SamplerBindingType::Comparison),
+                    count: None,
+                },
+                PointShadowUniform::bind_group_layout_entry(3),
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        })
     }
 }
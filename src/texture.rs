@@ -1,5 +1,16 @@
 use wgpu::{BindGroupEntry, TextureFormat, TextureUsages};
 
+/// Size, format and usage of a texture made from bytes, the parts of a wgpu::TextureDescriptor
+/// the caller picks.
+#[derive(Clone, Copy, Debug)]
+pub struct BytesDescriptor<'a> {
+    pub label: Option<&'a str>,
+    pub width: u32,
+    pub height: u32,
+    pub format: wgpu::TextureFormat,
+    pub usage: wgpu::TextureUsages,
+}
+
 pub struct Texture {
     pub sample_type: wgpu::TextureSampleType,
     pub texture: wgpu::Texture,
@@ -20,6 +31,53 @@ impl Texture {
         label: Option<&str>,
         debug: bool,
     ) -> Self {
+        let descriptor = BytesDescriptor {
+            label,
+            width,
+            height,
+            format,
+            usage,
+        };
+        Texture::from_bytes(device, queue, bytes, descriptor, 1, debug)
+    }
+
+    /// Number of mip levels in a full chain down to 1x1
+    pub fn mip_level_count(width: u32, height: u32) -> u32 {
+        32 - width.max(height).leading_zeros()
+    }
+
+    /// Like new_from_bytes, but with room for a full mip chain. Only the first level is
+    /// written, fill in the rest with MipmapGenerator (the texture is created with
+    /// RENDER_ATTACHMENT for that).
+    pub fn new_from_bytes_with_mips(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        descriptor: BytesDescriptor,
+    ) -> Self {
+        let descriptor = BytesDescriptor {
+            usage: descriptor.usage | TextureUsages::RENDER_ATTACHMENT,
+            ..descriptor
+        };
+        let mip_level_count = Texture::mip_level_count(descriptor.width, descriptor.height);
+        Texture::from_bytes(device, queue, bytes, descriptor, mip_level_count, false)
+    }
+
+    fn from_bytes(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        descriptor: BytesDescriptor,
+        mip_level_count: u32,
+        debug: bool,
+    ) -> Self {
+        let BytesDescriptor {
+            label,
+            width,
+            height,
+            format,
+            usage,
+        } = descriptor;
         let dimensions = (width, height);
 
         let texture = device.create_texture(&wgpu::TextureDescriptor {
@@ -29,7 +87,7 @@ impl Texture {
                 height: dimensions.1,
                 depth_or_array_layers: 1,
             },
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
@@ -204,36 +262,66 @@ impl Texture {
     }
 }
 
+/// Wrap and filter modes of a material texture sampler. Hashable so textures with the same
+/// settings can share one sampler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialSamplerDescriptor {
+    pub address_mode_u: wgpu::AddressMode,
+    pub address_mode_v: wgpu::AddressMode,
+    pub mag_filter: wgpu::FilterMode,
+    pub min_filter: wgpu::FilterMode,
+    /// None samples only the base mip level
+    pub mipmap_filter: Option<wgpu::FilterMode>,
+}
+
+impl Default for MaterialSamplerDescriptor {
+    /// Repeating trilinear filtering
+    fn default() -> Self {
+        Self {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: Some(wgpu::FilterMode::Linear),
+        }
+    }
+}
+
 pub struct Sampler {
     pub sampler: wgpu::Sampler,
 }
 
 impl Sampler {
-    pub fn diffuse_texture_sampler(device: &wgpu::Device) -> Self {
+    pub fn material_sampler(device: &wgpu::Device, descriptor: MaterialSamplerDescriptor) -> Self {
+        // Anisotropic filtering is only valid when every filter is linear
+        let trilinear = descriptor.mag_filter == wgpu::FilterMode::Linear
+            && descriptor.min_filter == wgpu::FilterMode::Linear
+            && descriptor.mipmap_filter == Some(wgpu::FilterMode::Linear);
+
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            label: Some("Diffuse texture sampler"),
-            address_mode_u: wgpu::AddressMode::Repeat,
-            address_mode_v: wgpu::AddressMode::Repeat,
+            label: Some(format!("Material sampler {:?}", descriptor).as_str()),
+            address_mode_u: descriptor.address_mode_u,
+            address_mode_v: descriptor.address_mode_v,
             address_mode_w: wgpu::AddressMode::Repeat,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Linear,
-            mipmap_filter: wgpu::FilterMode::Nearest,
+            mag_filter: descriptor.mag_filter,
+            min_filter: descriptor.min_filter,
+            mipmap_filter: descriptor
+                .mipmap_filter
+                .unwrap_or(wgpu::FilterMode::Nearest),
             lod_min_clamp: 0.0,
-            lod_max_clamp: 0.0,
+            lod_max_clamp: if descriptor.mipmap_filter.is_some() {
+                32.0
+            } else {
+                0.0
+            },
             compare: None,
-            anisotropy_clamp: 1,
+            anisotropy_clamp: if trilinear { 16 } else { 1 },
             border_color: None,
         });
 
         Self { sampler }
     }
 
-    pub fn normal_texture_sampler(device: &wgpu::Device) -> Self {
-        // TODO: will diffuse and normal samplers always be same? check in after mipmaps
-        Sampler::diffuse_texture_sampler(device)
-    }
-
-    #[allow(dead_code)]
     pub fn shadow_map_sampler(device: &wgpu::Device) -> Self {
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: Some("Shadow map sampler (PCF)"),
@@ -272,6 +360,26 @@ impl Sampler {
         Self { sampler }
     }
 
+    /// Trilinear, wrapping around horizontally for equirectangular panoramas
+    pub fn environment_sampler(device: &wgpu::Device) -> Self {
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            lod_min_clamp: 0.0,
+            lod_max_clamp: 32.0,
+            compare: None,
+            anisotropy_clamp: 1,
+            border_color: None,
+        });
+
+        Self { sampler }
+    }
+
     pub fn fxaa_sampler(device: &wgpu::Device) -> Self {
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             label: Some("FXAA sampler"),